@@ -0,0 +1,141 @@
+use bootloader::progress::BootloaderEvent;
+use bundle::segments_sha256;
+use firmware_image::FirmwareImage;
+use std::time::SystemTime;
+
+/*
+ *  A record of every erase, write and check done to a chip, for manufacturing traceability
+ *  It is fed the same BootloaderEvents subscribers get, progress-only events are left out
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AuditEvent {
+    Identified {
+        chip_id: u32,
+    },
+    // None for a bank erase
+    Erased {
+        address: Option<u32>,
+    },
+    Written {
+        address: u32,
+        len: usize,
+        crc: u32,
+    },
+    Checked {
+        address: u32,
+        len: usize,
+        expected: u32,
+        actual: u32,
+    },
+    Completed,
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: SystemTime,
+    // of the last chip identified, None before the first
+    pub chip_id: Option<u32>,
+    pub event: AuditEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AuditLog {
+    // see bundle::segments_sha256
+    pub image_sha256: Option<[u8; 32]>,
+    pub entries: Vec<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn new() -> AuditLog {
+        AuditLog::default()
+    }
+
+    // for a log covering the flashing of one image
+    pub fn for_image(image: &FirmwareImage) -> AuditLog {
+        AuditLog {
+            image_sha256: Some(segments_sha256(image)),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, event: &BootloaderEvent) {
+        let event = match *event {
+            BootloaderEvent::Identified { chip_id } => AuditEvent::Identified { chip_id },
+            BootloaderEvent::SectorErased { addr } => AuditEvent::Erased {
+                address: Some(addr),
+            },
+            BootloaderEvent::BankErased => AuditEvent::Erased { address: None },
+            BootloaderEvent::SegmentWritten { addr, len, crc } => AuditEvent::Written {
+                address: addr,
+                len,
+                crc,
+            },
+            BootloaderEvent::CrcChecked {
+                addr,
+                len,
+                expected,
+                actual,
+            } => AuditEvent::Checked {
+                address: addr,
+                len,
+                expected,
+                actual,
+            },
+            BootloaderEvent::Completed => AuditEvent::Completed,
+            BootloaderEvent::Error(ref e) => AuditEvent::Failed(e.clone()),
+            BootloaderEvent::EnteredBootloader
+            | BootloaderEvent::SegmentStarted { .. }
+            | BootloaderEvent::ChunkWritten { .. }
+            | BootloaderEvent::VerifyProgress { .. } => return,
+        };
+        let chip_id = match event {
+            AuditEvent::Identified { chip_id } => Some(chip_id),
+            _ => self.entries.last().and_then(|entry| entry.chip_id),
+        };
+        self.entries.push(AuditEntry {
+            timestamp: SystemTime::now(),
+            chip_id,
+            event,
+        });
+    }
+}
+
+#[test]
+fn test_audit_log() {
+    let mut log = AuditLog::new();
+    log.record(&BootloaderEvent::EnteredBootloader);
+    log.record(&BootloaderEvent::Identified {
+        chip_id: 0x2000_8000,
+    });
+    log.record(&BootloaderEvent::BankErased);
+    log.record(&BootloaderEvent::ChunkWritten {
+        bytes_done: 252,
+        total: 252,
+    });
+    log.record(&BootloaderEvent::SegmentWritten {
+        addr: 0,
+        len: 252,
+        crc: 0x1234_5678,
+    });
+    log.record(&BootloaderEvent::Completed);
+
+    let events: Vec<&AuditEvent> = log.entries.iter().map(|entry| &entry.event).collect();
+    assert_eq!(
+        events,
+        [
+            &AuditEvent::Identified {
+                chip_id: 0x2000_8000
+            },
+            &AuditEvent::Erased { address: None },
+            &AuditEvent::Written {
+                address: 0,
+                len: 252,
+                crc: 0x1234_5678
+            },
+            &AuditEvent::Completed,
+        ]
+    );
+    assert!(log.entries.iter().all(|e| e.chip_id == Some(0x2000_8000)));
+}
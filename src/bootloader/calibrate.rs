@@ -0,0 +1,180 @@
+use bootloader::commands::{check_ack, Command, Download};
+use bootloader::{Bootloader, Error, Timeouts};
+use device;
+use firmware_image::Segment;
+use std::time::{Duration, Instant};
+use Cc131x;
+
+/*
+ *  Measures how long the connected chip actually takes to answer, instead of trusting numbers
+ *  tuned at 4 MHz on one board. Both routines scribble over the flash they are pointed at
+ *  and leave it erased, so they refuse to run without a Scratch
+ */
+
+// required by calibrate and bench, constructed only on purpose for flash the application does not use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scratch {
+    _private: (),
+}
+
+impl Scratch {
+    pub fn i_accept_losing_its_contents() -> Scratch {
+        Scratch { _private: () }
+    }
+}
+
+// latencies observed for a single command of each kind
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    pub command: Duration,
+    pub send_data: Duration,
+    pub erase: Duration,
+    pub crc: Duration,
+}
+
+impl Calibration {
+    // budgets margin times the measured latency, never below the floor of a millisecond
+    pub fn timeouts(&self, margin: u32) -> Timeouts {
+        const FLOOR: Duration = Duration::from_millis(1);
+        let budget = |measured: Duration| (measured * margin).max(FLOOR);
+        Timeouts {
+            command: budget(self.command),
+            send_data: budget(self.send_data),
+            erase: budget(self.erase),
+            crc: budget(self.crc),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub bytes: usize,
+    pub erase: Duration,
+    // download through CRC check
+    pub program: Duration,
+}
+
+impl BenchReport {
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.program.as_secs_f64()
+    }
+}
+
+impl Bootloader {
+    // sector is erased, written with one SendData packet and erased again
+    pub fn calibrate(io: &Cc131x, sector: u32, _scratch: Scratch) -> Result<Calibration, Error> {
+        let _bus = io.bus();
+        const PAYLOAD: [u8; 252] = [0xA5; 252];
+
+        let start = Instant::now();
+        Self::ping(io)?;
+        let command = start.elapsed();
+
+        let start = Instant::now();
+        Self::erase_sector(io, sector)?;
+        let erase = start.elapsed();
+
+        let packet = Download::new(sector, PAYLOAD.len() as u32).serialize_padded(io.clock_hz)?;
+        let response = io.write(&packet)?;
+        let response = Self::await_ack(io, "download", io.timeouts.command, response)?;
+        io.tally(check_ack(response))?;
+        let start = Instant::now();
+        Self::write_payload(io, &PAYLOAD)?;
+        let send_data = start.elapsed();
+        Self::check_status(io, "send_data")?;
+
+        let start = Instant::now();
+        Self::get_crc(io, sector, PAYLOAD.len() as u32)?;
+        let crc = start.elapsed();
+
+        Self::erase_sector(io, sector)?;
+        Self::system_reset(io)?;
+
+        let calibration = Calibration {
+            command,
+            send_data,
+            erase,
+            crc,
+        };
+        info!("calibrated {:?}", calibration);
+        Ok(calibration)
+    }
+
+    // programs len bytes of filler at start, the sectors involved are erased before and after
+    pub fn bench(
+        io: &Cc131x,
+        start: u32,
+        len: usize,
+        _scratch: Scratch,
+    ) -> Result<BenchReport, Error> {
+        let _bus = io.bus();
+        let info = Self::initialize(io)?;
+        let sectors = device::sectors_covering(start, len as u32, info.sector_size);
+
+        let begin = Instant::now();
+        for sector in &sectors {
+            Self::erase_sector(io, *sector)?;
+        }
+        let erase = begin.elapsed();
+
+        let segment = Segment::with_data(start as usize, vec![0xA5; len]);
+        let begin = Instant::now();
+        Self::write_segment(io, &segment)?;
+        let program = begin.elapsed();
+
+        for sector in &sectors {
+            Self::erase_sector(io, *sector)?;
+        }
+        Self::system_reset(io)?;
+
+        let report = BenchReport {
+            bytes: len,
+            erase,
+            program,
+        };
+        info!("bench: {:.0} bytes/s", report.bytes_per_sec());
+        Ok(report)
+    }
+}
+
+#[test]
+fn test_calibration_timeouts() {
+    let calibration = Calibration {
+        command: Duration::from_micros(100),
+        send_data: Duration::from_millis(2),
+        erase: Duration::from_millis(20),
+        crc: Duration::from_millis(5),
+    };
+    let timeouts = calibration.timeouts(4);
+    assert_eq!(timeouts.command, Duration::from_millis(1));
+    assert_eq!(timeouts.send_data, Duration::from_millis(8));
+    assert_eq!(timeouts.erase, Duration::from_millis(80));
+    assert_eq!(timeouts.crc, Duration::from_millis(20));
+}
+
+#[test]
+fn test_bytes_per_sec() {
+    let report = BenchReport {
+        bytes: 4096,
+        erase: Duration::from_millis(10),
+        program: Duration::from_millis(500),
+    };
+    assert_eq!(report.bytes_per_sec(), 8192.0);
+}
+
+#[test]
+fn test_calibrate_leaves_scratch_sector_erased() {
+    use mock::{MockChip, SECTOR_SIZE};
+
+    let chip = MockChip::new();
+    chip.load(0, &[0x11; 16]);
+    let io = MockChip::handle(&chip);
+    io.calibrate(0x4000, Scratch::i_accept_losing_its_contents())
+        .unwrap();
+
+    let flash = chip.flash();
+    assert!(flash[0x4000..0x4000 + SECTOR_SIZE]
+        .iter()
+        .all(|b| *b == 0xFF));
+    assert_eq!(&flash[..16], &[0x11; 16]);
+}
@@ -0,0 +1,133 @@
+use bootloader::verify::VerifyMode;
+use bootloader::{Bootloader, Error, FlashReport};
+use device::FlashLayout;
+use firmware_image::{FirmwareImage, Segment};
+use Cc131x;
+
+/*
+ *  Several images flashed in one bootloader session, e.g. the application and a separate hex of
+ *  calibration constants: one erase, every image written, all of them checked together, one reset
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CampaignOptions {
+    // erase the whole bank instead of only the sectors the images touch
+    pub erase_chip: bool,
+    // overrides Cc131x::verify for this campaign, CrcWholeImage falls back to CrcPerSegment without erase_chip
+    pub verify: Option<VerifyMode>,
+}
+
+// the images as one, refusing any two that have data at the same address
+pub fn combine(images: &[&FirmwareImage]) -> Result<FirmwareImage, Error> {
+    let mut segments: Vec<(usize, &Segment)> = images
+        .iter()
+        .enumerate()
+        .flat_map(|(index, image)| image.segments.iter().map(move |s| (index, s)))
+        .filter(|&(_, s)| !s.data.is_empty())
+        .collect();
+    segments.sort_by_key(|&(_, s)| s.start);
+    for pair in segments.windows(2) {
+        let (first, a) = pair[0];
+        let (second, b) = pair[1];
+        if a.start + a.data.len() > b.start {
+            return Err(Error::ImagesOverlap {
+                first: first.min(second),
+                second: first.max(second),
+                address: b.start as u32,
+            });
+        }
+    }
+
+    Ok(FirmwareImage {
+        segments: segments.into_iter().map(|(_, s)| s.clone()).collect(),
+    })
+}
+
+impl Bootloader {
+    pub(crate) fn flash_many(
+        io: &Cc131x,
+        images: &[&FirmwareImage],
+        options: CampaignOptions,
+    ) -> Result<FlashReport, Error> {
+        let _bus = io.bus();
+        let combined = combine(images)?;
+        let info = Bootloader::initialize(io)?;
+        let retries = io.retry_count();
+        let (firmware, skipped) = Self::flash_part(io, &combined, &FlashLayout::from(&info))?;
+        Self::check_cancelled(io)?;
+        if options.erase_chip {
+            Bootloader::erase_chip(io)?;
+        } else {
+            for sector in firmware.touched_sectors(info.sector_size) {
+                Self::check_cancelled(io)?;
+                Bootloader::erase_sector(io, sector as u32)?;
+            }
+        }
+        // sectors between the images that were not erased keep whatever they held,
+        // where the whole image CRC would expect them erased
+        let verify = match options.verify.unwrap_or(io.verify) {
+            VerifyMode::CrcWholeImage if !options.erase_chip => VerifyMode::CrcPerSegment,
+            verify => verify,
+        };
+        Self::write_image(io, &firmware, &info, verify)?;
+        Bootloader::system_reset(io)?;
+        Ok(FlashReport {
+            retries: io.retry_count() - retries,
+            skipped,
+        })
+    }
+}
+
+#[test]
+fn test_combine_images() {
+    let app = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0, vec![1; 0x100]),
+            Segment::with_data(0x1_FFA8, vec![2; 0x58]),
+        ],
+    };
+    let calibration = FirmwareImage {
+        segments: vec![Segment::with_data(0x100, vec![3; 0x10])],
+    };
+    let combined = combine(&[&app, &calibration]).unwrap();
+    let starts: Vec<usize> = combined.segments.iter().map(|s| s.start).collect();
+    assert_eq!(starts, [0, 0x100, 0x1_FFA8]);
+
+    let clash = FirmwareImage {
+        segments: vec![Segment::with_data(0x1_FFF0, vec![4; 4])],
+    };
+    match combine(&[&app, &calibration, &clash]) {
+        Err(Error::ImagesOverlap {
+            first: 0,
+            second: 2,
+            address: 0x1_FFF0,
+        }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_flash_many_without_chip_erase_on_mock() {
+    use mock::MockChip;
+
+    let chip = MockChip::new();
+    // an untouched sector between the two images
+    chip.load(0x3000, &[0; 16]);
+    let io = MockChip::handle(&chip);
+    let app = FirmwareImage {
+        segments: vec![Segment::with_data(0x1000, vec![1; 0x100])],
+    };
+    let calibration = FirmwareImage {
+        segments: vec![Segment::with_data(0x5000, vec![2; 0x10])],
+    };
+    let options = CampaignOptions {
+        erase_chip: false,
+        verify: Some(VerifyMode::CrcWholeImage),
+    };
+    io.flash_many(&[&app, &calibration], options).unwrap();
+
+    let flash = chip.flash();
+    assert_eq!(&flash[0x1000..0x1100], &app.segments[0].data[..]);
+    assert_eq!(&flash[0x3000..0x3010], &[0; 16]);
+    assert_eq!(&flash[0x5000..0x5010], &calibration.segments[0].data[..]);
+}
@@ -0,0 +1,34 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/*
+ *  A CancelToken lets a supervisor (e.g. a SIGTERM handler) stop a long operation between chunks
+ *  The operation then fails with Error::Cancelled and the bootloader session resets the chip on the way out
+ */
+
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    // every clone of the token observes the cancellation
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_cancel_is_shared() {
+    let token = CancelToken::new();
+    let handle = token.clone();
+    assert!(!token.is_cancelled());
+    handle.cancel();
+    assert!(token.is_cancelled());
+}
@@ -0,0 +1,123 @@
+use bootloader::commands::StatusValue;
+use bootloader::retry::is_protocol_error;
+use bootloader::{Bootloader, Error};
+use device::{self, ChipFamily, FlashLayout};
+use firmware_image::FirmwareImage;
+use Cc131x;
+
+/*
+ *  What the ROM bootloader on the connected chip can do, as far as it can be asked
+ *  A ROM without MemoryRead ACKs the packet but never answers it, and GetStatus then reports UnknownCmd
+ */
+
+// SendData and MemoryRead payloads, bounded by the 255 byte packet
+const MAX_PAYLOAD: usize = 252;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootloaderCapabilities {
+    pub chip_id: u32,
+    // None when the device ID could not be read or its wafer ID is unknown
+    pub family: Option<ChipFamily>,
+    // PG_REV of ICEPICK_DEVICE_ID
+    pub revision: Option<u8>,
+    pub memory_read: bool,
+    // arrived in the same ROM revisions as MemoryRead, and cannot be tried without side effects
+    pub memory_write: bool,
+    pub max_payload: usize,
+}
+
+impl BootloaderCapabilities {
+    // device_id is None when MemoryRead is unsupported
+    pub fn new(chip_id: u32, device_id: Option<u32>) -> BootloaderCapabilities {
+        BootloaderCapabilities {
+            chip_id,
+            family: device_id
+                .and_then(|id| ChipFamily::from_wafer_id(((id >> 12) & 0xFFFF) as u16)),
+            revision: device_id.map(|id| (id >> 28) as u8),
+            memory_read: device_id.is_some(),
+            memory_write: device_id.is_some(),
+            max_payload: MAX_PAYLOAD,
+        }
+    }
+}
+
+impl Bootloader {
+    pub fn capabilities(io: &Cc131x) -> Result<BootloaderCapabilities, Error> {
+        let _bus = io.bus();
+        Self::ping(io)?;
+        let chip_id = Self::chip_id(io)?;
+        let device_id = match Self::read_word(io, device::ICEPICK_DEVICE_ID) {
+            Ok(device_id) => Some(device_id),
+            Err(e) => {
+                if !is_protocol_error(&e) {
+                    return Err(e);
+                }
+                match Self::get_status(io)? {
+                    StatusValue::UnknownCmd | StatusValue::InvalidCmd => None,
+                    _ => return Err(e),
+                }
+            }
+        };
+        let caps = BootloaderCapabilities::new(chip_id, device_id);
+        debug!("bootloader capabilities: {:?}", caps);
+        Ok(caps)
+    }
+
+    // verify_exact for ROMs without MemoryRead: every segment's CRC32 instead of its bytes
+    // the chip cannot be identified without MemoryRead, so the profile's memory map is used, or that of
+    // the largest CC13x2/CC26x2
+    pub(crate) fn verify_crc(io: &Cc131x, firmware: &FirmwareImage) -> Result<(), Error> {
+        let layout = io
+            .profile
+            .as_ref()
+            .map_or_else(|| ChipFamily::Cc13x2.layout(), FlashLayout::from);
+        let (firmware, _) = firmware.classify(&layout);
+        for segment in &firmware.segments {
+            Self::check_cancelled(io)?;
+            let address = segment.start as u32;
+            let (expected, actual) = Self::compare_crc(io, segment)?;
+            if actual != expected {
+                Bootloader::system_reset(io)?;
+                return Err(Error::CrcMismatch {
+                    address,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Bootloader::system_reset(io)
+    }
+}
+
+#[test]
+fn test_capabilities() {
+    // CC1310 PG2.1
+    let caps = BootloaderCapabilities::new(0x2000_8000, Some(0xBB9B_E02F));
+    assert_eq!(caps.family, Some(ChipFamily::Cc13x0));
+    assert_eq!(caps.revision, Some(0xB));
+    assert!(caps.memory_read && caps.memory_write);
+
+    let caps = BootloaderCapabilities::new(0x2000_8000, None);
+    assert_eq!(caps.family, None);
+    assert!(!caps.memory_read);
+    assert_eq!(caps.max_payload, 252);
+}
+
+#[test]
+fn test_capabilities_without_memory_read_on_mock() {
+    use mock::{MockChip, CHIP_ID};
+
+    let chip = MockChip::new();
+    chip.without_memory_read();
+    let io = MockChip::handle(&chip);
+    let session = io.enter_bootloader().unwrap();
+    let caps = Bootloader::capabilities(&io).unwrap();
+    session.finish();
+    assert_eq!(caps, BootloaderCapabilities::new(CHIP_ID, None));
+
+    let chip = MockChip::new();
+    let io = MockChip::handle(&chip);
+    let session = io.enter_bootloader().unwrap();
+    assert!(Bootloader::capabilities(&io).unwrap().memory_read);
+    session.finish();
+}
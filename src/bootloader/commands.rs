@@ -1,10 +1,14 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
 use std::io::Error as IoError;
-use std::io::{Cursor, Read};
 
 /*
  *  This module translates each TI boolotader commands into a type, allowing for serialize/deserialize
- *  It's my personal experiment in macros
+ *  It's my personal experiment in macros, see commands! below for how a packet type is declared
  */
 
 pub trait CommandDef: Sized {
@@ -12,9 +16,11 @@ pub trait CommandDef: Sized {
     const CMD: u8;
     const MIN_LEN: u8;
     const MAX_LEN: u8;
-    // NULL_BYTES are clocked out so as to receive the ACK (and response payload as well)
-    // note: some commands require a delay, so NULL_BYTES may be 0 and instead the parent bootloader module handles the delay
-    const NULL_BYTES: usize;
+    // bytes the bootloader answers with right away: the ACK, plus the data packet of commands that respond
+    // 0 for commands only answered once they complete (erase, CRC, programming), whose answer is polled for
+    const REPLY_LEN: usize;
+    // commands answered with a data packet after the ACK, which the host has to ACK in turn
+    const RESPONDS: bool;
     fn into_payload(self) -> Result<Option<Vec<u8>>, Error>;
 }
 
@@ -22,6 +28,7 @@ pub trait CommandDef: Sized {
 pub enum Error {
     MaxPayloadExceeded,
     MinPayloadNotMet,
+    #[cfg(feature = "std")]
     IO(IoError),
     NoAck,
     Nack,
@@ -32,37 +39,119 @@ pub enum Error {
     InvalidStatusCode,
 }
 
+#[cfg(feature = "std")]
 impl From<IoError> for Error {
     fn from(err: IoError) -> Error {
         Error::IO(err)
     }
 }
 
-pub fn check_ack(from_bus: Vec<u8>) -> Result<Cursor<Vec<u8>>, Error> {
-    const ACK_BYTE: u8 = 0xCC;
-    const NACK_BYTE: u8 = 0x33;
-    // search for checksum
-    let mut rdr = Cursor::new(from_bus);
-    loop {
-        if let Ok(cur) = rdr.read_u8() {
-            if cur == ACK_BYTE {
-                break;
-            } else if cur == NACK_BYTE {
-                return Err(Error::Nack);
-            }
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::MaxPayloadExceeded => write!(f, "payload exceeds the packet size"),
+            Error::MinPayloadNotMet => write!(f, "payload is shorter than the command requires"),
+            #[cfg(feature = "std")]
+            Error::IO(ref err) => write!(f, "packet I/O failed: {}", err),
+            Error::NoAck => write!(f, "no ACK received"),
+            Error::Nack => write!(f, "bootloader answered with a NACK"),
+            Error::BadChecksum => write!(f, "bad packet checksum"),
+            Error::BadCmdByte => write!(f, "unexpected command byte"),
+            Error::PacketTooShort => write!(f, "packet too short"),
+            Error::InvalidCmdStatus => write!(f, "invalid command status"),
+            Error::InvalidStatusCode => write!(f, "invalid status code"),
         }
-        // if we did not read a value, we got to end with NoAck
-        else {
-            return Err(Error::NoAck);
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IO(ref err) => Some(err),
+            _ => None,
         }
     }
-    Ok(rdr)
 }
 
-pub trait Command: CommandDef {
-    const PACKET_SIZE_INDEX: usize = 0;
-    const CHECKSUM_INDEX: usize = 1;
+pub const ACK_BYTE: u8 = 0xCC;
+pub const NACK_BYTE: u8 = 0x33;
+
+// true once the bootloader has answered at all, it clocks out 0x00 while busy
+pub fn has_ack(from_bus: &[u8]) -> bool {
+    from_bus.iter().any(|b| *b == ACK_BYTE || *b == NACK_BYTE)
+}
+
+// a command packet rather than a poll or the host's own ACK: size, checksum, then a command byte
+pub fn is_command_packet(from_host: &[u8]) -> bool {
+    from_host.len() >= 3 && from_host[0] >= 3 && (0x20..=0x2F).contains(&from_host[2])
+}
+
+// returns whatever was clocked in after the ACK
+pub fn check_ack(from_bus: Vec<u8>) -> Result<Vec<u8>, Error> {
+    // search for checksum
+    match from_bus
+        .iter()
+        .position(|b| *b == ACK_BYTE || *b == NACK_BYTE)
+    {
+        Some(pos) if from_bus[pos] == ACK_BYTE => Ok(from_bus[pos + 1..].to_vec()),
+        Some(_) => Err(Error::Nack),
+        // if we did not find a value, we got to end with NoAck
+        None => Err(Error::NoAck),
+    }
+}
+
+// the data packet after the ACK, the bootloader may clock out zeros while it is still preparing it
+fn after_ack(from_bus: &[u8]) -> Option<&[u8]> {
+    let pos = from_bus
+        .iter()
+        .position(|b| *b == ACK_BYTE || *b == NACK_BYTE)?;
+    let rest = &from_bus[pos + 1..];
+    let start = rest.iter().position(|b| *b != 0).unwrap_or(rest.len());
+    Some(&rest[start..])
+}
+
+// how many more bytes to clock in before from_bus holds the ACK and the whole data packet after it
+// a guess of 2 while the size byte has not arrived, 0 after a NACK, which carries no packet
+pub fn response_shortfall(from_bus: &[u8]) -> usize {
+    match from_bus
+        .iter()
+        .find(|b| **b == ACK_BYTE || **b == NACK_BYTE)
+    {
+        Some(&NACK_BYTE) => return 0,
+        None => return 2,
+        Some(_) => {}
+    }
+    match after_ack(from_bus) {
+        Some(packet) if !packet.is_empty() => (packet[0] as usize).saturating_sub(packet.len()),
+        _ => 2,
+    }
+}
 
+// how long the bootloader takes from the last byte of a packet to the first byte of its reply
+const REPLY_LATENCY_US: u64 = 70;
+
+// zeros to clock out after a packet so the reply comes back in the same transfer
+// erring short only costs one more poll in the bootloader module
+pub fn reply_padding(reply_len: usize, clock_hz: u32) -> usize {
+    if reply_len == 0 {
+        return 0;
+    }
+    let latency = (clock_hz as u64 / 8 * REPLY_LATENCY_US).div_ceil(1_000_000);
+    reply_len + latency as usize
+}
+
+// splits n bytes off the front of a packet being parsed
+fn take<'a>(rdr: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+    if rdr.len() < n {
+        return Err(Error::PacketTooShort);
+    }
+    let (head, tail) = rdr.split_at(n);
+    *rdr = tail;
+    Ok(head)
+}
+
+pub trait Command: CommandDef {
     fn serialize(self) -> Result<Vec<u8>, Error> {
         // serializes everything after the CMD byte
         let payload = self.into_payload()?;
@@ -87,24 +176,32 @@ pub trait Command: CommandDef {
             output.append(&mut payload);
         }
 
-        output.resize(size as usize + Self::NULL_BYTES, 0);
         Ok(output)
     }
 
-    fn read_header(from_bus: Vec<u8>) -> Result<(Vec<u8>), Error> {
+    // followed by enough zeros to clock the reply in with the same transfer at clock_hz
+    fn serialize_padded(self, clock_hz: u32) -> Result<Vec<u8>, Error> {
+        let mut output = self.serialize()?;
+        let len = output.len() + reply_padding(Self::REPLY_LEN, clock_hz);
+        output.resize(len, 0);
+        Ok(output)
+    }
+
+    fn read_header(from_bus: Vec<u8>) -> Result<Vec<u8>, Error> {
         // create the packet with header
         // byte[0] = packet size
         // byte[1] = packet checksum
         // byte[2..N] = Option<payload>
         // NOTE: no command byte
 
-        // helper verifies ACK byte and returns reader after it
-        let mut rdr = check_ack(from_bus)?;
+        // helper verifies ACK byte, the packet starts after any zeros clocked out while it was prepared
+        check_ack(from_bus.clone())?;
+        let mut rdr = after_ack(&from_bus).unwrap_or(&[]);
 
         // first byte is packet size
-        let length = rdr.read_u8()? as usize;
+        let length = take(&mut rdr, 1)?[0] as usize;
         // second byte is checksum
-        let checksum = rdr.read_u8()?;
+        let checksum = take(&mut rdr, 1)?[0];
 
         if length < (Self::MIN_LEN as usize - 1) {
             return Err(Error::MinPayloadNotMet);
@@ -113,11 +210,7 @@ pub trait Command: CommandDef {
         }
 
         const BYTES_NIBBLED: usize = 2;
-        let mut payload = vec![0; length - BYTES_NIBBLED];
-        let bytes_read = rdr.read(payload.as_mut_slice())?;
-        if bytes_read != length - BYTES_NIBBLED {
-            return Err(Error::PacketTooShort);
-        }
+        let payload = take(&mut rdr, length - BYTES_NIBBLED)?.to_vec();
 
         // initialize checksum calculation with CMD byte
         let mut checksum_calc = 0;
@@ -131,256 +224,310 @@ pub trait Command: CommandDef {
     }
 }
 
-use num_traits::FromPrimitive;
-#[derive(Primitive, Debug, PartialEq)]
+// COMMAND_RET_* codes from the ROM bootloader documentation
+// anything else is kept as Unknown so the raw byte still shows up in error reports
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum StatusValue {
-    Default = 0,
-    Success = 0x40,
-    UnknownCmd = 0x41,
-    InvalidCmd = 0x42,
-    InvalidAddr = 0x43,
-    FlashFail = 0x44,
+    #[default]
+    Default,
+    Success,
+    UnknownCmd,
+    InvalidCmd,
+    InvalidAddr,
+    FlashFail,
+    Unknown(u8),
 }
 
-impl Default for StatusValue {
-    fn default() -> StatusValue {
-        StatusValue::Default
+impl StatusValue {
+    pub fn from_byte(byte: u8) -> StatusValue {
+        match byte {
+            0x00 => StatusValue::Default,
+            0x40 => StatusValue::Success,
+            0x41 => StatusValue::UnknownCmd,
+            0x42 => StatusValue::InvalidCmd,
+            0x43 => StatusValue::InvalidAddr,
+            0x44 => StatusValue::FlashFail,
+            other => StatusValue::Unknown(other),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            StatusValue::Default => 0x00,
+            StatusValue::Success => 0x40,
+            StatusValue::UnknownCmd => 0x41,
+            StatusValue::InvalidCmd => 0x42,
+            StatusValue::InvalidAddr => 0x43,
+            StatusValue::FlashFail => 0x44,
+            StatusValue::Unknown(byte) => byte,
+        }
     }
 }
 
-enum CommandFields {
-    Sma(u8),
-    Med(u16),
-    Big(u32),
-    Vector(Vec<u8>),
-    StatusValue(StatusValue),
+// a single field of a command or response payload, sent big endian
+pub trait Field: Default {
+    // bytes taken by a fixed-size field, 0 for the trailing variable-length one
+    const SIZE: usize;
+    fn write(&self, out: &mut Vec<u8>);
+    // remaining is what is left of the payload, variable-length fields take all of it
+    fn read(rdr: &mut &[u8], remaining: usize) -> Result<Self, Error>;
+    // non-default value for the generated round trip test
+    #[cfg(test)]
+    fn sample() -> Self;
 }
 
-macro_rules! commandFieldFrom {
-    ($id:ident, $type:ty) => {
-        impl From<$type> for CommandFields {
-            fn from(thing: $type) -> CommandFields {
-                CommandFields::$id(thing)
-            }
-        }
-        impl From<CommandFields> for $type {
-            fn from(field: CommandFields) -> $type {
-                if let CommandFields::$id(v) = field {
-                    v
-                } else {
-                    panic!("Failed at unwrapping CommandField enum");
-                }
-            }
-        }
-    };
+impl Field for u8 {
+    const SIZE: usize = 1;
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+    fn read(rdr: &mut &[u8], _remaining: usize) -> Result<u8, Error> {
+        Ok(take(rdr, 1)?[0])
+    }
+    #[cfg(test)]
+    fn sample() -> u8 {
+        0x5A
+    }
 }
 
-commandFieldFrom!(Sma, u8);
-commandFieldFrom!(Med, u16);
-commandFieldFrom!(Big, u32);
-commandFieldFrom!(Vector, Vec<u8>);
-commandFieldFrom!(StatusValue, StatusValue);
-
-fn serializer(vec: &mut Vec<u8>, input: &CommandFields) -> Result<(), Error> {
-    use self::CommandFields::*;
-    match *input {
-        Sma(u) => vec.push(u),
-        Med(u) => vec.write_u16::<BigEndian>(u)?,
-        Big(u) => vec.write_u32::<BigEndian>(u)?,
-        Vector(ref v) => {
-            let mut vec_clone = v.clone();
-            vec.append(&mut vec_clone);
-        }
-        _ => panic!("Status is never seralized"),
+impl Field for u16 {
+    const SIZE: usize = 2;
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+    fn read(rdr: &mut &[u8], _remaining: usize) -> Result<u16, Error> {
+        Ok(BigEndian::read_u16(take(rdr, 2)?))
+    }
+    #[cfg(test)]
+    fn sample() -> u16 {
+        0x5AA5
+    }
+}
+
+impl Field for u32 {
+    const SIZE: usize = 4;
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+    fn read(rdr: &mut &[u8], _remaining: usize) -> Result<u32, Error> {
+        Ok(BigEndian::read_u32(take(rdr, 4)?))
+    }
+    #[cfg(test)]
+    fn sample() -> u32 {
+        0x1234_5678
     }
-    Ok(())
 }
 
-fn deserializer(
-    rdr: &mut Cursor<Vec<u8>>,
-    input: &mut CommandFields,
-    count: usize,
-) -> Result<(), Error> {
-    use self::CommandFields::*;
+impl Field for Vec<u8> {
+    const SIZE: usize = 0;
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+    fn read(rdr: &mut &[u8], remaining: usize) -> Result<Vec<u8>, Error> {
+        Ok(take(rdr, remaining)?.to_vec())
+    }
+    #[cfg(test)]
+    fn sample() -> Vec<u8> {
+        vec![0xA5]
+    }
+}
 
-    match *input {
-        Sma(ref mut u) => *u = rdr.read_u8()?,
-        Med(ref mut u) => *u = rdr.read_u16::<BigEndian>()?,
-        Big(ref mut u) => *u = rdr.read_u32::<BigEndian>()?,
-        Vector(ref mut v) => {
-            v.resize(count, 0);
-            rdr.read_exact(&mut v.as_mut_slice())?;
-        }
-        StatusValue(ref mut s) => {
-            let status_byte = rdr.read_u8()?;
-            let status_value = self::StatusValue::from_u8(status_byte);
-            match status_value {
-                Some(v) => *s = v,
-                None => return Err(Error::InvalidStatusCode),
-            }
-            //*s = status_value.into() ;
-        }
+impl Field for StatusValue {
+    const SIZE: usize = 1;
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.to_byte());
+    }
+    fn read(rdr: &mut &[u8], _remaining: usize) -> Result<StatusValue, Error> {
+        Ok(StatusValue::from_byte(take(rdr, 1)?[0]))
+    }
+    #[cfg(test)]
+    fn sample() -> StatusValue {
+        StatusValue::Success
     }
-    Ok(())
 }
 
-macro_rules! command {
-    ($i:ident, $e:expr, $null:expr, $min:expr, $max:expr; $($arg_name:ident, $arg_type:ty),*) => {
-        pub struct $i {
-            $(pub $arg_name: $arg_type),*
+/*
+ *  Each entry declares one packet type: its command byte, how many bytes it is answered with right away,
+ *  whether a data packet follows the ACK, the allowed packet length (size, checksum and command byte
+ *  included) and its fields in wire order
+ *  Lengths are checked against the field sizes at compile time, and every packet type gets a round trip test
+ */
+macro_rules! commands {
+    ($(
+        $i:ident {
+            cmd: $cmd:expr,
+            reply: $reply:expr,
+            responds: $responds:expr,
+            len: $min:expr, $max:expr,
+            fields: { $($arg_name:ident: $arg_type:ty),* }
         }
+    )*) => {
+        $(
+            #[derive(Debug, Default, PartialEq)]
+            pub struct $i {
+                $(pub $arg_name: $arg_type),*
+            }
+
+            // fixed fields have to fit, and without a variable-length field they have to fill the packet exactly
+            const _: () = {
+                let fixed = 3 $(+ <$arg_type as Field>::SIZE)*;
+                let variable = false $(|| <$arg_type as Field>::SIZE == 0)*;
+                assert!($min <= $max && $max <= 255);
+                assert!(fixed <= $min);
+                assert!(variable || fixed == $max);
+            };
 
-        impl CommandDef for $i {
-            const CMD: u8 = $e;
-            const NULL_BYTES: usize = $null;
-            const MIN_LEN: u8 = $min;
-            const MAX_LEN: u8 = $max;
-            fn into_payload(self) -> Result<Option<Vec<u8>>, Error> {
-                // macros are kind of dumb
-                #[allow(unused_mut)]
-                let mut payload: Vec<u8> = Vec::new();
-                $(serializer(&mut payload, &self.$arg_name.into())?;)*
-                let len = payload.len();
-                if len + 3 < (Self::MIN_LEN as usize) {
-                    return Err(Error::MinPayloadNotMet);
+            impl CommandDef for $i {
+                const CMD: u8 = $cmd;
+                const REPLY_LEN: usize = $reply;
+                const RESPONDS: bool = $responds;
+                const MIN_LEN: u8 = $min;
+                const MAX_LEN: u8 = $max;
+                fn into_payload(self) -> Result<Option<Vec<u8>>, Error> {
+                    #[allow(unused_mut)]
+                    let mut payload: Vec<u8> = Vec::new();
+                    $(Field::write(&self.$arg_name, &mut payload);)*
+                    let len = payload.len();
+                    if len + 3 < (Self::MIN_LEN as usize) {
+                        return Err(Error::MinPayloadNotMet);
+                    } else if len + 3 > Self::MAX_LEN as usize {
+                        return Err(Error::MaxPayloadExceeded);
+                    }
+                    if len == 0 {
+                        Ok(None)
+                    } else {
+                        Ok(Some(payload))
+                    }
                 }
-                else if len + 3 > Self::MAX_LEN as usize {
-                    return Err(Error::MaxPayloadExceeded);
+            }
+
+            impl Command for $i {}
+
+            impl $i {
+                #[allow(dead_code)]
+                #[allow(clippy::too_many_arguments)]
+                pub fn new($($arg_name: $arg_type),*) -> $i {
+                    $i { $($arg_name),* }
                 }
-                if len == 0 {
-                    return Ok(None);
+
+                #[allow(dead_code)]
+                pub fn from_payload(from_bus: Vec<u8>) -> Result<$i, Error> {
+                    let payload = Self::read_header(from_bus)?;
+                    #[allow(unused_variables, unused_mut)]
+                    let mut rdr = payload.as_slice();
+                    $(
+                        let remaining = rdr.len();
+                        let $arg_name = <$arg_type as Field>::read(&mut rdr, remaining)?;
+                    )*
+                    Ok($i { $($arg_name),* })
                 }
-                else{
-                    Ok(Some(payload))
+
+                // serializes a sample, then parses its payload back as if the bootloader had sent it
+                #[cfg(test)]
+                fn round_trip() {
+                    let sample = $i { $($arg_name: <$arg_type as Field>::sample()),* };
+                    let payload = $i { $($arg_name: <$arg_type as Field>::sample()),* }
+                        .into_payload()
+                        .unwrap()
+                        .unwrap_or_default();
+                    let checksum = payload.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+                    let mut from_bus = vec![ACK_BYTE, payload.len() as u8 + 2, checksum];
+                    from_bus.extend_from_slice(&payload);
+                    assert_eq!($i::from_payload(from_bus).unwrap(), sample, stringify!($i));
                 }
             }
+        )*
+
+        #[test]
+        fn test_round_trip() {
+            $($i::round_trip();)*
         }
-        impl Command for $i {}
-        impl $i {
-            #[allow(dead_code)] // macros like to complain about unused code that is used
-            pub fn new($($arg_name: $arg_type),*) -> $i {
-                $i { $($arg_name),*}
-            }
-            #[allow(dead_code)]
-            #[allow(unused_mut)]
-            pub fn from_payload(mut from_bus: Vec<u8>) -> Result<$i, Error> {
-                let payload = Self::read_header(from_bus)?;
-                $(let mut $arg_name: $arg_type = Default::default();)*
-                #[allow(unused_variables)] // macros like to complain about unused code that is used
-                let len = payload.len();
-                #[allow(unused_variables)] // macros like to complain about unused code that is used
-                let mut rdr = Cursor::new(payload);
-                $(
-                    let pos = rdr.position() as usize;
-                    let mut tmp = $arg_name.into();
-                    deserializer(&mut rdr, &mut tmp, len - pos)?;
-                    $arg_name = tmp.into();
-                )*
-                return Ok(
-                        $i {
-                    $($arg_name),*
-                            }
-                )
-            }
-        }
-    };
-    ($i:ident, $e:expr, $null:expr, $fix:expr; $($arg_name:ident, $arg_type:ty),*) =>{
-        command![$i, $e, $null, $fix, $fix; $($arg_name, $arg_type),*];
-    };
-    ($i:ident, $e:expr, $null:expr) => {
-        command![$i, $e, $null, 3, 3; ];
     };
 }
 
-command!(Ping, 0x20, 36);
-command!(
-    Download,
-    0x21,       // command byte
-    24,          // num null bytes
-    11;         // fixed payload size
-    address,   // serializer arg 1
-    u32,        // serializer type arg1
-    size,     // serializer arg 2
-    u32         // serializer type arg2
-);
-command!(GetStatus, 0x23, 32);
-command!(
-    SendData,
-    0x24,   // command byte
-    0,     // num null bytes
-    4,      // min payload
-    255;    // max payload
-    data,   // serializer arg 1
-    Vec<u8> // serializer type 1
-    );
-command!(Reset, 0x25, 32);
-command!(
-    SectorErase,
-    0x26,
-    0,
-    7;
-    address,
-    u32
-);
-command!(
-    Crc32,
-    0x27,
-    0,
-    15;
-    address,
-    u32,
-    size,
-    u32,
-    repeat,
-    u32
-    );
-command!(ChipId, 0x20, 0, 7; value, u32);
-command!(GetChipId, 0x28, 42);
-command!(
-    MemoryRead,
-    0x2A,
-    272,
-    9;
-    address,
-    u32,
-    access_type,
-    u8,
-    size,
-    u8
-    );
-command!(
-    MemoryWrite,
-    0x2B,
-    50,
-    9,
-    255;
-    address,
-    u32,
-    size,
-    u32
-);
-command!(
-    BankErase,
-    0x2C,
-    0,
-    3;
-);
-command!(
-    Crc32Response,
-    0x00,
-    0,
-    7;
-    value,
-    u32
-);
-command!(
-    CommandStatus,
-    0x00,
-    0,
-    4;
-    value,
-    StatusValue
-);
+commands! {
+    Ping { cmd: 0x20, reply: 2, responds: false, len: 3, 3, fields: {} }
+    Download {
+        cmd: 0x21,
+        reply: 2,
+        responds: false,
+        len: 11, 11,
+        fields: { address: u32, size: u32 }
+    }
+    GetStatus { cmd: 0x23, reply: 5, responds: true, len: 3, 3, fields: {} }
+    SendData { cmd: 0x24, reply: 0, responds: false, len: 4, 255, fields: { data: Vec<u8> } }
+    Reset { cmd: 0x25, reply: 2, responds: false, len: 3, 3, fields: {} }
+    SectorErase { cmd: 0x26, reply: 0, responds: false, len: 7, 7, fields: { address: u32 } }
+    Crc32 {
+        cmd: 0x27,
+        reply: 0,
+        responds: true,
+        len: 15, 15,
+        fields: { address: u32, size: u32, repeat: u32 }
+    }
+    ChipId { cmd: 0x20, reply: 0, responds: false, len: 7, 7, fields: { value: u32 } }
+    GetChipId { cmd: 0x28, reply: 8, responds: true, len: 3, 3, fields: {} }
+    MemoryRead {
+        cmd: 0x2A,
+        reply: 257,
+        responds: true,
+        len: 9, 9,
+        fields: { address: u32, access_type: u8, size: u8 }
+    }
+    MemoryWrite {
+        cmd: 0x2B,
+        reply: 2,
+        responds: false,
+        len: 9, 255,
+        fields: { address: u32, access_type: u8, data: Vec<u8> }
+    }
+    BankErase { cmd: 0x2C, reply: 0, responds: false, len: 3, 3, fields: {} }
+    SetCcfg {
+        cmd: 0x2D,
+        reply: 0,
+        responds: false,
+        len: 11, 11,
+        fields: { field_id: u32, value: u32 }
+    }
+    // responses, the command byte is never on the wire
+    Crc32Response { cmd: 0x00, reply: 0, responds: false, len: 7, 7, fields: { value: u32 } }
+    MemoryReadResponse {
+        cmd: 0x00,
+        reply: 0,
+        responds: false,
+        len: 4, 255,
+        fields: { data: Vec<u8> }
+    }
+    CommandStatus {
+        cmd: 0x00,
+        reply: 0,
+        responds: false,
+        len: 4, 4,
+        fields: { value: StatusValue }
+    }
+}
+
+impl SendData {
+    // builds the packet straight from a borrowed chunk, so flashing never copies a segment around
+    pub fn serialize_from(data: &[u8]) -> Result<Vec<u8>, Error> {
+        let size = data.len() + Self::BASE_PACKET_SIZE as usize;
+        if size < Self::MIN_LEN as usize {
+            return Err(Error::MinPayloadNotMet);
+        } else if size > Self::MAX_LEN as usize {
+            return Err(Error::MaxPayloadExceeded);
+        }
+
+        let mut checksum = Self::CMD;
+        for i in data {
+            checksum = ((checksum as usize) + (*i as usize)) as u8;
+        }
+
+        let mut output = Vec::with_capacity(size);
+        output.extend_from_slice(&[size as u8, checksum, Self::CMD]);
+        output.extend_from_slice(data);
+        Ok(output)
+    }
+}
 
 #[test]
 fn test_bl_packet_serializer() {
@@ -436,3 +583,99 @@ fn test_bl_packet_deserializer() {
     assert_eq!(response.address, 0x3030);
     assert_eq!(response.size, 0xABAB);
 }
+
+#[test]
+fn test_memory_write_serializer() {
+    let cmd = MemoryWrite::new(0x2000_0000, 1, vec![0x01, 0x02, 0x03, 0x04]);
+
+    let packet: Vec<u8> = cmd.serialize().unwrap();
+    let checksum = (0x2B + 0x20 + 0x01 + 0x01 + 0x02 + 0x03 + 0x04) & 0xFF;
+    assert_eq!(
+        &packet[..12],
+        [
+            12, // packet length
+            checksum as u8,
+            0x2B, // command byte
+            0x20, // MSB address
+            0x00,
+            0x00,
+            0x00, // LSB address
+            0x01, // 32-bit access
+            0x01,
+            0x02,
+            0x03,
+            0x04
+        ]
+    );
+}
+
+#[test]
+fn test_has_ack() {
+    assert!(!has_ack(&[0, 0, 0, 0]));
+    assert!(has_ack(&[0, 0, ACK_BYTE, 3]));
+    assert!(has_ack(&[0, NACK_BYTE, 0]));
+}
+
+#[test]
+fn test_response_shortfall() {
+    assert_eq!(response_shortfall(&[0, 0]), 2);
+    assert_eq!(response_shortfall(&[0, NACK_BYTE]), 0);
+    assert_eq!(response_shortfall(&[0, ACK_BYTE, 0, 0]), 2);
+    // CommandStatus: size 3, checksum 0x40, status 0x40
+    assert_eq!(response_shortfall(&[ACK_BYTE, 0, 3]), 2);
+    assert_eq!(response_shortfall(&[ACK_BYTE, 0, 3, 0x40]), 1);
+    assert_eq!(response_shortfall(&[0, ACK_BYTE, 0, 3, 0x40, 0x40]), 0);
+    assert_eq!(response_shortfall(&[ACK_BYTE, 3, 0x40, 0x40, 0, 0]), 0);
+    assert_eq!(
+        CommandStatus::from_payload(vec![0, ACK_BYTE, 0, 3, 0x40, 0x40])
+            .unwrap()
+            .value,
+        StatusValue::Success
+    );
+}
+
+#[test]
+fn test_send_data_serialize_from() {
+    let data: Vec<u8> = (0..252).map(|i| i as u8).collect();
+    assert_eq!(
+        SendData::serialize_from(&data).unwrap(),
+        SendData::new(data.clone()).serialize().unwrap()
+    );
+    assert!(SendData::serialize_from(&[0; 253]).is_err());
+}
+
+#[test]
+fn test_unknown_status_keeps_raw_byte() {
+    for byte in 0..=255u8 {
+        assert_eq!(StatusValue::from_byte(byte).to_byte(), byte);
+    }
+    // ACK, size, checksum, status
+    let status = CommandStatus::from_payload(vec![0, 0xCC, 3, 0x45, 0x45]).unwrap();
+    assert_eq!(status.value, StatusValue::Unknown(0x45));
+}
+
+#[test]
+fn test_responds() {
+    let responds = [
+        GetStatus::RESPONDS,
+        MemoryRead::RESPONDS,
+        Crc32::RESPONDS,
+        GetChipId::RESPONDS,
+        Ping::RESPONDS,
+        SendData::RESPONDS,
+        SectorErase::RESPONDS,
+    ];
+    assert_eq!(responds, [true, true, true, true, false, false, false]);
+}
+
+#[test]
+fn test_reply_padding() {
+    // at 4 MHz the padding matches what was tuned by hand for GetChipId
+    assert_eq!(reply_padding(GetChipId::REPLY_LEN, 4_000_000), 43);
+    // and grows with the clock
+    assert_eq!(reply_padding(Ping::REPLY_LEN, 8_000_000), 72);
+    assert_eq!(reply_padding(SectorErase::REPLY_LEN, 8_000_000), 0);
+    let packet = GetStatus::new().serialize_padded(1_000_000).unwrap();
+    assert_eq!(packet.len(), 3 + 5 + 9);
+    assert!(packet[3..].iter().all(|b| *b == 0));
+}
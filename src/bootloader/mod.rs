@@ -1,24 +1,268 @@
-mod commands;
+pub mod calibrate;
+pub mod campaign;
+pub mod cancel;
+pub mod caps;
+pub mod commands;
+pub mod progress;
+pub mod protect;
+pub mod recover;
+pub mod resume;
+pub mod retry;
+pub mod sram;
+pub mod stub;
+pub mod verify;
 use bootloader::commands::Error as BlPkError;
 use bootloader::commands::*;
+pub use bootloader::commands::{Command, CommandDef, StatusValue};
+use bootloader::progress::{BootloaderEvent, Progress, ProgressEvent};
+use bootloader::resume::{FlashProgress, StateStore};
+use bootloader::retry::{is_protocol_error, is_rejected};
+use bootloader::verify::VerifyMode;
 
-use firmware_image::Segment;
-use std::io;
-use std::{thread, time};
+use bundle::FirmwareBundle;
+use byteorder::{ByteOrder, LittleEndian};
+use device::{self, ChipFamily, DeviceInfo, FlashLayout, Region};
+use firmware_image::{ihex_records, Segment, SkippedSegment, DEFAULT_FILL};
+use ihex::record::Record;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use transport::Line;
 
 use Cc131x;
 pub struct Bootloader;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessWidth {
+    Byte = 0,
+    Word = 1,
+}
+
+impl AccessWidth {
+    fn for_span(address: u32, len: usize) -> AccessWidth {
+        if address & 0x3 == 0 && len & 0x3 == 0 {
+            AccessWidth::Word
+        } else {
+            AccessWidth::Byte
+        }
+    }
+}
+
+// upper bounds on how long the bootloader may stay silent before an operation is given up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Timeouts {
+    pub command: Duration,
+    pub send_data: Duration,
+    pub erase: Duration,
+    pub crc: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            command: Duration::from_millis(50),
+            send_data: Duration::from_millis(100),
+            erase: Duration::from_millis(500),
+            crc: Duration::from_secs(1),
+        }
+    }
+}
+
 /*
  *  The responsbility of this library is to exercise the commands module and provide a high level bootloader interface
- *  It handles delays required between commands on a more or less case-by-case basis.
- *  All the timings were empirically determined at 4Mhz
+ *  Rather than sleeping for delays tuned to one SPI clock, it polls for the ACK of each command,
+ *  bounded by the Timeouts configured on Cc131x
  */
 
 #[derive(Debug)]
 pub enum Error {
     IO(io::Error),
+    GPIO(::sysfs_gpio::Error),
     BOOTLOADER(BlPkError),
+    // holds the raw ICEPICK_DEVICE_ID
+    UnknownChip(u32),
+    TargetMismatch {
+        expected: ChipFamily,
+        detected: ChipFamily,
+    },
+    Timeout {
+        operation: &'static str,
+        elapsed: Duration,
+    },
+    // first byte read back that differs from the image
+    VerifyMismatch {
+        address: u32,
+        expected: u8,
+        actual: u8,
+    },
+    // the bootloader reported anything but Success after a command
+    UnexpectedStatus {
+        operation: &'static str,
+        status: StatusValue,
+    },
+    // CRC32 read back after writing a segment
+    CrcMismatch {
+        address: u32,
+        expected: u32,
+        actual: u32,
+    },
+    // the CancelToken was triggered between two chunks
+    Cancelled,
+    // the image has data outside flash that is not wholly in SRAM or FCFG1, or any when
+    // Cc131x::strict_segments is set
+    NotInFlash {
+        address: u32,
+        region: Region,
+    },
+    // load_and_run needs a VTOR aligned vector table, including PendSV, that fits in SRAM
+    BadRamImage {
+        address: u32,
+        len: usize,
+    },
+    // two images given to flash_many, by index, both have data at address
+    ImagesOverlap {
+        first: usize,
+        second: usize,
+        address: u32,
+    },
+    // flash_firmware_preserving was asked to keep a range the image has data in
+    PreservedRangeOverlap {
+        address: u32,
+    },
+    // flashing failed with the boxed error, and the backed up sectors were written back
+    RolledBack(Box<Error>),
+    // what was being attempted, and where, when cause occurred
+    Context {
+        operation: &'static str,
+        address: Option<u32>,
+        cause: Box<Error>,
+    },
+}
+
+impl Error {
+    // the innermost error, with all context stripped
+    pub fn root(&self) -> &Error {
+        match *self {
+            Error::Context { ref cause, .. } => cause.root(),
+            ref err => err,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IO(ref err) => write!(f, "SPI I/O failed: {}", err),
+            Error::GPIO(ref err) => write!(f, "GPIO failed: {}", err),
+            Error::BOOTLOADER(ref err) => write!(f, "bootloader protocol error: {}", err),
+            Error::UnknownChip(id) => write!(f, "unknown chip, device id {:#010x}", id),
+            Error::TargetMismatch { expected, detected } => write!(
+                f,
+                "image targets {:?} but a {:?} was detected",
+                expected, detected
+            ),
+            Error::Timeout { operation, elapsed } => {
+                write!(f, "{} timed out after {:?}", operation, elapsed)
+            }
+            Error::VerifyMismatch {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "verify failed at {:#010x}: expected {:#04x}, read {:#04x}",
+                address, expected, actual
+            ),
+            Error::UnexpectedStatus { operation, status } => write!(
+                f,
+                "{} failed with status {:?} ({:#04x})",
+                operation,
+                status,
+                status.to_byte()
+            ),
+            Error::CrcMismatch {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "CRC at {:#010x} is {:#010x}, expected {:#010x}",
+                address, actual, expected
+            ),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::NotInFlash { address, region } => {
+                write!(
+                    f,
+                    "image has data at {:#010x} in {}, outside flash",
+                    address, region
+                )
+            }
+            Error::BadRamImage { address, len } => write!(
+                f,
+                "{} bytes at {:#010x} cannot be run from SRAM",
+                len, address
+            ),
+            Error::ImagesOverlap {
+                first,
+                second,
+                address,
+            } => write!(
+                f,
+                "images {} and {} overlap at {:#010x}",
+                first, second, address
+            ),
+            Error::PreservedRangeOverlap { address } => write!(
+                f,
+                "image data at {:#010x} falls within a preserved range",
+                address
+            ),
+            Error::RolledBack(ref cause) => {
+                write!(f, "flashing failed and was rolled back: {}", cause)
+            }
+            Error::Context {
+                operation,
+                address,
+                ref cause,
+            } => match address {
+                Some(address) => write!(f, "{} at {:#010x} failed: {}", operation, address, cause),
+                None => write!(f, "{} failed: {}", operation, cause),
+            },
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IO(ref err) => Some(err),
+            Error::GPIO(ref err) => Some(err),
+            Error::BOOTLOADER(ref err) => Some(err),
+            Error::Context { ref cause, .. } => Some(cause.as_ref()),
+            Error::RolledBack(ref cause) => Some(cause.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// attaches the operation and target address to whatever error a step returns
+trait Context<T> {
+    fn context(self, operation: &'static str, address: Option<u32>) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> Context<T> for Result<T, E> {
+    fn context(self, operation: &'static str, address: Option<u32>) -> Result<T, Error> {
+        self.map_err(|err| Error::Context {
+            operation,
+            address,
+            cause: Box::new(err.into()),
+        })
+    }
 }
 
 impl From<BlPkError> for Error {
@@ -33,6 +277,50 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<::sysfs_gpio::Error> for Error {
+    fn from(err: ::sysfs_gpio::Error) -> Error {
+        Error::GPIO(err)
+    }
+}
+
+// what the bootloader answered to a command sent through Bootloader::execute
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Ack,
+    // the checksum-verified payload, without size and checksum bytes
+    Data(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Probe {
+    pub info: DeviceInfo,
+    // from the first ping until the bootloader answered
+    pub sync_time: Duration,
+    pub attempts: usize,
+}
+
+// what flash_firmware would do to the connected chip, without erasing or writing anything
+#[derive(Debug, Clone, PartialEq)]
+pub struct DryRun {
+    pub info: DeviceInfo,
+    // flash_firmware erases the whole bank, so this is every sector
+    pub erased_sectors: Vec<u32>,
+    // (start, length) of every download
+    pub segments: Vec<(u32, usize)>,
+    // segments of the image outside flash, which would not be written
+    pub skipped: Vec<SkippedSegment>,
+    // sectors the image touches whose current contents differ from it
+    pub changed_sectors: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlashReport {
+    // commands that had to be sent again
+    pub retries: usize,
+    // segments of the image outside flash, which were not written
+    pub skipped: Vec<SkippedSegment>,
+}
+
 impl Bootloader {
     fn ack(io: &Cc131x) -> Result<(), Error> {
         let packet = [0xCC];
@@ -40,98 +328,513 @@ impl Bootloader {
         Ok(())
     }
 
-    fn get_status(io: &Cc131x) -> Result<StatusValue, Error> {
-        let packet = GetStatus::new().serialize()?;
-        let resp = io.write(&packet)?;
-        let status = CommandStatus::from_payload(resp)?;
-        Self::ack(&io)?;
-        Ok(status.value)
+    // keeps clocking out zeros until the response holds an ACK or NACK, rather than parsing silence
+    fn await_ack(
+        io: &Cc131x,
+        operation: &'static str,
+        timeout: Duration,
+        mut response: Vec<u8>,
+    ) -> Result<Vec<u8>, Error> {
+        const POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+        let start = io.delay.now();
+        while !has_ack(&response) {
+            let elapsed = io.delay.elapsed(start);
+            if elapsed > timeout {
+                warn!("{} timed out after {:?}", operation, elapsed);
+                return Err(Error::Timeout { operation, elapsed });
+            }
+            match io.slave_ready {
+                Some(ref slave_ready) if io.flow_control => {
+                    Self::wait_slave_ready(&**slave_ready, operation, timeout - elapsed)?
+                }
+                _ => io.delay.sleep(POLL_INTERVAL),
+            }
+            io.read(response.as_mut_slice())?;
+        }
+        Ok(response)
     }
 
-    pub fn initialize(io: &Cc131x) -> Result<(), Error> {
-        const CC1310_CHIP_ID: u32 = 0x2002_8000;
+    // await_ack, then keeps clocking in the bytes still missing until the data packet after the ACK is whole
+    // a busy chip can ACK in the last bytes of the buffer and send its packet only with the next transfer
+    // a packet that never completes is a protocol error rather than a timeout, as from a ROM that ACKs
+    // a command it does not have, which Bootloader::capabilities tells apart by GetStatus
+    fn await_response(
+        io: &Cc131x,
+        operation: &'static str,
+        timeout: Duration,
+        response: Vec<u8>,
+    ) -> Result<Vec<u8>, Error> {
+        const POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+        let start = io.delay.now();
+        let mut response = Self::await_ack(io, operation, timeout, response)?;
+        loop {
+            let missing = response_shortfall(&response);
+            if missing == 0 {
+                return Ok(response);
+            }
+            let elapsed = io.delay.elapsed(start);
+            if elapsed > timeout {
+                warn!("{} response incomplete after {:?}", operation, elapsed);
+                return Err(io.protocol_error(BlPkError::PacketTooShort));
+            }
+            let mut more = vec![0; missing];
+            io.read(more.as_mut_slice())?;
+            if more.iter().all(|b| *b == 0) {
+                io.delay.sleep(POLL_INTERVAL);
+            }
+            trace!("{}: clocked in {} more bytes", operation, missing);
+            response.append(&mut more);
+        }
+    }
 
-        let packet = Ping::new().serialize()?;
+    // slave_ready is the SSI handshake line, driven low by the CC13xx once it has data to clock out
+    fn wait_slave_ready(
+        slave_ready: &dyn Line,
+        operation: &'static str,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let start = Instant::now();
+        if !slave_ready.wait_for_level(0, timeout)? {
+            return Err(Error::Timeout {
+                operation,
+                elapsed: start.elapsed(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn ping(io: &Cc131x) -> Result<(), Error> {
+        let _bus = io.bus();
+        let packet = Ping::new().serialize_padded(io.clock_hz)?;
         let resp = io.write(&packet)?;
-        check_ack(resp)?;
+        let resp = Self::await_ack(io, "ping", io.timeouts.command, resp)?;
+        io.tally(check_ack(resp))?;
+        Ok(())
+    }
+
+    // repeats an exchange without side effects, re-pinging the bootloader in between
+    fn retry<T, F>(io: &Cc131x, op: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+    {
+        io.retry
+            .run(&io.retries, &*io.delay, is_protocol_error, op, || {
+                let _ = Self::ping(io);
+            })
+    }
+
+    fn get_status(io: &Cc131x) -> Result<StatusValue, Error> {
+        let packet = GetStatus::new().serialize_padded(io.clock_hz)?;
+        Self::retry(io, || {
+            let resp = io.write(&packet)?;
+            let resp = Self::await_response(io, "get_status", io.timeouts.command, resp)?;
+            let status = io.tally(CommandStatus::from_payload(resp))?;
+            Self::ack(io)?;
+            debug!("get_status: {:?}", status.value);
+            Ok(status.value)
+        })
+    }
+
+    // fails unless the last command left the bootloader in Success
+    fn check_status(io: &Cc131x, operation: &'static str) -> Result<(), Error> {
+        match Self::get_status(io)? {
+            StatusValue::Success => Ok(()),
+            status => Err(Error::UnexpectedStatus { operation, status }),
+        }
+    }
+
+    fn memory_read(
+        io: &Cc131x,
+        address: u32,
+        width: AccessWidth,
+        count: u8,
+    ) -> Result<Vec<u8>, Error> {
+        debug!("memory_read {:#010x} {:?} x{}", address, width, count);
+        let packet = MemoryRead::new(address, width as u8, count).serialize_padded(io.clock_hz)?;
+        Self::retry(io, || {
+            let response = io.write(&packet)?;
+            let response = Self::await_response(io, "memory_read", io.timeouts.command, response)?;
+            let read = io.tally(MemoryReadResponse::from_payload(response))?;
+            Bootloader::ack(io)?;
+            Ok(read.data)
+        })
+    }
+
+    // sends any command and collects its answer, for interactions this module has no wrapper for
+    pub fn execute<C: Command>(io: &Cc131x, cmd: C) -> Result<Response, Error> {
+        let _bus = io.bus();
+        let timeout = match C::CMD {
+            SendData::CMD => io.timeouts.send_data,
+            SectorErase::CMD | BankErase::CMD | SetCcfg::CMD => io.timeouts.erase,
+            Crc32::CMD => io.timeouts.crc,
+            _ => io.timeouts.command,
+        };
+        debug!("execute {:#04x}", C::CMD);
+        let packet = cmd.serialize_padded(io.clock_hz)?;
+        let mut response = io.write(&packet)?;
+        // commands answered only once they complete need the answer clocked out separately
+        if C::REPLY_LEN == 0 {
+            response = vec![0; 32];
+            io.read(response.as_mut_slice())?;
+        }
+        if !C::RESPONDS {
+            let response = Self::await_ack(io, "execute", timeout, response)?;
+            io.tally(check_ack(response))?;
+            return Ok(Response::Ack);
+        }
+        let response = Self::await_response(io, "execute", timeout, response)?;
+        // any response layout does for reading the generic size/checksum header
+        let payload = io.tally(MemoryReadResponse::read_header(response))?;
+        Self::ack(io)?;
+        Ok(Response::Data(payload))
+    }
+
+    // reads are done with 32-bit accesses whenever alignment allows it, which peripheral registers require
+    pub fn read_memory(io: &Cc131x, address: u32, len: usize) -> Result<Vec<u8>, Error> {
+        let _bus = io.bus();
+        // largest response payload the command layer accepts
+        const MAX_READ: usize = 252;
+
+        let width = AccessWidth::for_span(address, len);
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let chunk = (len - data.len()).min(MAX_READ);
+            let count = match width {
+                AccessWidth::Byte => chunk,
+                AccessWidth::Word => chunk / 4,
+            };
+            let chunk_address = address + data.len() as u32;
+            let mut read = Self::memory_read(io, chunk_address, width, count as u8)
+                .context("read_memory", Some(chunk_address))?;
+            if read.len() != chunk {
+                return Err(BlPkError::PacketTooShort).context("read_memory", Some(chunk_address));
+            }
+            data.append(&mut read);
+        }
+        Ok(data)
+    }
+
+    pub fn write_memory(io: &Cc131x, address: u32, data: &[u8]) -> Result<(), Error> {
+        let _bus = io.bus();
+        // a MemoryWrite packet holds at most 247 data bytes, or 61 whole words
+        let width = AccessWidth::for_span(address, data.len());
+        let max_write = match width {
+            AccessWidth::Byte => 247,
+            AccessWidth::Word => 244,
+        };
+
+        let mut offset = 0;
+        for chunk in data.chunks(max_write) {
+            debug!(
+                "memory_write {:#010x} {:?} {} bytes",
+                address + offset,
+                width,
+                chunk.len()
+            );
+            Self::memory_write(io, address + offset, width, chunk)
+                .context("write_memory", Some(address + offset))?;
+            offset += chunk.len() as u32;
+        }
+        Ok(())
+    }
 
-        let packet = GetChipId::new().serialize()?;
+    fn memory_write(
+        io: &Cc131x,
+        address: u32,
+        width: AccessWidth,
+        chunk: &[u8],
+    ) -> Result<(), Error> {
+        let packet =
+            MemoryWrite::new(address, width as u8, chunk.to_vec()).serialize_padded(io.clock_hz)?;
         let response = io.write(&packet)?;
-        let chip_id = ChipId::from_payload(response)?;
-        Bootloader::ack(io)?;
-        assert_eq!(chip_id.value, CC1310_CHIP_ID);
+        let response = Self::await_ack(io, "memory_write", io.timeouts.command, response)?;
+        io.tally(check_ack(response))?;
+
+        Self::check_status(io, "memory_write")
+    }
+
+    pub fn read_word(io: &Cc131x, address: u32) -> Result<u32, Error> {
+        let _bus = io.bus();
+        let data = Self::memory_read(io, address, AccessWidth::Word, 1)?;
+        Ok(LittleEndian::read_u32(&data))
+    }
+
+    pub fn write_word(io: &Cc131x, address: u32, value: u32) -> Result<(), Error> {
+        let _bus = io.bus();
+        let mut data = [0; 4];
+        LittleEndian::write_u32(&mut data, value);
+        Self::write_memory(io, address, &data)
+    }
+
+    fn read_mac(io: &Cc131x, address: u32, mac: &mut [u8]) -> Result<(), Error> {
+        let mem = Self::read_memory(io, address, mac.len())?;
+        device::mac_from_memory(&mem, mac);
         Ok(())
     }
 
-    pub fn erase_sector(io: &Cc131x, sector: u32) -> Result<(), Error> {
-        let packet = SectorErase::new(sector).serialize()?;
-        io.write(&packet)?;
+    // factory programmed IEEE 802.15.4 address in FCFG1
+    pub fn read_primary_mac(io: &Cc131x) -> Result<[u8; 8], Error> {
+        let _bus = io.bus();
+        let mut mac = [0; 8];
+        Self::read_mac(io, device::MAC_15_4_0, &mut mac)?;
+        Ok(mac)
+    }
 
-        let delay = time::Duration::from_millis(10);
-        thread::sleep(delay);
-        let mut response = vec![0; 28];
-        io.read(&mut response.as_mut_slice())?;
-        check_ack(response)?;
+    // customer IEEE 802.15.4 address in CCFG, None when left erased
+    pub fn read_secondary_mac(io: &Cc131x, info: &DeviceInfo) -> Result<Option<[u8; 8]>, Error> {
+        let _bus = io.bus();
+        let address = info.ccfg_address() + device::CCFG_IEEE_MAC_OFFSET;
+        let mut mac = [0; 8];
+        Self::read_mac(io, address as u32, &mut mac)?;
+        if mac.iter().all(|b| *b == 0xFF) {
+            return Ok(None);
+        }
+        Ok(Some(mac))
+    }
+
+    // factory programmed BLE address in FCFG1
+    pub fn read_ble_mac(io: &Cc131x) -> Result<[u8; 6], Error> {
+        let _bus = io.bus();
+        let mut mac = [0; 6];
+        Self::read_mac(io, device::MAC_BLE_0, &mut mac)?;
+        Ok(mac)
+    }
+
+    // pings with a doubling backoff until the ROM bootloader answers, e.g. while a cold board is still powering up
+    pub fn probe(io: &Cc131x, timeout: Duration) -> Result<Probe, Error> {
+        let _bus = io.bus();
+        const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+        let start = io.delay.now();
+        let mut backoff = Duration::from_millis(1);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match Self::ping(io) {
+                Ok(()) => break,
+                Err(e) => {
+                    let elapsed = io.delay.elapsed(start);
+                    if elapsed + backoff > timeout {
+                        warn!("probe gave up after {} pings: {}", attempts, e);
+                        return Err(Error::Timeout {
+                            operation: "probe",
+                            elapsed,
+                        });
+                    }
+                    debug!("probe: ping {} failed: {}", attempts, e);
+                }
+            }
+            io.delay.sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+        let sync_time = io.delay.elapsed(start);
+        info!(
+            "bootloader answered after {:?} ({} pings)",
+            sync_time, attempts
+        );
 
-        let status = Self::get_status(&io)?;
-        assert_eq!(status, StatusValue::Success, "Failed to Erase Sector");
+        Ok(Probe {
+            info: Self::initialize(io)?,
+            sync_time,
+            attempts,
+        })
+    }
+
+    fn chip_id(io: &Cc131x) -> Result<u32, Error> {
+        let packet = GetChipId::new().serialize_padded(io.clock_hz)?;
+        let response = io.write(&packet)?;
+        let response = Self::await_response(io, "get_chip_id", io.timeouts.command, response)?;
+        let chip_id = io.tally(ChipId::from_payload(response))?;
+        Bootloader::ack(io)?;
+        Ok(chip_id.value)
+    }
+
+    pub fn initialize(io: &Cc131x) -> Result<DeviceInfo, Error> {
+        let _bus = io.bus();
+        Self::ping(io)?;
+        let chip_id = Self::chip_id(io)?;
+
+        let device_id = Self::read_word(io, device::ICEPICK_DEVICE_ID)?;
+        let flash_size = Self::read_word(io, device::FLASH_SIZE)?;
+        let ramhwopt = Self::read_word(io, device::PRCM_RAMHWOPT)?;
+        let mac = Self::read_primary_mac(io)?;
+
+        let info = DeviceInfo::from_registers(chip_id, device_id, flash_size, ramhwopt, mac)
+            .ok_or(Error::UnknownChip(device_id))?;
+        info!(
+            "found {:?}, chip id {:#010x}, {} KB flash",
+            info.family,
+            info.chip_id,
+            info.flash_size / 1024
+        );
+        io.emit(BootloaderEvent::Identified {
+            chip_id: info.chip_id,
+        });
+        Ok(info)
+    }
+
+    pub fn erase_sector(io: &Cc131x, sector: u32) -> Result<(), Error> {
+        let _bus = io.bus();
+        debug!("erase_sector {:#010x}", sector);
+        let packet = SectorErase::new(sector).serialize()?;
+        Self::erase(io, "erase_sector", &packet).context("erase_sector", Some(sector))?;
+        io.emit(BootloaderEvent::SectorErased { addr: sector });
         Ok(())
     }
 
     pub fn erase_chip(io: &Cc131x) -> Result<(), Error> {
+        let _bus = io.bus();
+        debug!("erase_chip");
         let packet = BankErase::new().serialize()?;
-        io.write(&packet)?;
+        Self::erase(io, "erase_chip", &packet).context("erase_chip", None)?;
+        io.emit(BootloaderEvent::BankErased);
+        Ok(())
+    }
+
+    fn erase(io: &Cc131x, operation: &'static str, packet: &[u8]) -> Result<(), Error> {
+        io.write(packet)?;
 
-        let delay = time::Duration::from_millis(25);
-        thread::sleep(delay);
         let mut response = vec![0; 28];
-        io.read(&mut response.as_mut_slice())?;
-        check_ack(response)?;
+        io.read(response.as_mut_slice())?;
+        let response = Self::await_ack(io, operation, io.timeouts.erase, response)?;
+        io.tally(check_ack(response))?;
+
+        Self::check_status(io, operation)
+    }
 
-        let status = Self::get_status(&io)?;
-        assert_eq!(status, StatusValue::Success, "Failed to Erase Sector");
+    // erases the minimal set of sectors covering [start, start + len), using the detected sector size
+    pub fn erase_range(io: &Cc131x, start: u32, len: u32) -> Result<(), Error> {
+        let _bus = io.bus();
+        let info = Self::initialize(io)?;
+        for sector in device::sectors_covering(start, len, info.sector_size) {
+            Self::erase_sector(io, sector)?;
+        }
         Ok(())
     }
 
-    fn write_payload(io: &Cc131x, payload: Vec<u8>) -> Result<(), Error> {
-        let len = payload.len() as u32;
-        let packet = SendData::new(payload).serialize()?;
-        io.write(&packet)?;
+    // a NACKed packet is dropped by the bootloader, so it is simply sent again
+    // re-pinging here would abort the download in progress
+    // pipelined, the first poll for the ACK is clocked out behind the packet in the same transfer,
+    // either way the next packet only goes out once this one's ACK is in
+    fn write_payload(io: &Cc131x, payload: &[u8]) -> Result<(), Error> {
+        const POLL_LEN: usize = 32;
 
-        let delay = time::Duration::new(0, len * 6500);
+        let mut packet = SendData::serialize_from(payload)?;
+        // the ACK would be lost while 3-wire SPI drives the packet
+        let pipelined = io.pipeline && !io.half_duplex;
+        if pipelined {
+            packet.resize(packet.len() + POLL_LEN, 0);
+        }
+        io.retry.run(
+            &io.retries,
+            &*io.delay,
+            is_rejected,
+            || {
+                let mut response = io.write(&packet)?;
+                if !pipelined {
+                    response = vec![0; POLL_LEN];
+                    io.read(response.as_mut_slice())?;
+                }
+                let response = Self::await_ack(io, "send_data", io.timeouts.send_data, response)?;
+                io.tally(check_ack(response))?;
+                Ok(())
+            },
+            || {},
+        )
+    }
 
-        thread::sleep(delay);
+    // what get_crc should return over the segment, Segment::crc already holds it for IEEE
+    pub fn expected_crc(io: &Cc131x, segment: &Segment) -> u32 {
+        let params = io.crc_params();
+        if params.is_ieee() {
+            segment.crc
+        } else {
+            params.checksum(&segment.data)
+        }
+    }
 
-        let mut response = vec![0; 32];
-        io.read(&mut response.as_mut_slice())?;
-        check_ack(response)?;
-        Ok(())
+    // the CRC command's answer over the segment and what it should be, reported as CrcChecked either way
+    pub(crate) fn compare_crc(io: &Cc131x, segment: &Segment) -> Result<(u32, u32), Error> {
+        let address = segment.start as u32;
+        let actual = Self::get_crc(io, address, segment.data.len() as u32)?;
+        let expected = Self::expected_crc(io, segment);
+        io.emit(BootloaderEvent::CrcChecked {
+            addr: address,
+            len: segment.data.len(),
+            expected,
+            actual,
+        });
+        Ok((expected, actual))
     }
 
     pub fn get_crc(io: &Cc131x, addr: u32, size: u32) -> Result<u32, Error> {
-        let packet = Crc32::new(addr, size, 0).serialize().unwrap();
-        io.write(&packet).unwrap();
+        let _bus = io.bus();
+        debug!("get_crc {:#010x} {} bytes", addr, size);
+        let packet = Crc32::new(addr, size, io.crc_params().read_repeat).serialize()?;
+        Self::retry(io, || {
+            io.write(&packet)?;
 
-        let delay = time::Duration::new(0, size * 500);
-        thread::sleep(delay);
-
-        let mut response = vec![0; 16];
-        io.read(&mut response.as_mut_slice())?;
-        let crc32_checksum = Crc32Response::from_payload(response).unwrap();
-        Bootloader::ack(io)?;
-        Ok(crc32_checksum.value)
+            let mut response = vec![0; 16];
+            io.read(response.as_mut_slice())?;
+            let response = Self::await_response(io, "get_crc", io.timeouts.crc, response)?;
+            let crc32_checksum = io.tally(Crc32Response::from_payload(response))?;
+            Bootloader::ack(io)?;
+            debug!("get_crc: {:#010x}", crc32_checksum.value);
+            Ok(crc32_checksum.value)
+        })
+        .context("get_crc", Some(addr))
     }
 
     pub fn system_reset(io: &Cc131x) -> Result<(), Error> {
-        let packet = Reset::new().serialize().unwrap();
-        let response = io.write(&packet).unwrap();
-        check_ack(response)?;
-        let delay = time::Duration::from_millis(20);
-        thread::sleep(delay);
+        let _bus = io.bus();
+        // a nested session leaves the chip in the bootloader for the one it is nested in
+        if io.sessions.load(Ordering::Relaxed) > 1 {
+            debug!("reset left to the outermost session");
+            return Ok(());
+        }
+        debug!("reset");
+        let packet = Reset::new().serialize_padded(io.clock_hz)?;
+        let response = io.write(&packet)?;
+        let response = Self::await_ack(io, "reset", io.timeouts.command, response)?;
+        io.tally(check_ack(response))?;
+        io.delay.sleep(Duration::from_millis(20));
         Ok(())
     }
 
+    fn check_cancelled(io: &Cc131x) -> Result<(), Error> {
+        match io.cancel {
+            Some(ref token) if token.is_cancelled() => {
+                info!("operation cancelled");
+                Err(Error::Cancelled)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn report(io: &Cc131x, event: ProgressEvent) {
+        if let Some(ref sink) = io.progress {
+            sink.event(&event);
+        }
+        io.emit(BootloaderEvent::from(&event));
+    }
+
+    // checked by CRC unless Cc131x::verify is None, sector rewrites have no whole image to check later
     pub fn write_segment(io: &Cc131x, segment: &Segment) -> Result<(), Error> {
+        let _bus = io.bus();
+        let check_crc = io.verify != VerifyMode::None;
+        let mut progress = Progress::new(segment.data.len());
+        Self::write_segment_tracked(io, segment, &mut progress, check_crc)
+            .context("write_segment", Some(segment.start as u32))
+    }
+
+    fn write_segment_tracked(
+        io: &Cc131x,
+        segment: &Segment,
+        progress: &mut Progress,
+        check_crc: bool,
+    ) -> Result<(), Error> {
         const MAX_PAYLOAD: usize = 252;
 
         #[derive(Debug)]
@@ -143,68 +846,598 @@ impl Bootloader {
             address: segment.start as u32,
             size: segment.data.len() as u32,
         };
+        Self::report(
+            io,
+            ProgressEvent::SegmentStarted {
+                addr: s.address,
+                len: segment.data.len(),
+            },
+        );
+        debug!("download {:#010x} {} bytes", s.address, s.size);
         // prepare chip for download of segment
-        let start_segment_download = Download::new(s.address, s.size).serialize()?;
+        let start_segment_download =
+            Download::new(s.address, s.size).serialize_padded(io.clock_hz)?;
         let resp = io.write(&start_segment_download)?;
-        check_ack(resp)?;
+        let resp = Self::await_ack(io, "download", io.timeouts.command, resp)?;
+        io.tally(check_ack(resp))?;
 
-        let mut data = segment.data.clone();
         // send the whole segment chunk by chunk
-        loop {
-            let len = data.len();
-            if len <= MAX_PAYLOAD {
-                break;
-            }
-            let mut payload = data;
-            data = payload.split_off(MAX_PAYLOAD);
+        for payload in segment.data.chunks(MAX_PAYLOAD) {
+            Self::check_cancelled(io)?;
             Self::write_payload(io, payload)?;
+            Self::report(io, progress.advance(payload.len()));
+        }
+
+        Self::check_status(io, "send_data")?;
+        io.emit(BootloaderEvent::SegmentWritten {
+            addr: s.address,
+            len: segment.data.len(),
+            crc: segment.crc,
+        });
+        if !check_crc {
+            return Ok(());
+        }
+
+        Self::check_crc(io, segment)?;
+        Self::check_status(io, "get_crc")
+    }
+
+    // the part of firmware that lies in the flash of the chip laid out as layout
+    // segments wholly in SRAM or FCFG1 are logged and left out, unless Cc131x::strict_segments is set,
+    // anything else outside flash, e.g. running past its end, is refused
+    pub fn flash_part(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        layout: &FlashLayout,
+    ) -> Result<(FirmwareImage, Vec<SkippedSegment>), Error> {
+        let (flash, skipped) = firmware.classify(layout);
+        for segment in &skipped {
+            if io.strict_segments || !segment.can_skip() {
+                return Err(Error::NotInFlash {
+                    address: segment.start as u32,
+                    region: segment.region,
+                });
+            }
+            warn!(
+                "leaving out {} bytes at {:#010x} in {}",
+                segment.len, segment.start, segment.region
+            );
+        }
+        Ok((flash, skipped))
+    }
+
+    // this and the other entry points that erase and write a whole image are only reachable through
+    // the Cc131x methods, which refuse a CCFG that would lock out the ROM bootloader before calling them
+    pub(crate) fn flash_firmware(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+    ) -> Result<FlashReport, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        Bootloader::program(io, firmware, &info)
+    }
+
+    pub(crate) fn flash_bundle(io: &Cc131x, bundle: &FirmwareBundle) -> Result<FlashReport, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        if info.family != bundle.target {
+            return Err(Error::TargetMismatch {
+                expected: bundle.target,
+                detected: info.family,
+            });
+        }
+        Bootloader::program(io, &bundle.image, &info)
+    }
+
+    fn program(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        info: &DeviceInfo,
+    ) -> Result<FlashReport, Error> {
+        let retries = io.retry_count();
+        let (firmware, skipped) = Self::flash_part(io, firmware, &FlashLayout::from(info))?;
+        Self::check_cancelled(io)?;
+        Bootloader::erase_chip(io)?;
+        Self::write_image(io, &firmware, info, io.verify)?;
+        Bootloader::system_reset(io)?;
+        Ok(FlashReport {
+            retries: io.retry_count() - retries,
+            skipped,
+        })
+    }
+
+    // writes firmware into freshly erased flash and checks it as verify says
+    fn write_image(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        info: &DeviceInfo,
+        verify: VerifyMode,
+    ) -> Result<(), Error> {
+        let firmware = firmware.split_at_sectors(info.sector_size);
+        let segments = firmware.segments.iter();
+        // the erased CCFG keeps the ROM bootloader in charge until everything else has verified
+        let reordered;
+        let segments: Vec<&Segment> = if info.family.defers_ccfg() {
+            debug!("deferring CCFG at {:#010x}", info.ccfg_address());
+            reordered = ccfg_last(segments, info.ccfg_address());
+            reordered.segments.iter().collect()
+        } else {
+            segments.collect()
+        };
+        let mut progress = Progress::new(segments.iter().map(|s| s.data.len()).sum());
+        let per_segment = verify == VerifyMode::CrcPerSegment;
+        for segment in &segments {
+            Bootloader::write_segment_tracked(io, segment, &mut progress, per_segment)
+                .context("write_segment", Some(segment.start as u32))?;
         }
-        Self::write_payload(io, data)?;
+        Bootloader::check_written(io, &segments, verify).context("verify", None)
+    }
 
-        let status = Self::get_status(&io)?;
-        assert_eq!(status, StatusValue::Success, "Failed to Send Data");
+    // saves the sectors the image touches to backup before rewriting them, and writes them back
+    // if writing or verifying the new image fails
+    // a cancelled flash is not rolled back, backup holds a serialized FirmwareImage to restore from
+    pub(crate) fn flash_firmware_with_backup(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        backup: &Path,
+    ) -> Result<FlashReport, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        let retries = io.retry_count();
+        let sector_size = info.sector_size;
+        let (firmware, skipped) = Self::flash_part(io, firmware, &FlashLayout::from(&info))?;
+        let firmware = &firmware;
+        let sectors = firmware.touched_sectors(sector_size);
 
-        let crc_read = Self::get_crc(&io, s.address, s.size)?;
-        assert_eq!(segment.crc, crc_read);
+        let mut saved = Vec::new();
+        for sector in &sectors {
+            Self::check_cancelled(io)?;
+            let data = Bootloader::read_memory(io, *sector as u32, sector_size)?;
+            saved.push(Segment::with_data(*sector, data));
+        }
+        let saved = FirmwareImage { segments: saved };
+        let encoded = saved.serialize().map_err(io::Error::other)?;
+        fs::write(backup, encoded)?;
 
-        let status = Self::get_status(&io)?;
-        assert_eq!(status, StatusValue::Success, "Failed to Read CRC");
+        if let Err(e) = Bootloader::rewrite_and_verify(io, firmware, &sectors, sector_size) {
+            if matches!(*e.root(), Error::Cancelled) {
+                return Err(e);
+            }
+            warn!(
+                "flashing failed, restoring {} sectors: {}",
+                sectors.len(),
+                e
+            );
+            for sector in &sectors {
+                Bootloader::rewrite_sector(io, &saved, *sector, sector_size)
+                    .context("rollback", Some(*sector as u32))?;
+            }
+            Bootloader::system_reset(io)?;
+            return Err(Error::RolledBack(Box::new(e)));
+        }
+        Bootloader::system_reset(io)?;
+        Ok(FlashReport {
+            retries: io.retry_count() - retries,
+            skipped,
+        })
+    }
 
+    fn rewrite_and_verify(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        sectors: &[usize],
+        sector_size: usize,
+    ) -> Result<(), Error> {
+        for sector in sectors {
+            Bootloader::rewrite_sector(io, firmware, *sector, sector_size)?;
+        }
+        for segment in &firmware.segments {
+            let (expected, actual) = Self::compare_crc(io, segment)?;
+            if actual != expected {
+                return Err(Error::CrcMismatch {
+                    address: segment.start as u32,
+                    expected,
+                    actual,
+                });
+            }
+        }
         Ok(())
     }
 
-    pub fn flash_firmware(io: &Cc131x, firmware: &FirmwareImage, sram: usize) -> Result<(), Error> {
-        Bootloader::initialize(&io)?;
-        Bootloader::erase_chip(&io)?;
+    // like flash_firmware, but records every verified segment in store until done
+    pub(crate) fn flash_firmware_resumable(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        store: &mut dyn StateStore,
+    ) -> Result<(), Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        let (firmware, _) = Self::flash_part(io, firmware, &FlashLayout::from(&info))?;
+        // saved before erasing so an interrupted erase gets resumed as well
+        let mut progress = FlashProgress::default();
+        store.save(&progress)?;
+        Bootloader::erase_chip(io)?;
         for segment in &firmware.segments {
-            // throw away hex segments writing to SRAM
-            if (segment.start & sram) == 0 {
-                Bootloader::write_segment(&io, segment)?;
+            Bootloader::write_segment(io, segment)?;
+            progress.verified.push((segment.start, segment.crc));
+            store.save(&progress)?;
+        }
+        Bootloader::system_reset(io)?;
+        store.clear()?;
+        Ok(())
+    }
+
+    // skips the segments recorded in store that still pass the CRC check and rewrites the rest
+    pub(crate) fn resume_flash(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        store: &mut dyn StateStore,
+    ) -> Result<(), Error> {
+        let _bus = io.bus();
+        let progress = match store.load()? {
+            Some(progress) => progress,
+            None => return Bootloader::flash_firmware_resumable(io, firmware, store),
+        };
+        let info = Bootloader::initialize(io)?;
+        let (firmware, _) = Self::flash_part(io, firmware, &FlashLayout::from(&info))?;
+        let firmware = &firmware;
+
+        let segments: Vec<&Segment> = firmware.segments.iter().collect();
+        let mut first_mismatch = segments.len();
+        for (i, segment) in segments.iter().enumerate() {
+            if !progress.is_verified(segment) {
+                first_mismatch = i;
+                break;
             }
+            let (expected, actual) = Self::compare_crc(io, segment)?;
+            if actual != expected {
+                first_mismatch = i;
+                break;
+            }
+        }
+
+        // the interrupted segment may be half written, so its sectors are erased and rewritten whole
+        let remaining = FirmwareImage {
+            segments: segments[first_mismatch..]
+                .iter()
+                .map(|s| (*s).clone())
+                .collect(),
+        };
+        for sector in remaining.touched_sectors(info.sector_size) {
+            Bootloader::rewrite_sector(io, firmware, sector, info.sector_size)?;
         }
-        Bootloader::system_reset(&io)?;
+        Bootloader::system_reset(io)?;
+        store.clear()?;
         Ok(())
     }
 
-    pub fn firmware_match(
+    // the sector is written whole, with the bytes the image leaves out written as erased flash
+    fn rewrite_sector(
         io: &Cc131x,
         firmware: &FirmwareImage,
-        sram: usize,
+        sector: usize,
+        sector_size: usize,
+    ) -> Result<(), Error> {
+        Bootloader::erase_sector(io, sector as u32)?;
+        Bootloader::write_segment(
+            io,
+            &firmware.sector_image(sector, sector_size, DEFAULT_FILL),
+        )
+    }
+
+    // erases sector by sector instead of the whole bank
+    // sectors overlapping a preserved range are read first and written back with the preserved bytes kept
+    pub(crate) fn flash_firmware_preserving(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        preserve: &[Range<u32>],
+    ) -> Result<(), Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        let sector_size = info.sector_size;
+        let (firmware, _) = Self::flash_part(io, firmware, &FlashLayout::from(&info))?;
+        let firmware = &firmware;
+        // refused before anything is erased
+        for range in preserve {
+            if let Some(segment) = firmware
+                .clip(range.start as usize, range.end as usize)
+                .segments
+                .first()
+            {
+                return Err(Error::PreservedRangeOverlap {
+                    address: segment.start as u32,
+                });
+            }
+        }
+        for sector in firmware.touched_sectors(sector_size) {
+            Self::check_cancelled(io)?;
+            let end = sector + sector_size;
+            let kept: Vec<Range<usize>> = preserve
+                .iter()
+                .map(|r| (r.start as usize).max(sector)..(r.end as usize).min(end))
+                .filter(|r| r.start < r.end)
+                .collect();
+            if kept.is_empty() {
+                Bootloader::rewrite_sector(io, firmware, sector, sector_size)?;
+                continue;
+            }
+            let current = Bootloader::read_memory(io, sector as u32, sector_size)?;
+            let mut merged = firmware.sector_image(sector, sector_size, DEFAULT_FILL);
+            for range in kept {
+                let range = range.start - sector..range.end - sector;
+                merged.data[range.clone()].copy_from_slice(&current[range]);
+            }
+            merged.recompute_crc();
+            Bootloader::erase_sector(io, sector as u32)?;
+            Bootloader::write_segment(io, &merged)?;
+        }
+        Bootloader::system_reset(io)?;
+        Ok(())
+    }
+
+    // compares a sector's CRC with what the image would leave in it
+    fn sector_differs(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        sector: usize,
+        sector_size: usize,
     ) -> Result<bool, Error> {
-        Bootloader::initialize(&io)?;
+        // bytes the image leaves out are expected to be erased
+        let expected = firmware.sector_image(sector, sector_size, DEFAULT_FILL);
+        let (expected, actual) = Self::compare_crc(io, &expected)?;
+        Ok(actual != expected)
+    }
+
+    pub fn flash_firmware_dry_run(io: &Cc131x, firmware: &FirmwareImage) -> Result<DryRun, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        if let Some(ref profile) = io.profile {
+            if profile.family != info.family {
+                return Err(Error::TargetMismatch {
+                    expected: profile.family,
+                    detected: info.family,
+                });
+            }
+        }
+
+        let sector_size = info.sector_size;
+        let layout = FlashLayout::from(&info);
+        let erased_sectors = layout.sector_addresses();
+        let (firmware, skipped) = Self::flash_part(io, firmware, &layout)?;
+        let firmware = &firmware;
+        let segments = firmware
+            .segments
+            .iter()
+            .map(|segment| (segment.start as u32, segment.data.len()))
+            .collect();
+        let mut changed_sectors = Vec::new();
+        for sector in firmware.touched_sectors(sector_size) {
+            Self::check_cancelled(io)?;
+            if Bootloader::sector_differs(io, firmware, sector, sector_size)? {
+                changed_sectors.push(sector as u32);
+            }
+        }
+        Bootloader::system_reset(io)?;
+        Ok(DryRun {
+            info,
+            erased_sectors,
+            segments,
+            skipped,
+            changed_sectors,
+        })
+    }
+
+    // erases and rewrites only the sectors whose CRC differs from the image, returns how many were rewritten
+    pub(crate) fn flash_firmware_incremental(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+    ) -> Result<usize, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        let sector_size = info.sector_size;
+        let (firmware, _) = Self::flash_part(io, firmware, &FlashLayout::from(&info))?;
+        let firmware = &firmware;
+        let mut rewritten = 0;
+        for sector in firmware.touched_sectors(sector_size) {
+            if !Bootloader::sector_differs(io, firmware, sector, sector_size)? {
+                continue;
+            }
+            Bootloader::rewrite_sector(io, firmware, sector, sector_size)?;
+            rewritten += 1;
+        }
+        Bootloader::system_reset(io)?;
+        Ok(rewritten)
+    }
+
+    pub(crate) fn flash_bundle_incremental(
+        io: &Cc131x,
+        bundle: &FirmwareBundle,
+    ) -> Result<usize, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        if info.family != bundle.target {
+            return Err(Error::TargetMismatch {
+                expected: bundle.target,
+                detected: info.family,
+            });
+        }
+        Bootloader::flash_firmware_incremental(io, &bundle.image)
+    }
+
+    // CRC-checks every segment, erases and rewrites only the sectors backing the ones that fail,
+    // then checks those segments again; returns the rewritten sectors
+    pub(crate) fn repair(io: &Cc131x, firmware: &FirmwareImage) -> Result<Vec<u32>, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        let (firmware, _) = Self::flash_part(io, firmware, &FlashLayout::from(&info))?;
+        let firmware = &firmware;
+        let mut mismatched = Vec::new();
         for segment in &firmware.segments {
-            // throw away hex segments writing to SRAM
-            if (segment.start & sram) == 0 {
-                let crc =
-                    Bootloader::get_crc(&io, segment.start as u32, segment.data.len() as u32)?;
-                if crc != segment.crc {
-                    Bootloader::system_reset(&io)?;
-
-                    return Ok(false);
+            Self::check_cancelled(io)?;
+            let (expected, actual) = Self::compare_crc(io, segment)?;
+            if actual != expected {
+                debug!("segment at {:#010x} needs repair", segment.start);
+                mismatched.push(segment.clone());
+            }
+        }
+
+        let damaged = FirmwareImage {
+            segments: mismatched,
+        };
+        let sectors = damaged.touched_sectors(info.sector_size);
+        for sector in &sectors {
+            Self::check_cancelled(io)?;
+            Bootloader::rewrite_sector(io, firmware, *sector, info.sector_size)?;
+        }
+        for segment in &damaged.segments {
+            let (expected, actual) = Self::compare_crc(io, segment)?;
+            if actual != expected {
+                return Err(Error::CrcMismatch {
+                    address: segment.start as u32,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Bootloader::system_reset(io)?;
+        Ok(sectors.into_iter().map(|sector| sector as u32).collect())
+    }
+
+    // unreadable chunks are left out of the image, splitting it into several segments
+    pub fn dump_flash(io: &Cc131x, range: Range<u32>) -> Result<FirmwareImage, Error> {
+        let _bus = io.bus();
+        const CHUNK: u32 = 252;
+
+        Bootloader::initialize(io)?;
+        let mut segments = Vec::new();
+        let mut current: Option<(u32, Vec<u8>)> = None;
+        let mut address = range.start;
+        while address < range.end {
+            Self::check_cancelled(io)?;
+            let len = CHUNK.min(range.end - address);
+            match Self::read_memory(io, address, len as usize) {
+                Ok(mut data) => match current {
+                    Some((_, ref mut buf)) => buf.append(&mut data),
+                    None => current = Some((address, data)),
+                },
+                Err(ref e) if matches!(*e.root(), Error::BOOTLOADER(_)) => {
+                    // clear the failed command before moving on
+                    Self::get_status(io)?;
+                    if let Some((start, data)) = current.take() {
+                        segments.push(Segment::with_data(start as usize, data));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            address += len;
+        }
+        if let Some((start, data)) = current {
+            segments.push(Segment::with_data(start as usize, data));
+        }
+        Bootloader::system_reset(io)?;
+        Ok(FirmwareImage { segments })
+    }
+
+    // like dump_flash, but each chunk goes out as Intel HEX records as soon as it is read
+    pub fn dump_to_writer(
+        io: &Cc131x,
+        range: Range<u32>,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let _bus = io.bus();
+        const CHUNK: u32 = 252;
+
+        Bootloader::initialize(io)?;
+        let mut upper = None;
+        let mut address = range.start;
+        while address < range.end {
+            Self::check_cancelled(io)?;
+            let len = CHUNK.min(range.end - address);
+            match Self::read_memory(io, address, len as usize) {
+                Ok(data) => {
+                    for record in ihex_records(address as usize, &data, &mut upper) {
+                        Self::write_record(out, &record)?;
+                    }
+                }
+                // unreadable chunks are left out of the file
+                Err(ref e) if matches!(*e.root(), Error::BOOTLOADER(_)) => {
+                    Self::get_status(io)?;
                 }
+                Err(e) => return Err(e),
             }
+            address += len;
         }
-        Bootloader::system_reset(&io)?;
+        Self::write_record(out, &Record::EndOfFile)?;
+        out.flush()?;
+        Bootloader::system_reset(io)?;
+        Ok(())
+    }
+
+    fn write_record(out: &mut dyn Write, record: &Record) -> Result<(), Error> {
+        let line = record.to_string().map_err(io::Error::other)?;
+        out.write_all(line.as_bytes())?;
+        out.write_all(b"\r\n")?;
+        Ok(())
+    }
+
+    // compares a single version word instead of CRC-checking every segment
+    pub fn version_match(io: &Cc131x, address: u32, version: u32) -> Result<bool, Error> {
+        let _bus = io.bus();
+        Bootloader::initialize(io)?;
+        let current = Bootloader::read_word(io, address)?;
+        Bootloader::system_reset(io)?;
+        Ok(current == version)
+    }
+
+    // reads every segment back and compares it byte by byte rather than relying on CRC32
+    // falls back to CRC32 on ROMs that cannot read memory
+    pub fn verify_exact(io: &Cc131x, firmware: &FirmwareImage) -> Result<(), Error> {
+        let _bus = io.bus();
+        if !Self::capabilities(io)?.memory_read {
+            warn!("bootloader has no MemoryRead, verifying by CRC32 instead");
+            return Self::verify_crc(io, firmware);
+        }
+        Bootloader::verify(io, firmware, VerifyMode::ReadBack)
+    }
+
+    // merges segments separated by at most max_gap bytes and checks each span with a single CRC command
+    // the gaps are expected to read back erased, as they do after flash_firmware
+    pub fn firmware_match_coalesced(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        max_gap: usize,
+    ) -> Result<bool, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        let (mut spans, _) = firmware.classify(&FlashLayout::from(&info));
+        if let Err(e) = spans.normalize(max_gap, DEFAULT_FILL) {
+            debug!("not coalescing: {}", e);
+            return Bootloader::firmware_match(io, firmware);
+        }
+        Bootloader::firmware_match(io, &spans)
+    }
+
+    // segments outside flash are left out of the comparison
+    pub fn firmware_match(io: &Cc131x, firmware: &FirmwareImage) -> Result<bool, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        let (firmware, _) = firmware.classify(&FlashLayout::from(&info));
+        for segment in &firmware.segments {
+            Self::check_cancelled(io)?;
+            let (expected, actual) = Self::compare_crc(io, segment)?;
+            if actual != expected {
+                Bootloader::system_reset(io)?;
+
+                return Ok(false);
+            }
+            Self::report(
+                io,
+                ProgressEvent::Verified {
+                    addr: segment.start as u32,
+                },
+            );
+        }
+        Bootloader::system_reset(io)?;
         Ok(true)
     }
 }
@@ -214,7 +1447,7 @@ fn test_enter_bootloader_and_get_ack() {
     // instantiate Lms6002 device with the mock registers rather than Spidev
     // P9_15 <=> GPIO 48, P9_23 <=> GPIO 49
     let io = Cc131x::new("/dev/spidev1.0", 60, 115, 49, 48).unwrap();
-    io.enter_bootloader().unwrap();
+    let _session = io.enter_bootloader().unwrap();
 
     //Bootloader::poll_until_ready(&io);
     let packet = Ping::new().serialize().unwrap();
@@ -227,13 +1460,13 @@ use firmware_image::FirmwareImage;
 #[test]
 fn test_write_memory_location() {
     let io = Cc131x::new("/dev/spidev1.0", 60, 115, 49, 48).unwrap();
-    io.enter_bootloader().unwrap();
+    let _session = io.enter_bootloader().unwrap();
 
     Bootloader::initialize(&io).unwrap();
     Bootloader::erase_sector(&io, 0).unwrap();
 
-    const FW_FILE: &'static str = include_str!("../../src/firmware/test_parsing.ihex");
-    let mut firmware = FirmwareImage::new(FW_FILE);
+    const FW_FILE: &str = include_str!("../../src/firmware/test_parsing.ihex");
+    let mut firmware = FirmwareImage::new(FW_FILE).unwrap();
     if let Some(segment) = firmware.segments.pop() {
         Bootloader::write_segment(&io, &segment).unwrap();
     }
@@ -242,23 +1475,172 @@ fn test_write_memory_location() {
 #[test]
 fn test_write_whole_memory() {
     let io = Cc131x::new("/dev/spidev1.0", 60, 115, 49, 48).unwrap();
-    io.enter_bootloader().unwrap();
-    const FW_SERIALIZED: &'static [u8] = include_bytes!("../firmware/firmware.bincode");
+    let _session = io.enter_bootloader().unwrap();
+    const FW_SERIALIZED: &[u8] = include_bytes!("../firmware/firmware.bincode");
     let firmware = FirmwareImage::deserialize(FW_SERIALIZED).unwrap();
-    const SRAM_START: usize = 0x20000000;
 
-    Bootloader::flash_firmware(&io, &firmware, SRAM_START).unwrap();
+    Bootloader::flash_firmware(&io, &firmware).unwrap();
 }
 
 #[test]
 fn test_verify_whole_memory() {
     let io = Cc131x::new("/dev/spidev1.0", 60, 115, 49, 48).unwrap();
-    io.enter_bootloader().unwrap();
-    const FW_SERIALIZED: &'static [u8] = include_bytes!("../firmware/firmware.bincode");
+    let _session = io.enter_bootloader().unwrap();
+    const FW_SERIALIZED: &[u8] = include_bytes!("../firmware/firmware.bincode");
     let firmware = FirmwareImage::deserialize(FW_SERIALIZED).unwrap();
-    const SRAM_START: usize = 0x20000000;
-    let firmware_match = Bootloader::firmware_match(&io, &firmware, SRAM_START).unwrap();
-    if !firmware_match {
-        assert!(false, "Firmware mismatch");
+    let firmware_match = Bootloader::firmware_match(&io, &firmware).unwrap();
+    assert!(firmware_match, "Firmware mismatch");
+}
+
+// splits off whatever falls in the CCFG and moves it behind all other segments
+fn ccfg_last<'a, I: Iterator<Item = &'a Segment>>(
+    segments: I,
+    ccfg_address: usize,
+) -> FirmwareImage {
+    let image = FirmwareImage {
+        segments: segments.cloned().collect(),
+    };
+    let mut reordered = image.clip(0, ccfg_address);
+    reordered
+        .segments
+        .append(&mut image.clip(ccfg_address, usize::MAX).segments);
+    reordered
+}
+
+#[test]
+fn test_ccfg_written_last() {
+    let ccfg_address = 0x57FA8;
+    let segments = [
+        Segment::with_data(ccfg_address - 8, vec![1; 96]),
+        Segment::with_data(0, vec![2; 16]),
+    ];
+    let reordered = ccfg_last(segments.iter(), ccfg_address);
+    let starts: Vec<usize> = reordered.segments.iter().map(|s| s.start).collect();
+    assert_eq!(starts, [ccfg_address - 8, 0, ccfg_address]);
+    assert_eq!(reordered.segments[2].data.len(), 88);
+}
+
+#[test]
+fn test_error_context() {
+    let err = Err::<(), _>(BlPkError::Nack)
+        .context("get_crc", Some(0x1000))
+        .context("write_segment", Some(0x1000))
+        .unwrap_err();
+    assert!(matches!(*err.root(), Error::BOOTLOADER(BlPkError::Nack)));
+    assert_eq!(
+        err.to_string(),
+        "write_segment at 0x00001000 failed: get_crc at 0x00001000 failed: \
+         bootloader protocol error: bootloader answered with a NACK"
+    );
+}
+
+#[test]
+fn test_reply_split_across_two_reads_on_mock() {
+    use mock::{MockChip, Seen, CHIP_ID};
+
+    let chip = MockChip::new();
+    let io = MockChip::handle(&chip);
+    let session = io.enter_bootloader().unwrap();
+    // the ACK comes with the last byte of the padding, the packet after it only with the next read
+    let packet = GetChipId::new().serialize_padded(io.clock_hz).unwrap();
+    let padding = packet.len() - GetChipId::new().serialize().unwrap().len();
+    chip.set_latency(padding - 2);
+    assert_eq!(Bootloader::chip_id(&session).unwrap(), CHIP_ID);
+    drop(session);
+    assert!(!chip.seen().iter().any(|s| matches!(s, Seen::Dropped(_))));
+}
+
+#[test]
+fn test_pipelined_acks_come_before_the_next_packet() {
+    use mock::{MockChip, Seen};
+
+    let chip = MockChip::new();
+    // programming a packet outlasts the padding behind it
+    chip.set_latency(64);
+    let mut io = MockChip::handle(&chip);
+    io.pipeline = true;
+    let segment = Segment::with_data(0x2000, (0..1000).map(|i| (i * 7) as u8).collect());
+    let session = io.enter_bootloader().unwrap();
+    Bootloader::write_segment(&session, &segment).unwrap();
+    drop(session);
+
+    let seen = chip.seen();
+    assert!(!seen.iter().any(|s| matches!(s, Seen::Dropped(_))));
+    let send_data: Vec<usize> = seen
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| **s == Seen::Command(0x24))
+        .map(|(i, _)| i)
+        .collect();
+    assert_eq!(send_data.len(), 4);
+    for pair in send_data.windows(2) {
+        assert!(seen[pair[0]..pair[1]].contains(&Seen::Answer(ACK_BYTE)));
     }
+    assert_eq!(&chip.flash()[0x2000..0x2000 + 1000], &segment.data[..]);
+}
+
+#[test]
+fn test_only_sram_and_fcfg_segments_are_left_out() {
+    use mock::{MockChip, FLASH_SIZE};
+
+    let chip = MockChip::new();
+    let io = MockChip::handle(&chip);
+    let app = Segment::with_data(0x1000, vec![0xA5; 16]);
+    let ram = Segment::with_data(device::SRAM_START + 0x100, vec![1; 16]);
+    let firmware = FirmwareImage {
+        segments: vec![app.clone(), ram, MockChip::ccfg()],
+    };
+    let report = io.flash_firmware(&firmware).unwrap();
+    assert_eq!(report.skipped.len(), 1);
+    assert_eq!(report.skipped[0].region, Region::Sram);
+
+    // running past the end of flash
+    let straddling = FirmwareImage {
+        segments: vec![
+            app,
+            MockChip::ccfg(),
+            Segment::with_data(FLASH_SIZE - 8, vec![2; 16]),
+        ],
+    };
+    match io.flash_firmware(&straddling).map(|_| ()) {
+        Err(::Error::BOOTLOADER(ref e)) => match *e.root() {
+            Error::NotInFlash { address, .. } => assert_eq!(address as usize, FLASH_SIZE - 8),
+            ref other => panic!("unexpected error {:?}", other),
+        },
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_every_crc_comparison_is_reported() {
+    use mock::MockChip;
+
+    let crc_checks = |events: &std::sync::mpsc::Receiver<BootloaderEvent>| {
+        events
+            .try_iter()
+            .filter(|e| matches!(e, BootloaderEvent::CrcChecked { .. }))
+            .count()
+    };
+    let chip = MockChip::new();
+    chip.load(0x1000, &[1; 64]);
+    chip.load(0x3000, &[0; 64]);
+    let io = MockChip::handle(&chip);
+    let events = io.subscribe();
+    let firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x1000, vec![1; 64]),
+            Segment::with_data(0x3000, vec![2; 64]),
+        ],
+    };
+
+    assert!(!io.crc_match(&firmware).unwrap());
+    assert_eq!(crc_checks(&events), 2);
+    // both segments, the rewritten sector as it is written, then the repaired segment again
+    assert_eq!(io.repair(&firmware).unwrap(), [0x3000]);
+    assert_eq!(crc_checks(&events), 4);
+    assert!(io.crc_match(&firmware).unwrap());
+    assert_eq!(crc_checks(&events), 2);
+    // every sector the image touches, none of which differs now
+    assert_eq!(io.flash_firmware_incremental(&firmware).unwrap(), 0);
+    assert_eq!(crc_checks(&events), 2);
 }
@@ -0,0 +1,144 @@
+/*
+ *  Events reported while flashing and verifying, so a multi-minute update does not have to run silently
+ *  Any Fn(&ProgressEvent) closure can serve as a sink, or Cc131x::subscribe hands out a channel of BootloaderEvent
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    SegmentStarted { addr: u32, len: usize },
+    // counts bytes across the whole operation, not just the current segment
+    ChunkWritten { bytes_done: usize, total: usize },
+    Verified { addr: u32 },
+    SectorErased { addr: u32 },
+}
+
+// everything a Cc131x reports to its subscribers, as owned values that can cross threads
+#[derive(Debug, Clone, PartialEq)]
+pub enum BootloaderEvent {
+    EnteredBootloader,
+    Identified {
+        chip_id: u32,
+    },
+    SectorErased {
+        addr: u32,
+    },
+    BankErased,
+    SegmentStarted {
+        addr: u32,
+        len: usize,
+    },
+    ChunkWritten {
+        bytes_done: usize,
+        total: usize,
+    },
+    // every byte of the segment was sent, crc is what the host computed over it
+    SegmentWritten {
+        addr: u32,
+        len: usize,
+        crc: u32,
+    },
+    // a CRC command's answer against what was expected, whether they matched or not
+    CrcChecked {
+        addr: u32,
+        len: usize,
+        expected: u32,
+        actual: u32,
+    },
+    VerifyProgress {
+        addr: u32,
+    },
+    // a flashing operation succeeded, or failed with the given error
+    Completed,
+    Error(String),
+}
+
+impl From<&ProgressEvent> for BootloaderEvent {
+    fn from(event: &ProgressEvent) -> BootloaderEvent {
+        match *event {
+            ProgressEvent::SegmentStarted { addr, len } => {
+                BootloaderEvent::SegmentStarted { addr, len }
+            }
+            ProgressEvent::ChunkWritten { bytes_done, total } => {
+                BootloaderEvent::ChunkWritten { bytes_done, total }
+            }
+            ProgressEvent::Verified { addr } => BootloaderEvent::VerifyProgress { addr },
+            ProgressEvent::SectorErased { addr } => BootloaderEvent::SectorErased { addr },
+        }
+    }
+}
+
+pub trait ProgressSink: Send + Sync {
+    fn event(&self, event: &ProgressEvent);
+}
+
+impl<F: Fn(&ProgressEvent) + Send + Sync> ProgressSink for F {
+    fn event(&self, event: &ProgressEvent) {
+        self(event)
+    }
+}
+
+// running byte count for ChunkWritten
+pub struct Progress {
+    pub bytes_done: usize,
+    pub total: usize,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Progress {
+        Progress {
+            bytes_done: 0,
+            total,
+        }
+    }
+
+    pub fn advance(&mut self, bytes: usize) -> ProgressEvent {
+        self.bytes_done += bytes;
+        ProgressEvent::ChunkWritten {
+            bytes_done: self.bytes_done,
+            total: self.total,
+        }
+    }
+}
+
+#[test]
+fn test_closure_sink() {
+    use std::sync::Mutex;
+
+    let events = Mutex::new(Vec::new());
+    {
+        let sink = |event: &ProgressEvent| events.lock().unwrap().push(event.clone());
+        let mut progress = Progress::new(300);
+        sink.event(&ProgressEvent::SegmentStarted { addr: 0, len: 300 });
+        sink.event(&progress.advance(252));
+        sink.event(&progress.advance(48));
+    }
+    assert_eq!(
+        events.into_inner().unwrap(),
+        [
+            ProgressEvent::SegmentStarted { addr: 0, len: 300 },
+            ProgressEvent::ChunkWritten {
+                bytes_done: 252,
+                total: 300
+            },
+            ProgressEvent::ChunkWritten {
+                bytes_done: 300,
+                total: 300
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_progress_maps_to_bootloader_events() {
+    assert_eq!(
+        BootloaderEvent::from(&ProgressEvent::Verified { addr: 0x1000 }),
+        BootloaderEvent::VerifyProgress { addr: 0x1000 }
+    );
+    assert_eq!(
+        BootloaderEvent::from(&Progress::new(10).advance(4)),
+        BootloaderEvent::ChunkWritten {
+            bytes_done: 4,
+            total: 10
+        }
+    );
+}
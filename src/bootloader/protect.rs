@@ -0,0 +1,97 @@
+use bootloader::commands::{check_ack, Command, SetCcfg};
+use bootloader::{Bootloader, Error};
+use byteorder::{ByteOrder, LittleEndian};
+use Cc131x;
+
+/*
+ *  Flash write protection lives in the CCFG_PROT words of the CCFG, one bit per sector
+ *  A cleared bit protects its sector until the next chip erase, and bits can only ever be cleared
+ */
+
+// CCFG_PROT_31_0 to CCFG_PROT_127_96
+const CCFG_PROT: usize = 0x48;
+const CCFG_PROT_WORDS: usize = 4;
+// COMMAND_SET_CCFG field id whose value is the number of the sector to protect
+const ID_SECTOR_PROT: u32 = 0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectorProtection {
+    pub sector_size: usize,
+    words: [u32; CCFG_PROT_WORDS],
+}
+
+impl SectorProtection {
+    pub fn from_bytes(raw: &[u8], sector_size: usize) -> SectorProtection {
+        let mut words = [0xFFFF_FFFF; CCFG_PROT_WORDS];
+        for (word, chunk) in words.iter_mut().zip(raw.chunks(4)) {
+            if chunk.len() == 4 {
+                *word = LittleEndian::read_u32(chunk);
+            }
+        }
+        SectorProtection { sector_size, words }
+    }
+
+    pub fn is_protected(&self, address: u32) -> bool {
+        let sector = address as usize / self.sector_size;
+        match self.words.get(sector / 32) {
+            Some(word) => word & (1 << (sector % 32)) == 0,
+            None => false,
+        }
+    }
+
+    // start addresses of the protected sectors below flash_size
+    pub fn protected_sectors(&self, flash_size: usize) -> Vec<u32> {
+        (0..flash_size / self.sector_size)
+            .map(|sector| (sector * self.sector_size) as u32)
+            .filter(|address| self.is_protected(*address))
+            .collect()
+    }
+}
+
+impl Bootloader {
+    pub fn set_ccfg(io: &Cc131x, field_id: u32, value: u32) -> Result<(), Error> {
+        let _bus = io.bus();
+        debug!("set CCFG field {} to {:#010x}", field_id, value);
+        let packet = SetCcfg::new(field_id, value).serialize()?;
+        io.write(&packet)?;
+
+        let mut response = vec![0; 28];
+        io.read(response.as_mut_slice())?;
+        let response = Self::await_ack(io, "set_ccfg", io.timeouts.erase, response)?;
+        io.tally(check_ack(response))?;
+        Self::check_status(io, "set_ccfg")
+    }
+
+    pub fn read_protection(io: &Cc131x) -> Result<SectorProtection, Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        let address = (info.ccfg_address() + CCFG_PROT) as u32;
+        let raw = Bootloader::read_memory(io, address, CCFG_PROT_WORDS * 4)?;
+        Ok(SectorProtection::from_bytes(&raw, info.sector_size))
+    }
+
+    // protects the sectors holding each address, e.g. the BIM or a keys page once flashing is done
+    pub fn protect_sectors(io: &Cc131x, addresses: &[u32]) -> Result<(), Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        for address in addresses {
+            let sector = *address as usize / info.sector_size;
+            Bootloader::set_ccfg(io, ID_SECTOR_PROT, sector as u32)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sector_protection() {
+    let mut raw = vec![0xFF; 16];
+    // sectors 1 and 33 protected
+    raw[0] = 0xFD;
+    raw[4] = 0xFD;
+    let protection = SectorProtection::from_bytes(&raw, 4096);
+    assert!(!protection.is_protected(0));
+    assert!(protection.is_protected(0x1000));
+    assert!(protection.is_protected(0x1FFF));
+    assert_eq!(protection.protected_sectors(0x20000), [0x1000]);
+    assert_eq!(protection.protected_sectors(0x40000), [0x1000, 0x21000]);
+}
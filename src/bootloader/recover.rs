@@ -0,0 +1,108 @@
+use bootloader::progress::BootloaderEvent;
+use bootloader::{Bootloader, Error};
+use device::{self, ChipFamily, CrcParams, DeviceInfo, KNOWN_FLASH_SIZES};
+use std::time::Duration;
+use Cc131x;
+
+/*
+ *  Unbricking a chip whose flash holds garbage: nothing on it is trusted, the CCFG included,
+ *  so the bank is erased without looking at the image and the result is checked by CRC alone
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recovery {
+    // None when no known family answered, the bank was erased anyway
+    pub info: Option<DeviceInfo>,
+    // None when the flash size is unknown, i.e. no chip was identified and there is no profile
+    pub blank: Option<bool>,
+}
+
+// what the CRC32 command returns over len bytes of erased flash
+fn erased_crc(params: CrcParams, len: usize) -> u32 {
+    params.checksum(&vec![0xFF; len])
+}
+
+impl Bootloader {
+    // pings until the bootloader answers, erases the whole bank and checks that it reads back erased
+    pub fn recover(io: &Cc131x, timeout: Duration) -> Result<Recovery, Error> {
+        let _bus = io.bus();
+        let info = match Self::probe(io, timeout) {
+            Ok(probe) => Some(probe.info),
+            Err(e) => match *e.root() {
+                Error::UnknownChip(_) => Self::detect(io)?,
+                _ => return Err(e),
+            },
+        };
+
+        // the bank erase leaves the CCFG at its defaults, whatever the old one said
+        Self::erase_chip(io)?;
+
+        let flash_size = info
+            .as_ref()
+            .map(|info| info.flash_size)
+            .or_else(|| io.profile.as_ref().map(|p| p.flash_size));
+        let blank = match flash_size {
+            Some(flash_size) => {
+                let actual = Self::get_crc(io, 0, flash_size as u32)?;
+                let expected = erased_crc(io.crc_params(), flash_size);
+                io.emit(BootloaderEvent::CrcChecked {
+                    addr: 0,
+                    len: flash_size,
+                    expected,
+                    actual,
+                });
+                Some(actual == expected)
+            }
+            None => None,
+        };
+        info!("recovery done, flash blank: {:?}", blank);
+        Ok(Recovery { info, blank })
+    }
+
+    // the chip answered but its wafer ID in ICEPICK_DEVICE_ID (0x5000_1318) is not one we know,
+    // so the registers are decoded with each family's parameters in turn
+    // and the first giving a flash size some part ships with is taken
+    fn detect(io: &Cc131x) -> Result<Option<DeviceInfo>, Error> {
+        let chip_id = Self::chip_id(io)?;
+        let flash_size = Self::read_word(io, device::FLASH_SIZE)?;
+        let ramhwopt = Self::read_word(io, device::PRCM_RAMHWOPT)?;
+        let mac = Self::read_primary_mac(io)?;
+        let info = ChipFamily::ALL
+            .iter()
+            .map(|family| DeviceInfo::for_family(*family, chip_id, flash_size, ramhwopt, mac))
+            .find(|info| KNOWN_FLASH_SIZES.contains(&info.flash_size));
+        match info {
+            Some(ref info) => warn!("unknown chip taken for a {:?}", info.family),
+            None => warn!(
+                "unknown chip, FLASH_SIZE {:#010x} fits no family",
+                flash_size
+            ),
+        }
+        Ok(info)
+    }
+}
+
+#[test]
+fn test_erased_crc() {
+    // the CRC32 of four 0xFF bytes happens to be 0xFFFFFFFF, and of nothing 0
+    assert_eq!(erased_crc(CrcParams::default(), 4), 0xFFFF_FFFF);
+    assert_eq!(erased_crc(CrcParams::default(), 0), 0);
+}
+
+#[test]
+fn test_recover_unknown_wafer_on_mock() {
+    use mock::{MockChip, FLASH_SIZE};
+
+    let chip = MockChip::new();
+    chip.load(0x1000, &[0; 64]);
+    // a wafer ID no family has
+    chip.poke(device::ICEPICK_DEVICE_ID, &[0x2F, 0x40, 0x23, 0x21]);
+    let io = MockChip::handle(&chip);
+    let recovery = io.recover(Duration::from_millis(100)).unwrap();
+
+    let info = recovery.info.unwrap();
+    assert_eq!(info.sector_size, 4096);
+    assert_eq!(info.flash_size, FLASH_SIZE);
+    assert_eq!(recovery.blank, Some(true));
+    assert!(chip.flash().iter().all(|b| *b == 0xFF));
+}
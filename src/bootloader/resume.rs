@@ -0,0 +1,94 @@
+use bincode::{deserialize, serialize};
+use firmware_image::Segment;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+ *  Keeps track of which segments of an image have been written and verified
+ *  so an interrupted flash can be picked up again after the host reboots
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FlashProgress {
+    // (start, crc) of every segment written and verified so far, in write order
+    pub verified: Vec<(usize, u32)>,
+}
+
+impl FlashProgress {
+    pub fn is_verified(&self, segment: &Segment) -> bool {
+        self.verified
+            .iter()
+            .any(|&(start, crc)| start == segment.start && crc == segment.crc)
+    }
+}
+
+pub trait StateStore {
+    // Ok(None) when no flash was in progress
+    fn load(&mut self) -> io::Result<Option<FlashProgress>>;
+    fn save(&mut self, progress: &FlashProgress) -> io::Result<()>;
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+// progress lives in a single file, replaced atomically on every save
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileStore {
+        FileStore {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+fn invalid_data(err: ::bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl StateStore for FileStore {
+    fn load(&mut self) -> io::Result<Option<FlashProgress>> {
+        match fs::read(&self.path) {
+            Ok(bytes) => deserialize(&bytes).map(Some).map_err(invalid_data),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&mut self, progress: &FlashProgress) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serialize(progress).map_err(invalid_data)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+}
+
+#[test]
+fn test_file_store_round_trip() {
+    let path = ::std::env::temp_dir().join(format!("cc131x-progress-{}", ::std::process::id()));
+    let mut store = FileStore::new(&path);
+    store.clear().unwrap();
+    assert_eq!(store.load().unwrap(), None);
+
+    let progress = FlashProgress {
+        verified: vec![(0x0000, 0xDEAD_BEEF), (0x1000, 0x1234_5678)],
+    };
+    store.save(&progress).unwrap();
+    let loaded = store.load().unwrap().unwrap();
+    assert_eq!(loaded, progress);
+    assert!(loaded.is_verified(&Segment {
+        start: 0x1000,
+        data: Vec::new(),
+        crc: 0x1234_5678,
+    }));
+
+    store.clear().unwrap();
+    assert_eq!(store.load().unwrap(), None);
+}
@@ -0,0 +1,138 @@
+use bootloader::commands::Error as BlPkError;
+use bootloader::Error;
+use delay::DelayProvider;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/*
+ *  Retrying at the command layer keeps a single NACK or corrupted response from aborting a whole flash
+ *  Only protocol errors are retried, failures of the SPI device itself are passed on right away
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    // total tries per command, 1 disables retrying
+    pub attempts: u32,
+    // waited before the first retry, doubled for every further one
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(5),
+        }
+    }
+}
+
+// the command was rejected or never received, so sending it again is safe
+pub fn is_rejected(err: &Error) -> bool {
+    matches!(
+        *err,
+        Error::BOOTLOADER(BlPkError::Nack) | Error::BOOTLOADER(BlPkError::NoAck)
+    )
+}
+
+// any garbled exchange, only safe to repeat for commands without side effects
+pub fn is_protocol_error(err: &Error) -> bool {
+    matches!(*err, Error::BOOTLOADER(_))
+}
+
+impl RetryPolicy {
+    // resync gets the chance to bring the bootloader back in step before every retry
+    pub fn run<T, F, R>(
+        &self,
+        retries: &AtomicUsize,
+        delay: &dyn DelayProvider,
+        retryable: fn(&Error) -> bool,
+        mut op: F,
+        mut resync: R,
+    ) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+        R: FnMut(),
+    {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(ref e) if attempt < self.attempts && retryable(e) => {
+                    warn!("attempt {} of {} failed: {:?}", attempt, self.attempts, e);
+                    retries.fetch_add(1, Ordering::Relaxed);
+                    delay.sleep(backoff);
+                    backoff *= 2;
+                    resync();
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[test]
+fn test_retries_until_success() {
+    use delay::VirtualClock;
+
+    let policy = RetryPolicy {
+        attempts: 3,
+        backoff: Duration::from_millis(5),
+    };
+    let retries = AtomicUsize::new(0);
+    let clock = VirtualClock::new();
+    let mut calls = 0;
+    let mut resyncs = 0;
+    let result = policy.run(
+        &retries,
+        &clock,
+        is_rejected,
+        || {
+            calls += 1;
+            if calls < 3 {
+                Err(Error::BOOTLOADER(BlPkError::Nack))
+            } else {
+                Ok(calls)
+            }
+        },
+        || resyncs += 1,
+    );
+    assert_eq!(result.unwrap(), 3);
+    assert_eq!(resyncs, 2);
+    assert_eq!(retries.load(Ordering::Relaxed), 2);
+    assert_eq!(clock.slept(), Duration::from_millis(15));
+}
+
+#[test]
+fn test_gives_up() {
+    use delay::VirtualClock;
+
+    let policy = RetryPolicy {
+        attempts: 2,
+        backoff: Duration::from_millis(0),
+    };
+    let retries = AtomicUsize::new(0);
+    let result: Result<(), Error> = policy.run(
+        &retries,
+        &VirtualClock::new(),
+        is_rejected,
+        || Err(Error::BOOTLOADER(BlPkError::BadChecksum)),
+        || {},
+    );
+    match result {
+        Err(Error::BOOTLOADER(BlPkError::BadChecksum)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(retries.load(Ordering::Relaxed), 0);
+
+    let result: Result<(), Error> = policy.run(
+        &retries,
+        &VirtualClock::new(),
+        is_protocol_error,
+        || Err(Error::BOOTLOADER(BlPkError::BadChecksum)),
+        || {},
+    );
+    assert!(result.is_err());
+    assert_eq!(retries.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,79 @@
+use bootloader::commands::{Command, MemoryWrite};
+use bootloader::{AccessWidth, Bootloader, Error};
+use byteorder::{ByteOrder, LittleEndian};
+use device::SRAM_START;
+use firmware_image::Segment;
+use Cc131x;
+
+/*
+ *  The CC13xx/CC26xx ROM bootloader has no run command, so a RAM stub is started through the core itself:
+ *  the blob is a vector table followed by code, VTOR is pointed at it and PendSV is pended,
+ *  which makes the core take the stub's PendSV vector straight out of the bootloader's command loop
+ */
+
+const SCB_ICSR: u32 = 0xE000_ED04;
+const SCB_VTOR: u32 = 0xE000_ED08;
+const ICSR_PENDSVSET: u32 = 1 << 28;
+// the CC13xx/CC26xx vector table has 50 entries, VTOR needs it aligned to the next power of two
+const VTOR_ALIGN: u32 = 256;
+// the PendSV entry has to be part of the blob
+const PENDSV_VECTOR: usize = 0x38;
+
+// the blob has to hold the vector table up to PendSV and fit in SRAM at a VTOR aligned address
+pub fn check_ram_image(address: u32, len: usize, ram_size: usize) -> Result<(), Error> {
+    let start = address as usize;
+    let in_sram = start >= SRAM_START && start + len <= SRAM_START + ram_size;
+    if !in_sram || !address.is_multiple_of(VTOR_ALIGN) || len < PENDSV_VECTOR + 4 {
+        return Err(Error::BadRamImage { address, len });
+    }
+    Ok(())
+}
+
+impl Bootloader {
+    // COMMAND_DOWNLOAD only takes flash addresses, so the blob goes in with MemoryWrite and is CRC checked
+    // the final write never gets an ACK, the bootloader is gone by then
+    pub fn load_and_run(io: &Cc131x, address: u32, blob: &[u8]) -> Result<(), Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        check_ram_image(address, blob.len(), info.ram_size)?;
+
+        Bootloader::write_memory(io, address, blob)?;
+        let loaded = Segment::with_data(address as usize, blob.to_vec());
+        let (expected, actual) = Bootloader::compare_crc(io, &loaded)?;
+        if actual != expected {
+            return Err(Error::CrcMismatch {
+                address,
+                expected,
+                actual,
+            });
+        }
+
+        Bootloader::write_word(io, SCB_VTOR, address)?;
+        debug!(
+            "running RAM image at {:#010x}, PendSV handler {:#010x}",
+            address,
+            LittleEndian::read_u32(&blob[PENDSV_VECTOR..])
+        );
+        let mut pend = [0; 4];
+        LittleEndian::write_u32(&mut pend, ICSR_PENDSVSET);
+        let packet =
+            MemoryWrite::new(SCB_ICSR, AccessWidth::Word as u8, pend.to_vec()).serialize()?;
+        io.write(&packet)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_check_ram_image() {
+    const RAM_SIZE: usize = 20 * 1024;
+    assert!(check_ram_image(0x2000_0000, 0x100, RAM_SIZE).is_ok());
+    assert!(check_ram_image(0x2000_4F00, 0x100, RAM_SIZE).is_ok());
+    // past the end of SRAM
+    assert!(check_ram_image(0x2000_4F00, 0x101, RAM_SIZE).is_err());
+    // VTOR cannot point there
+    assert!(check_ram_image(0x2000_0080, 0x100, RAM_SIZE).is_err());
+    // flash
+    assert!(check_ram_image(0x0000_1000, 0x100, RAM_SIZE).is_err());
+    // no PendSV vector
+    assert!(check_ram_image(0x2000_0000, 0x38, RAM_SIZE).is_err());
+}
@@ -0,0 +1,344 @@
+use bootloader::commands::{check_ack, Error as BlPkError};
+use bootloader::progress::{BootloaderEvent, Progress, ProgressEvent};
+use bootloader::retry::is_rejected;
+use bootloader::{ccfg_last, Bootloader, Error, FlashReport};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use device::FlashLayout;
+use firmware_image::{FirmwareImage, Segment};
+use std::time::Duration;
+use Cc131x;
+
+/*
+ *  An open flashing stub is loaded into SRAM with load_and_run and then takes over the SPI slave,
+ *  accepting frames far larger than the 252 bytes a ROM SendData packet carries
+ *  The stub speaking this protocol is stub/flash_stub.c, built against TI's driverlib
+ *
+ *  Frame: 0xA5, op, payload length (u16 LE), address (u32 LE), payload, CRC32 of op..payload (u32 LE)
+ *  The stub answers like the ROM bootloader, 0xCC for ACK and 0x33 for NACK,
+ *  OP_CRC follows its ACK with a reply frame of the same op and address,
+ *  holding the CRC32 of [address, address + length) as a u32 LE
+ */
+
+pub const SYNC: u8 = 0xA5;
+const HEADER_LEN: usize = 8;
+pub const OP_PING: u8 = 0x00;
+pub const OP_ERASE: u8 = 0x01;
+pub const OP_WRITE: u8 = 0x02;
+// payload is the length to checksum as a u32 LE
+pub const OP_CRC: u8 = 0x03;
+// not answered, the chip resets into the application
+pub const OP_RESET: u8 = 0x04;
+
+pub const DEFAULT_MAX_FRAME: usize = 4096;
+// what the u16 length field can hold
+pub const MAX_FRAME: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone)]
+pub struct FlashStub {
+    pub blob: Vec<u8>,
+    // SRAM address the blob is linked for, see sram::check_ram_image
+    pub address: u32,
+    // largest payload per OP_WRITE frame, bounded by the stub's receive buffer and at most MAX_FRAME
+    pub max_frame: usize,
+}
+
+impl FlashStub {
+    pub fn new(blob: Vec<u8>, address: u32) -> FlashStub {
+        FlashStub {
+            blob,
+            address,
+            max_frame: DEFAULT_MAX_FRAME,
+        }
+    }
+}
+
+pub fn encode_frame(op: u8, address: u32, payload: &[u8]) -> Result<Vec<u8>, BlPkError> {
+    if payload.len() > MAX_FRAME {
+        return Err(BlPkError::MaxPayloadExceeded);
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len() + 4);
+    frame.push(SYNC);
+    frame.push(op);
+    let mut header = [0; 6];
+    LittleEndian::write_u16(&mut header, payload.len() as u16);
+    LittleEndian::write_u32(&mut header[2..], address);
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(payload);
+    let mut crc = [0; 4];
+    LittleEndian::write_u32(&mut crc, crc32::checksum_ieee(&frame[1..]));
+    frame.extend_from_slice(&crc);
+    Ok(frame)
+}
+
+// op, address and payload of a whole frame, None unless its length and CRC check out
+pub fn decode_frame(frame: &[u8]) -> Option<(u8, u32, &[u8])> {
+    if frame.len() < HEADER_LEN + 4 || frame[0] != SYNC {
+        return None;
+    }
+    let len = LittleEndian::read_u16(&frame[2..]) as usize;
+    if frame.len() != HEADER_LEN + len + 4 {
+        return None;
+    }
+    let end = HEADER_LEN + len;
+    if LittleEndian::read_u32(&frame[end..]) != crc32::checksum_ieee(&frame[1..end]) {
+        return None;
+    }
+    Some((
+        frame[1],
+        LittleEndian::read_u32(&frame[4..]),
+        &frame[HEADER_LEN..end],
+    ))
+}
+
+impl Bootloader {
+    // same end result as flash_firmware, with every erase and write going through the stub
+    pub(crate) fn flash_firmware_with_stub(
+        io: &Cc131x,
+        firmware: &FirmwareImage,
+        stub: &FlashStub,
+    ) -> Result<FlashReport, Error> {
+        if stub.max_frame == 0 || stub.max_frame > MAX_FRAME {
+            return Err(BlPkError::MaxPayloadExceeded.into());
+        }
+        let _bus = io.bus();
+        let retries = io.retry_count();
+        let info = Bootloader::initialize(io)?;
+        let (firmware, skipped) = Self::flash_part(io, firmware, &FlashLayout::from(&info))?;
+        Self::check_cancelled(io)?;
+        Bootloader::load_and_run(io, stub.address, &stub.blob)?;
+        io.retry.run(
+            &io.retries,
+            &*io.delay,
+            is_rejected,
+            || Self::stub_command(io, "stub_ping", io.timeouts.command, OP_PING, 0, &[]),
+            || {},
+        )?;
+
+        for sector in (0..info.flash_size).step_by(info.sector_size) {
+            Self::check_cancelled(io)?;
+            let sector = sector as u32;
+            Self::stub_command(io, "stub_erase", io.timeouts.erase, OP_ERASE, sector, &[])?;
+            Self::report(io, ProgressEvent::SectorErased { addr: sector });
+        }
+
+        let segments = firmware.segments.iter();
+        let reordered;
+        let segments: Vec<&Segment> = if info.family.defers_ccfg() {
+            reordered = ccfg_last(segments, info.ccfg_address());
+            reordered.segments.iter().collect()
+        } else {
+            segments.collect()
+        };
+        let mut progress = Progress::new(segments.iter().map(|s| s.data.len()).sum());
+        for segment in segments {
+            let start = segment.start as u32;
+            Self::report(
+                io,
+                ProgressEvent::SegmentStarted {
+                    addr: start,
+                    len: segment.data.len(),
+                },
+            );
+            let mut offset = 0;
+            for payload in segment.data.chunks(stub.max_frame) {
+                Self::check_cancelled(io)?;
+                let address = start + offset;
+                // a NACKed frame was dropped by the stub, sending it again is safe
+                io.retry.run(
+                    &io.retries,
+                    &*io.delay,
+                    is_rejected,
+                    || {
+                        let timeout = io.timeouts.send_data;
+                        Self::stub_command(io, "stub_write", timeout, OP_WRITE, address, payload)
+                    },
+                    || {},
+                )?;
+                offset += payload.len() as u32;
+                Self::report(io, progress.advance(payload.len()));
+            }
+
+            let mut len = [0; 4];
+            LittleEndian::write_u32(&mut len, segment.data.len() as u32);
+            let rest = Self::stub_command(io, "stub_crc", io.timeouts.crc, OP_CRC, start, &len)?;
+            let actual = Self::stub_crc_reply(io, start, rest)?;
+            io.emit(BootloaderEvent::SegmentWritten {
+                addr: start,
+                len: segment.data.len(),
+                crc: segment.crc,
+            });
+            io.emit(BootloaderEvent::CrcChecked {
+                addr: start,
+                len: segment.data.len(),
+                expected: segment.crc,
+                actual,
+            });
+            if actual != segment.crc {
+                return Err(Error::CrcMismatch {
+                    address: start,
+                    expected: segment.crc,
+                    actual,
+                });
+            }
+            Self::report(io, ProgressEvent::Verified { addr: start });
+        }
+
+        io.write(&encode_frame(OP_RESET, 0, &[])?)?;
+        Ok(FlashReport {
+            retries: io.retry_count() - retries,
+            skipped,
+        })
+    }
+
+    // returns whatever was clocked in after the ACK
+    fn stub_command(
+        io: &Cc131x,
+        operation: &'static str,
+        timeout: Duration,
+        op: u8,
+        address: u32,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let response = io.write(&encode_frame(op, address, payload)?)?;
+        let response = Self::await_ack(io, operation, timeout, response)?;
+        Ok(io.tally(check_ack(response))?)
+    }
+
+    // the stub clocks out zeros until its reply frame is ready, rest is whatever came in after the ACK
+    fn stub_crc_reply(io: &Cc131x, address: u32, mut reply: Vec<u8>) -> Result<u32, Error> {
+        const REPLY_LEN: usize = HEADER_LEN + 4 + 4;
+        const POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+        let start = io.delay.now();
+        loop {
+            let leading = reply.iter().take_while(|b| **b == 0).count();
+            reply.drain(..leading);
+            if reply.len() >= REPLY_LEN {
+                break;
+            }
+            let elapsed = io.delay.elapsed(start);
+            if elapsed > io.timeouts.crc {
+                return Err(Error::Timeout {
+                    operation: "stub_crc",
+                    elapsed,
+                });
+            }
+            if reply.is_empty() {
+                io.delay.sleep(POLL_INTERVAL);
+            }
+            let mut more = vec![0; REPLY_LEN - reply.len()];
+            io.read(more.as_mut_slice())?;
+            reply.extend_from_slice(&more);
+        }
+        match decode_frame(&reply[..REPLY_LEN]) {
+            Some((OP_CRC, echoed, crc)) if echoed == address && crc.len() == 4 => {
+                Ok(LittleEndian::read_u32(crc))
+            }
+            _ => Err(io.protocol_error(BlPkError::BadChecksum)),
+        }
+    }
+}
+
+#[test]
+fn test_encode_frame() {
+    let frame = encode_frame(OP_WRITE, 0x0001_2000, &[0xDE, 0xAD]).unwrap();
+    assert_eq!(
+        &frame[..10],
+        &[0xA5, 0x02, 0x02, 0x00, 0x00, 0x20, 0x01, 0x00, 0xDE, 0xAD]
+    );
+    assert_eq!(
+        LittleEndian::read_u32(&frame[10..]),
+        crc32::checksum_ieee(&frame[1..10])
+    );
+    assert_eq!(encode_frame(OP_PING, 0, &[]).unwrap().len(), 12);
+    assert_eq!(
+        encode_frame(OP_WRITE, 0, &[0; MAX_FRAME]).unwrap().len(),
+        MAX_FRAME + 12
+    );
+    assert!(matches!(
+        encode_frame(OP_WRITE, 0, &[0; MAX_FRAME + 1]),
+        Err(BlPkError::MaxPayloadExceeded)
+    ));
+}
+
+#[test]
+fn test_decode_frame() {
+    let frame = encode_frame(OP_CRC, 0x1000, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(
+        decode_frame(&frame),
+        Some((OP_CRC, 0x1000, &[1, 2, 3, 4][..]))
+    );
+    let mut corrupted = frame.clone();
+    corrupted[9] ^= 1;
+    assert_eq!(decode_frame(&corrupted), None);
+    assert_eq!(decode_frame(&frame[..frame.len() - 1]), None);
+}
+
+#[test]
+fn test_flash_firmware_with_stub_on_mock() {
+    use device::SRAM_START;
+    use mock::{MockChip, Seen, FLASH_SIZE, SECTOR_SIZE};
+
+    let chip = MockChip::new();
+    // the CRC reply frame only follows a while after the ACK
+    chip.set_latency(20);
+    let mut io = MockChip::handle(&chip);
+    let mut stub = FlashStub::new(vec![0x5A; 0x100], SRAM_START as u32);
+    stub.max_frame = 1024;
+    io.flash_stub = Some(stub);
+    let events = io.subscribe();
+    let firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x1000, (0..3000).map(|i| (i * 7) as u8).collect()),
+            MockChip::ccfg(),
+        ],
+    };
+    io.flash_firmware(&firmware).unwrap();
+
+    let flash = chip.flash();
+    assert_eq!(
+        &flash[0x1000..0x1000 + 3000],
+        &firmware.segments[0].data[..]
+    );
+    assert_eq!(chip.count(Seen::Stub(OP_ERASE)), FLASH_SIZE / SECTOR_SIZE);
+    assert_eq!(chip.count(Seen::Stub(OP_WRITE)), 3 + 1);
+    assert_eq!(chip.count(Seen::Stub(OP_CRC)), 2);
+    assert!(!chip.seen().iter().any(|s| matches!(s, Seen::Dropped(_))));
+
+    let events: Vec<BootloaderEvent> = events.try_iter().collect();
+    let erased = events
+        .iter()
+        .filter(|e| matches!(e, BootloaderEvent::SectorErased { .. }))
+        .count();
+    assert_eq!(erased, FLASH_SIZE / SECTOR_SIZE);
+    assert!(events.contains(&BootloaderEvent::CrcChecked {
+        addr: 0x1000,
+        len: 3000,
+        expected: firmware.segments[0].crc,
+        actual: firmware.segments[0].crc,
+    }));
+}
+
+#[test]
+fn test_stub_frame_too_large_for_its_length_field() {
+    use device::SRAM_START;
+    use mock::{MockChip, Seen};
+
+    let chip = MockChip::new();
+    let mut io = MockChip::handle(&chip);
+    let mut stub = FlashStub::new(vec![0x5A; 0x100], SRAM_START as u32);
+    stub.max_frame = MAX_FRAME + 1;
+    io.flash_stub = Some(stub);
+    let firmware = FirmwareImage {
+        segments: vec![MockChip::ccfg()],
+    };
+    match io.flash_firmware(&firmware).map(|_| ()) {
+        Err(::Error::BOOTLOADER(ref e)) => assert!(matches!(
+            *e.root(),
+            Error::BOOTLOADER(BlPkError::MaxPayloadExceeded)
+        )),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(chip.count(Seen::Stub(OP_PING)), 0);
+}
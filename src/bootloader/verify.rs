@@ -0,0 +1,160 @@
+use bootloader::progress::ProgressEvent;
+use bootloader::{Bootloader, Error};
+use device::FlashLayout;
+use firmware_image::{FirmwareImage, Segment, DEFAULT_FILL};
+use Cc131x;
+
+/*
+ *  How flashing checks what it wrote, from nothing at all to reading every byte back
+ *  CrcWholeImage costs a single CRC command, with the gaps between segments expected to read back erased
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum VerifyMode {
+    None,
+    // one CRC command after each segment, as flashing always did
+    #[default]
+    CrcPerSegment,
+    // one CRC command over everything from the first to the last byte written, once all are written
+    CrcWholeImage,
+    // every segment read back and compared byte by byte, once all are written
+    ReadBack,
+}
+
+// largest read the command layer accepts in one go
+const CHUNK: usize = 252;
+
+// the image as one span from its first to its last byte, gaps filled like freshly erased flash
+fn whole_image(segments: &[&Segment]) -> Option<Segment> {
+    let start = segments.iter().map(|s| s.start).min()?;
+    let image = FirmwareImage {
+        segments: segments.iter().map(|s| (*s).clone()).collect(),
+    };
+    Some(Segment::with_data(start, image.to_bin(start, DEFAULT_FILL)))
+}
+
+impl Bootloader {
+    // the checks mode leaves for after every segment is written, CrcPerSegment has done its part already
+    pub(crate) fn check_written(
+        io: &Cc131x,
+        segments: &[&Segment],
+        mode: VerifyMode,
+    ) -> Result<(), Error> {
+        match mode {
+            VerifyMode::None | VerifyMode::CrcPerSegment => Ok(()),
+            VerifyMode::CrcWholeImage => match whole_image(segments) {
+                Some(span) => Self::check_crc(io, &span),
+                None => Ok(()),
+            },
+            VerifyMode::ReadBack => Self::read_back(io, segments),
+        }
+    }
+
+    // checks a chip flashed earlier, CrcPerSegment checks each segment on its own
+    // segments outside flash are left out
+    pub fn verify(io: &Cc131x, firmware: &FirmwareImage, mode: VerifyMode) -> Result<(), Error> {
+        let _bus = io.bus();
+        let info = Bootloader::initialize(io)?;
+        let (firmware, _) = firmware.classify(&FlashLayout::from(&info));
+        let segments: Vec<&Segment> = firmware.segments.iter().collect();
+        let result = match mode {
+            VerifyMode::CrcPerSegment => segments
+                .iter()
+                .try_for_each(|segment| Self::check_crc(io, segment)),
+            mode => Self::check_written(io, &segments, mode),
+        };
+        Bootloader::system_reset(io)?;
+        result
+    }
+
+    pub(crate) fn check_crc(io: &Cc131x, segment: &Segment) -> Result<(), Error> {
+        Self::check_cancelled(io)?;
+        let address = segment.start as u32;
+        let (expected, actual) = Self::compare_crc(io, segment)?;
+        if actual != expected {
+            return Err(Error::CrcMismatch {
+                address,
+                expected,
+                actual,
+            });
+        }
+        Self::report(io, ProgressEvent::Verified { addr: address });
+        Ok(())
+    }
+
+    pub(crate) fn read_back(io: &Cc131x, segments: &[&Segment]) -> Result<(), Error> {
+        for segment in segments {
+            for (i, expected) in segment.data.chunks(CHUNK).enumerate() {
+                Self::check_cancelled(io)?;
+                let address = (segment.start + i * CHUNK) as u32;
+                let actual = Bootloader::read_memory(io, address, expected.len())?;
+                if let Some(offset) = expected.iter().zip(&actual).position(|(e, a)| e != a) {
+                    return Err(Error::VerifyMismatch {
+                        address: address + offset as u32,
+                        expected: expected[offset],
+                        actual: actual[offset],
+                    });
+                }
+            }
+            Self::report(
+                io,
+                ProgressEvent::Verified {
+                    addr: segment.start as u32,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_whole_image_fills_gaps() {
+    let segments = [
+        Segment::with_data(0x1000, vec![1; 4]),
+        Segment::with_data(0x1008, vec![2; 4]),
+    ];
+    let span = whole_image(&segments.iter().collect::<Vec<_>>()).unwrap();
+    assert_eq!(span.start, 0x1000);
+    assert_eq!(span.data, [1, 1, 1, 1, 0xFF, 0xFF, 0xFF, 0xFF, 2, 2, 2, 2]);
+    assert!(whole_image(&[]).is_none());
+}
+
+#[test]
+fn test_verify_with_read_repeat_on_mock() {
+    use bootloader::progress::BootloaderEvent;
+    use device::{ChipFamily, ChipProfile, CrcParams};
+    use mock::{MockChip, FLASH_SIZE, SECTOR_SIZE};
+
+    let chip = MockChip::new();
+    let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    chip.load(0x2000, &data);
+    let mut io = MockChip::handle(&chip);
+    let firmware = FirmwareImage {
+        segments: vec![Segment::with_data(0x2000, data)],
+    };
+    io.profile = Some(ChipProfile {
+        family: ChipFamily::Cc13x0,
+        flash_size: FLASH_SIZE,
+        sram_size: 20 * 1024,
+        sector_size: SECTOR_SIZE,
+        require_sector_alignment: false,
+        crc: CrcParams {
+            read_repeat: 2,
+            ..CrcParams::default()
+        },
+    });
+    let events = io.subscribe();
+    io.verify(&firmware, VerifyMode::CrcPerSegment).unwrap();
+    io.verify(&firmware, VerifyMode::CrcWholeImage).unwrap();
+
+    // the segment's own IEEE CRC is not what the chip computes reading everything three times
+    match events
+        .try_iter()
+        .find(|e| matches!(e, BootloaderEvent::CrcChecked { .. }))
+    {
+        Some(BootloaderEvent::CrcChecked { expected, .. }) => {
+            assert_ne!(expected, firmware.segments[0].crc)
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+}
@@ -0,0 +1,230 @@
+use bincode::serialize;
+use byteorder::{ByteOrder, LittleEndian};
+use device::ChipFamily;
+#[cfg(feature = "crypto")]
+use ed25519_compact::{KeyPair, PublicKey, Seed, Signature};
+use firmware_image::{self, FirmwareImage};
+use sha2::{Digest, Sha256};
+use std::error;
+use std::fmt;
+
+/*
+ *  A FirmwareBundle is what gets shipped to the field: the image plus enough metadata
+ *  to decide whether and where it may be flashed
+ */
+
+const MAGIC: &[u8; 4] = b"CCFB";
+
+#[derive(Debug)]
+pub enum Error {
+    Format(firmware_image::Error),
+    HashMismatch,
+    Unsigned,
+    BadSignature,
+}
+
+impl From<firmware_image::Error> for Error {
+    fn from(err: firmware_image::Error) -> Error {
+        Error::Format(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Format(ref err) => write!(f, "malformed bundle: {}", err),
+            Error::HashMismatch => write!(f, "image does not match the bundle hash"),
+            Error::Unsigned => write!(f, "bundle is not signed"),
+            Error::BadSignature => write!(f, "bundle signature is invalid"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Format(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Version {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FirmwareBundle {
+    pub version: Version,
+    pub target: ChipFamily,
+    pub build_id: String,
+    pub sha256: [u8; 32],
+    // ed25519 signature over the manifest, see signed_message
+    pub signature: Option<Vec<u8>>,
+    pub image: FirmwareImage,
+}
+
+// decides which bundles may be flashed at all
+#[derive(Debug, Clone, Default)]
+pub enum SignaturePolicy {
+    #[default]
+    AllowUnsigned,
+    // holds the vendor's ed25519 public key
+    #[cfg(feature = "crypto")]
+    RequireSigned([u8; 32]),
+}
+
+impl SignaturePolicy {
+    pub fn check(&self, bundle: &FirmwareBundle) -> Result<(), Error> {
+        match *self {
+            SignaturePolicy::AllowUnsigned => bundle.check_hash(),
+            #[cfg(feature = "crypto")]
+            SignaturePolicy::RequireSigned(ref public_key) => bundle.verify(public_key),
+        }
+    }
+
+    // a bare image carries no signature
+    pub fn check_image(&self, _image: &FirmwareImage) -> Result<(), Error> {
+        match *self {
+            SignaturePolicy::AllowUnsigned => Ok(()),
+            #[cfg(feature = "crypto")]
+            SignaturePolicy::RequireSigned(_) => Err(Error::Unsigned),
+        }
+    }
+}
+
+// hashes every segment's address, length and data in order
+pub fn segments_sha256(image: &FirmwareImage) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for segment in &image.segments {
+        let mut header = [0; 8];
+        LittleEndian::write_u32(&mut header[..4], segment.start as u32);
+        LittleEndian::write_u32(&mut header[4..], segment.data.len() as u32);
+        hasher.input(header);
+        hasher.input(&segment.data);
+    }
+    hasher.result().into()
+}
+
+impl FirmwareBundle {
+    pub fn new(
+        image: FirmwareImage,
+        version: Version,
+        target: ChipFamily,
+        build_id: &str,
+    ) -> FirmwareBundle {
+        FirmwareBundle {
+            version,
+            target,
+            build_id: build_id.to_string(),
+            sha256: segments_sha256(&image),
+            signature: None,
+            image,
+        }
+    }
+
+    pub fn check_hash(&self) -> Result<(), Error> {
+        if segments_sha256(&self.image) != self.sha256 {
+            return Err(Error::HashMismatch);
+        }
+        Ok(())
+    }
+
+    // the signature covers the metadata and, through sha256, the image contents
+    pub fn signed_message(&self) -> Result<Vec<u8>, Error> {
+        let message = serialize(&(&self.version, &self.target, &self.build_id, &self.sha256))
+            .map_err(firmware_image::Error::from)?;
+        Ok(message)
+    }
+
+    #[cfg(feature = "crypto")]
+    pub fn sign(&mut self, seed: &[u8; 32]) -> Result<(), Error> {
+        let key_pair = KeyPair::from_seed(Seed::new(*seed));
+        let signature = key_pair.sk.sign(self.signed_message()?, None);
+        self.signature = Some(signature.to_vec());
+        Ok(())
+    }
+
+    #[cfg(feature = "crypto")]
+    pub fn verify(&self, public_key: &[u8; 32]) -> Result<(), Error> {
+        self.check_hash()?;
+        let signature = match self.signature {
+            Some(ref signature) => {
+                Signature::from_slice(signature).map_err(|_| Error::BadSignature)?
+            }
+            None => return Err(Error::Unsigned),
+        };
+        PublicKey::new(*public_key)
+            .verify(self.signed_message()?, &signature)
+            .map_err(|_| Error::BadSignature)
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        Ok(firmware_image::encode_versioned(MAGIC, self)?)
+    }
+
+    pub fn deserialize(encoded: &[u8]) -> Result<FirmwareBundle, Error> {
+        let bundle: FirmwareBundle = firmware_image::decode_versioned(MAGIC, encoded)?;
+        bundle.image.verify_integrity()?;
+        Ok(bundle)
+    }
+}
+
+#[test]
+fn test_bundle_round_trip() {
+    const FW_FILE: &str = include_str!("firmware/test_parsing.ihex");
+    let image = FirmwareImage::new(FW_FILE).unwrap();
+    let bundle = FirmwareBundle::new(image, Version::new(1, 2, 3), ChipFamily::Cc13x0, "abc123");
+
+    let encoded = bundle.serialize().unwrap();
+    let mut decoded = FirmwareBundle::deserialize(&encoded).unwrap();
+    assert_eq!(decoded.version, Version::new(1, 2, 3));
+    assert_eq!(decoded.target, ChipFamily::Cc13x0);
+    assert_eq!(decoded.build_id, "abc123");
+    decoded.check_hash().unwrap();
+
+    decoded.image.segments[0].data[0] ^= 0xFF;
+    match decoded.check_hash() {
+        Err(Error::HashMismatch) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn test_signature_policy() {
+    const FW_FILE: &str = include_str!("firmware/test_parsing.ihex");
+    let seed = [7; 32];
+    let public_key = *KeyPair::from_seed(Seed::new(seed)).pk;
+    let policy = SignaturePolicy::RequireSigned(public_key);
+
+    let image = FirmwareImage::new(FW_FILE).unwrap();
+    let mut bundle = FirmwareBundle::new(image, Version::new(1, 0, 0), ChipFamily::Cc13x0, "b1");
+    match policy.check(&bundle) {
+        Err(Error::Unsigned) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(policy.check_image(&bundle.image).is_err());
+
+    bundle.sign(&seed).unwrap();
+    policy.check(&bundle).unwrap();
+
+    bundle.build_id = "b2".to_string();
+    match policy.check(&bundle) {
+        Err(Error::BadSignature) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
@@ -0,0 +1,1589 @@
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::ThreadId;
+use std::time::Duration;
+
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SPI_3WIRE, SPI_MODE_3};
+use sysfs_gpio::{Direction, Pin};
+
+use audit::AuditLog;
+use bootloader::calibrate::{BenchReport, Calibration, Scratch};
+use bootloader::campaign::{self, CampaignOptions};
+use bootloader::cancel::CancelToken;
+use bootloader::caps::BootloaderCapabilities;
+use bootloader::commands::{is_command_packet, Error as BlPkError};
+use bootloader::progress::{BootloaderEvent, ProgressSink};
+use bootloader::protect::SectorProtection;
+use bootloader::recover::Recovery;
+use bootloader::resume::StateStore;
+use bootloader::retry::RetryPolicy;
+use bootloader::stub::FlashStub;
+use bootloader::verify::VerifyMode;
+use bootloader::{Bootloader, DryRun, FlashReport, Probe, Timeouts};
+use bundle::{FirmwareBundle, SignaturePolicy};
+use ccfg::{self, Ccfg, CcfgError, CcfgOverrides, CcfgPolicy, UnsafeOverride};
+use config::Cc131xConfig;
+use delay::{DelayProvider, StdDelay};
+use device::{ChipProfile, CrcParams, FlashLayout};
+#[cfg(any(test, feature = "fault-injection"))]
+use fault::FaultInjector;
+use firmware_image::FirmwareImage;
+use oad::{OadHeader, HEADER_LEN as OAD_HEADER_LEN};
+use session::{BootloaderSession, BusLock};
+use stats::{Counters, Statistics};
+use transport::{Line, Transport};
+use {bootloader, bundle, config, firmware_image, oad};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct SpiConfig {
+    pub max_speed_hz: u32,
+    #[serde(with = "config::spi_mode_bits")]
+    pub mode: SpiModeFlags,
+    // 3-wire SSI with MOSI and MISO shared: SPI_3WIRE is added to mode and every exchange is
+    // split into a write followed by separate reads, so ACKs are only ever seen while polling
+    pub half_duplex: bool,
+}
+
+impl Default for SpiConfig {
+    fn default() -> SpiConfig {
+        SpiConfig {
+            max_speed_hz: 4_000_000,
+            // SPI_MODE_3 is picked to match built-in bootloader on CC131x
+            mode: SPI_MODE_3,
+            half_duplex: false,
+        }
+    }
+}
+
+// levels and timings used when resetting the chip and driving the bootloader backdoor
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PinConfig {
+    // false when asserting the pin means pulling it low
+    pub reset_active_high: bool,
+    pub bootloader_en_active_high: bool,
+    // CC13xx DIO that bootloader_en is wired to, i.e. BL_PIN_NUMBER in CCFG
+    pub backdoor_dio: u8,
+    // how long reset is held asserted
+    pub reset_pulse: Duration,
+    // how long the chip is given to boot after reset is released
+    pub reset_settle: Duration,
+    // how long bootloader_en stays asserted once out of reset
+    pub bootloader_hold: Duration,
+}
+
+impl Default for PinConfig {
+    fn default() -> PinConfig {
+        PinConfig {
+            reset_active_high: false,
+            bootloader_en_active_high: false,
+            backdoor_dio: BL_BACKDOOR_PIN,
+            reset_pulse: Duration::from_millis(15),
+            reset_settle: Duration::from_millis(35),
+            bootloader_hold: Duration::from_millis(20),
+        }
+    }
+}
+
+impl PinConfig {
+    // takes over BL_PIN_NUMBER and BL_LEVEL so enter_bootloader drives what the image expects
+    pub fn backdoor_from_ccfg(&mut self, ccfg: &Ccfg) {
+        self.backdoor_dio = ccfg.backdoor_pin;
+        self.bootloader_en_active_high = ccfg.backdoor_active_high;
+    }
+}
+
+// how hard enter_bootloader tries before giving up, e.g. after a brown-out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EntryPolicy {
+    // with more than one attempt every entry is confirmed with a ping
+    pub attempts: usize,
+    // doubles the reset pulse on every retry
+    pub extend_reset: bool,
+    // unexports and exports both GPIOs again before a retry
+    pub reexport_gpios: bool,
+}
+
+impl Default for EntryPolicy {
+    fn default() -> EntryPolicy {
+        EntryPolicy {
+            attempts: 1,
+            extend_reset: true,
+            reexport_gpios: false,
+        }
+    }
+}
+
+// where the version compared by the downgrade check lives
+enum VersionAt {
+    Word(u32),
+    OadHeader(u32),
+}
+
+fn drive(pin: &dyn Line, asserted: bool, active_high: bool) -> Result<(), sysfs_gpio::Error> {
+    pin.set_value((asserted == active_high) as u8)
+}
+
+pub struct Cc131x {
+    // spidev and sysfs GPIO when built by Cc131xBuilder
+    pub io: Box<dyn Transport>,
+    pub reset: Box<dyn Line>,
+    pub bootloader_en: Box<dyn Line>,
+    // handshake lines are not routed on every board
+    pub slave_ready: Option<Box<dyn Line>>,
+    pub slave_tx_req: Option<Box<dyn Line>>,
+    // flash address of a version word the application embeds in its image
+    pub version_address: Option<u32>,
+    // flash address of an OAD image header, its software version is used when there is no version word
+    pub oad_header_address: Option<u32>,
+    // flashing refuses images older than the one on the chip unless this is set
+    pub allow_downgrade: bool,
+    // segments closer than this many bytes share one CRC command in need_to_update_firmware
+    pub crc_coalesce_gap: Option<usize>,
+    pub retry: RetryPolicy,
+    pub timeouts: Timeouts,
+    // set when the board wires slave_ready to the CC13xx handshake line, ignored without slave_ready
+    pub flow_control: bool,
+    // polls for each SendData ACK in the same transfer as the packet, see Bootloader::write_payload
+    pub pipeline: bool,
+    // how flashing checks what it wrote, see VerifyMode
+    pub verify: VerifyMode,
+    // refuse images with data outside flash, e.g. RAM-load segments, instead of leaving it out
+    pub strict_segments: bool,
+    // flash_firmware loads it into SRAM and streams the image to it instead of using the ROM protocol
+    pub flash_stub: Option<FlashStub>,
+    pub pins: PinConfig,
+    pub entry: EntryPolicy,
+    // see SpiConfig::half_duplex
+    pub half_duplex: bool,
+    // SPI clock, sizes the zeros clocked out after a packet to collect its reply in the same transfer
+    pub clock_hz: u32,
+    // largest single SPI transfer the spidev driver accepts, longer ones are split
+    pub max_transfer: usize,
+    // images are validated against it before flashing when set
+    pub profile: Option<ChipProfile>,
+    pub progress: Option<Box<dyn ProgressSink>>,
+    // every erase, write and CRC check is recorded here, the caller keeps a clone to read it back
+    pub audit: Option<Arc<Mutex<AuditLog>>>,
+    // every pause and protocol deadline, StdDelay unless replaced
+    pub delay: Arc<dyn DelayProvider>,
+    // checked between chunks by flashing, verifying and dumping
+    pub cancel: Option<CancelToken>,
+    // lets images through whose CCFG disables the bootloader, its backdoor or the debug port
+    pub unsafe_override: Option<UnsafeOverride>,
+    // CCFG fields rewritten in every image before it is written or compared, e.g. per board revision
+    pub ccfg_overrides: Option<CcfgOverrides>,
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fault_injector: Option<FaultInjector>,
+    pub(crate) retries: AtomicUsize,
+    counters: Counters,
+    // held by the BootloaderSession so threads sharing the handle take turns on the bus, see BusLock
+    pub(crate) session_lock: Mutex<()>,
+    session_owner: Mutex<Option<ThreadId>>,
+    // sessions open on the thread holding the bus lock, only the outermost one enters and resets
+    pub(crate) sessions: AtomicUsize,
+    subscribers: Mutex<Vec<Sender<BootloaderEvent>>>,
+    // bootloader_en is always exported by the builder, reset only by reexport_gpios
+    reset_exported: AtomicBool,
+    // see lock_device, only ever dropped, None when not on spidev
+    _device_lock: Option<File>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(std::io::Error),
+    GPIO(sysfs_gpio::Error),
+    BOOTLOADER(bootloader::Error),
+    DESER(bincode::Error),
+    CCFG(CcfgError),
+    BUNDLE(bundle::Error),
+    // the operation needs a GPIO that was not configured
+    MissingPin(&'static str),
+    IMAGE(firmware_image::Error),
+    OAD(oad::Error),
+    // a GpioLine chip or label that is not on this board
+    GpioNotFound(String),
+    // the image carries an older version than the chip, see allow_downgrade
+    DowngradeRefused { current: u32, requested: u32 },
+    // the bootloader cannot read the chip's version back to compare, see allow_downgrade
+    DowngradeUnchecked { requested: u32 },
+    // another handle, usually in another process, holds the lock on this spidev node
+    DeviceBusy(PathBuf),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::IO(err)
+    }
+}
+
+impl From<sysfs_gpio::Error> for Error {
+    fn from(err: sysfs_gpio::Error) -> Error {
+        Error::GPIO(err)
+    }
+}
+
+impl From<bootloader::Error> for Error {
+    fn from(err: bootloader::Error) -> Error {
+        Error::BOOTLOADER(err)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Error {
+        Error::DESER(err)
+    }
+}
+
+impl From<bundle::Error> for Error {
+    fn from(err: bundle::Error) -> Error {
+        Error::BUNDLE(err)
+    }
+}
+
+impl From<firmware_image::Error> for Error {
+    fn from(err: firmware_image::Error) -> Error {
+        Error::IMAGE(err)
+    }
+}
+
+impl From<oad::Error> for Error {
+    fn from(err: oad::Error) -> Error {
+        Error::OAD(err)
+    }
+}
+
+impl From<CcfgError> for Error {
+    fn from(err: CcfgError) -> Error {
+        Error::CCFG(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IO(ref err) => write!(f, "I/O error: {}", err),
+            Error::GPIO(ref err) => write!(f, "GPIO error: {}", err),
+            Error::BOOTLOADER(ref err) => write!(f, "{}", err),
+            Error::DESER(ref err) => write!(f, "deserialization failed: {}", err),
+            Error::CCFG(ref err) => write!(f, "CCFG check failed: {}", err),
+            Error::BUNDLE(ref err) => write!(f, "bundle rejected: {}", err),
+            Error::MissingPin(pin) => write!(f, "{} pin is not configured", pin),
+            Error::IMAGE(ref err) => write!(f, "firmware image error: {}", err),
+            Error::OAD(ref err) => write!(f, "OAD image error: {}", err),
+            Error::GpioNotFound(ref line) => write!(f, "GPIO line {} not found", line),
+            Error::DowngradeRefused { current, requested } => write!(
+                f,
+                "refusing to downgrade from version {:#010x} to {:#010x}",
+                current, requested
+            ),
+            Error::DowngradeUnchecked { requested } => write!(
+                f,
+                "cannot read the chip's version to check version {:#010x} is no downgrade",
+                requested
+            ),
+            Error::DeviceBusy(ref path) => {
+                write!(f, "{} is in use by another process", path.display())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IO(ref err) => Some(err),
+            Error::GPIO(ref err) => Some(err),
+            Error::BOOTLOADER(ref err) => Some(err),
+            Error::DESER(ref err) => Some(err),
+            Error::CCFG(ref err) => Some(err),
+            Error::BUNDLE(ref err) => Some(err),
+            Error::MissingPin(_) => None,
+            Error::IMAGE(ref err) => Some(err),
+            Error::OAD(ref err) => Some(err),
+            Error::GpioNotFound(_) => None,
+            Error::DowngradeRefused { .. } => None,
+            Error::DowngradeUnchecked { .. } => None,
+            Error::DeviceBusy(_) => None,
+        }
+    }
+}
+
+// where the zeros after a command packet start, reads are all padding and anything else is all written
+fn padding_start(tx: &[u8]) -> usize {
+    if is_command_packet(tx) {
+        let size = tx[0] as usize;
+        let checksum = tx[2..size.min(tx.len())]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if size <= tx.len() && checksum == tx[1] && tx[size..].iter().all(|b| *b == 0) {
+            return size;
+        }
+    }
+    if tx.iter().all(|b| *b == 0) {
+        0
+    } else {
+        tx.len()
+    }
+}
+
+#[cfg(feature = "trace-packets")]
+fn hex(buf: &[u8]) -> String {
+    buf.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// the spidev module rejects longer transfers with EMSGSIZE, its default is 4096 bytes
+const SPIDEV_BUFSIZ: &str = "/sys/module/spidev/parameters/bufsiz";
+
+fn spidev_bufsiz() -> usize {
+    std::fs::read_to_string(SPIDEV_BUFSIZ)
+        .ok()
+        .and_then(|s| parse_bufsiz(&s))
+        .unwrap_or(4096)
+}
+
+// advisory flock on the spidev node, released when the file is closed with the handle
+// every process driving this chip through Cc131x gets DeviceBusy instead of interleaving commands
+fn lock_device(path: &Path) -> Result<File, Error> {
+    let file = File::open(path)?;
+    if !try_lock(&file)? {
+        return Err(Error::DeviceBusy(path.to_path_buf()));
+    }
+    Ok(file)
+}
+
+fn try_lock(file: &File) -> io::Result<bool> {
+    // flock only touches the lock of the open file description behind the fd
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+fn parse_bufsiz(s: &str) -> Option<usize> {
+    match s.trim().parse() {
+        Ok(0) | Err(_) => None,
+        Ok(n) => Some(n),
+    }
+}
+
+fn sysfs_line(pin: u16) -> Box<dyn Line> {
+    Box::new(Pin::new(pin.into()))
+}
+
+// only reset and bootloader_en are mandatory
+pub struct Cc131xBuilder {
+    path: PathBuf,
+    reset: u16,
+    bootloader_en: u16,
+    slave_ready: Option<u16>,
+    slave_tx_req: Option<u16>,
+    flow_control: bool,
+    spi: SpiConfig,
+    pins: PinConfig,
+}
+
+impl Cc131xBuilder {
+    pub fn new<P: AsRef<Path>>(path: P, reset: u16, bootloader_en: u16) -> Cc131xBuilder {
+        Cc131xBuilder {
+            path: path.as_ref().to_path_buf(),
+            reset,
+            bootloader_en,
+            slave_ready: None,
+            slave_tx_req: None,
+            flow_control: false,
+            spi: SpiConfig::default(),
+            pins: PinConfig::default(),
+        }
+    }
+
+    pub fn slave_ready(mut self, pin: u16) -> Cc131xBuilder {
+        self.slave_ready = Some(pin);
+        self
+    }
+
+    pub fn slave_tx_req(mut self, pin: u16) -> Cc131xBuilder {
+        self.slave_tx_req = Some(pin);
+        self
+    }
+
+    // paces responses on slave_ready instead of polling, needs slave_ready to be set
+    pub fn flow_control(mut self, enabled: bool) -> Cc131xBuilder {
+        self.flow_control = enabled;
+        self
+    }
+
+    pub fn spi_config(mut self, spi: SpiConfig) -> Cc131xBuilder {
+        self.spi = spi;
+        self
+    }
+
+    pub fn pin_config(mut self, pins: PinConfig) -> Cc131xBuilder {
+        self.pins = pins;
+        self
+    }
+
+    pub fn build(self) -> Result<Cc131x, Error> {
+        if self.flow_control && self.slave_ready.is_none() {
+            return Err(Error::MissingPin("slave_ready"));
+        }
+        // taken before any GPIO is touched, those belong to whoever holds it
+        let device_lock = lock_device(&self.path)?;
+
+        // BL_ON is active low for BL, keep as input
+        let bootloader_en = Pin::new(self.bootloader_en.into());
+
+        // TODO: remove this workaround
+        // for some reason, setting direction before unexport/export gave
+        // " sh: write error: Input/output error " on Hotspot Rev3
+        bootloader_en.unexport()?;
+        bootloader_en.export()?;
+
+        // reset the CC131x to put it in a known state
+        let reset = Pin::new(self.reset.into());
+
+        let spidev = Cc131x::init(&self.path, &self.spi)?;
+        let mut cc131x =
+            Cc131x::with_transport(Box::new(spidev), Box::new(reset), Box::new(bootloader_en));
+        cc131x.slave_ready = self.slave_ready.map(sysfs_line);
+        cc131x.slave_tx_req = self.slave_tx_req.map(sysfs_line);
+        cc131x.flow_control = self.flow_control;
+        cc131x.pins = self.pins;
+        cc131x.half_duplex = self.spi.half_duplex;
+        cc131x.clock_hz = self.spi.max_speed_hz;
+        cc131x.max_transfer = spidev_bufsiz();
+        cc131x._device_lock = Some(device_lock);
+        Ok(cc131x)
+    }
+}
+
+// this is where the TI linker puts it, but it gets copied over
+const CCFG: usize = 0x1FFA8;
+// the backdoor this crate drives in enter_bootloader: DIO7, active low
+const BL_BACKDOOR_PIN: u8 = 7;
+
+impl Cc131x {
+    // the default policy plus the backdoor enter_bootloader relies on
+    pub fn ccfg_policy() -> CcfgPolicy {
+        CcfgPolicy {
+            expected_backdoor: Some((BL_BACKDOOR_PIN, false)),
+            ..Default::default()
+        }
+    }
+
+    // like ccfg_policy, but expecting the backdoor this instance is configured for
+    pub fn backdoor_policy(&self) -> CcfgPolicy {
+        CcfgPolicy {
+            expected_backdoor: Some((self.pins.backdoor_dio, self.pins.bootloader_en_active_high)),
+            ..Default::default()
+        }
+    }
+
+    // for boards whose images move the backdoor away from DIO7 or make it active high
+    pub fn backdoor_from_image(&mut self, firmware: &FirmwareImage) -> Result<(), Error> {
+        let ccfg =
+            Ccfg::from_image(firmware, self.ccfg_address()).map_err(|_| CcfgError::Missing)?;
+        self.pins.backdoor_from_ccfg(&ccfg);
+        Ok(())
+    }
+
+    // the CCFG is looked for where the profile puts it, see ccfg_address
+    pub fn validate_ccfg(&self, firmware: &FirmwareImage) -> Result<(), CcfgError> {
+        self.validate_ccfg_with(firmware, &Self::ccfg_policy())
+    }
+
+    pub fn validate_ccfg_with(
+        &self,
+        firmware: &FirmwareImage,
+        policy: &CcfgPolicy,
+    ) -> Result<(), CcfgError> {
+        policy.check_image(firmware, self.ccfg_address())
+    }
+
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        reset: u16,
+        bootloader_en: u16,
+        slave_ready: u16,
+        slave_tx_req: u16,
+    ) -> Result<Cc131x, Error> {
+        Cc131x::with_spi_config(
+            path,
+            reset,
+            bootloader_en,
+            slave_ready,
+            slave_tx_req,
+            SpiConfig::default(),
+        )
+    }
+
+    // protocol waits are driven by ACK polling, so nothing needs rescaling for other clocks
+    pub fn with_spi_config<P: AsRef<Path>>(
+        path: P,
+        reset: u16,
+        bootloader_en: u16,
+        slave_ready: u16,
+        slave_tx_req: u16,
+        spi: SpiConfig,
+    ) -> Result<Cc131x, Error> {
+        Cc131xBuilder::new(path, reset, bootloader_en)
+            .slave_ready(slave_ready)
+            .slave_tx_req(slave_tx_req)
+            .spi_config(spi)
+            .build()
+    }
+
+    // for buses other than spidev, e.g. a USB to SPI bridge, with defaults for everything else
+    // nothing is exported or configured, and transfers are not split
+    pub fn with_transport(
+        io: Box<dyn Transport>,
+        reset: Box<dyn Line>,
+        bootloader_en: Box<dyn Line>,
+    ) -> Cc131x {
+        Cc131x {
+            io,
+            reset,
+            bootloader_en,
+            slave_ready: None,
+            slave_tx_req: None,
+            version_address: None,
+            oad_header_address: None,
+            allow_downgrade: false,
+            crc_coalesce_gap: None,
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            flow_control: false,
+            pipeline: false,
+            verify: VerifyMode::default(),
+            strict_segments: false,
+            flash_stub: None,
+            pins: PinConfig::default(),
+            entry: EntryPolicy::default(),
+            half_duplex: false,
+            clock_hz: SpiConfig::default().max_speed_hz,
+            max_transfer: usize::MAX,
+            profile: None,
+            progress: None,
+            audit: None,
+            delay: Arc::new(StdDelay),
+            cancel: None,
+            unsafe_override: None,
+            ccfg_overrides: None,
+            #[cfg(any(test, feature = "fault-injection"))]
+            fault_injector: None,
+            retries: AtomicUsize::new(0),
+            counters: Counters::default(),
+            session_lock: Mutex::new(()),
+            session_owner: Mutex::new(None),
+            sessions: AtomicUsize::new(0),
+            subscribers: Mutex::new(Vec::new()),
+            reset_exported: AtomicBool::new(false),
+            _device_lock: None,
+        }
+    }
+
+    pub fn from_config(config: &Cc131xConfig) -> Result<Cc131x, Error> {
+        let mut builder = Cc131xBuilder::new(
+            &config.spidev,
+            config.reset_pin.resolve()?,
+            config.bootloader_en_pin.resolve()?,
+        )
+        .flow_control(config.flow_control)
+        .spi_config(config.spi)
+        .pin_config(config.pins.clone());
+        if let Some(ref pin) = config.slave_ready_pin {
+            builder = builder.slave_ready(pin.resolve()?);
+        }
+        if let Some(ref pin) = config.slave_tx_req_pin {
+            builder = builder.slave_tx_req(pin.resolve()?);
+        }
+        let mut cc131x = builder.build()?;
+        cc131x.retry = config.retry.clone();
+        cc131x.timeouts = config.timeouts.clone();
+        cc131x.version_address = config.version_address;
+        cc131x.oad_header_address = config.oad_header_address;
+        cc131x.allow_downgrade = config.allow_downgrade;
+        cc131x.ccfg_overrides = config.ccfg_overrides.clone();
+        cc131x.crc_coalesce_gap = config.crc_coalesce_gap;
+        cc131x.profile = config.profile.clone();
+        cc131x.pipeline = config.pipeline;
+        cc131x.verify = config.verify;
+        cc131x.strict_segments = config.strict_segments;
+        cc131x.entry = config.entry.clone();
+        Ok(cc131x)
+    }
+
+    // every event from now on, until the receiver is dropped
+    pub fn subscribe(&self) -> Receiver<BootloaderEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.lock_subscribers().push(sender);
+        receiver
+    }
+
+    pub(crate) fn emit(&self, event: BootloaderEvent) {
+        if let Some(ref audit) = self.audit {
+            audit
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .record(&event);
+        }
+        self.lock_subscribers()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    // taken by every session and every public Bootloader call, see BusLock
+    pub(crate) fn bus(&self) -> BusLock<'_> {
+        BusLock::acquire(self)
+    }
+
+    pub(crate) fn lock_owner(&self) -> MutexGuard<'_, Option<ThreadId>> {
+        self.session_owner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_subscribers(&self) -> MutexGuard<'_, Vec<Sender<BootloaderEvent>>> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // reports how a flashing operation ended to subscribers
+    fn notify<T, F: FnOnce() -> Result<T, Error>>(&self, op: F) -> Result<T, Error> {
+        let result = op();
+        match result {
+            Ok(_) => self.emit(BootloaderEvent::Completed),
+            Err(ref e) => self.emit(BootloaderEvent::Error(e.to_string())),
+        }
+        result
+    }
+
+    // commands re-sent so far because of a NACK or corrupted response
+    pub fn retry_count(&self) -> usize {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    // counted since the handle was built or reset_statistics was last called
+    pub fn statistics(&self) -> Statistics {
+        self.counters.snapshot(self.retry_count())
+    }
+
+    pub fn reset_statistics(&self) {
+        self.counters.reset(self.retry_count());
+    }
+
+    // counts the protocol errors among the results it passes through
+    pub(crate) fn tally<T>(&self, result: Result<T, BlPkError>) -> Result<T, BlPkError> {
+        if let Err(ref e) = result {
+            self.counters.protocol_error(e);
+        }
+        result
+    }
+
+    // counted like tally, for an error found by the caller rather than in a reply it parsed
+    pub(crate) fn protocol_error(&self, err: BlPkError) -> bootloader::Error {
+        self.counters.protocol_error(&err);
+        bootloader::Error::BOOTLOADER(err)
+    }
+
+    // waits for the CC13xx application to request a transfer on slave_tx_req (active low)
+    // returns false if it did not within timeout
+    pub fn wait_tx_request(&self, timeout: Duration) -> Result<bool, Error> {
+        let pin = self
+            .slave_tx_req
+            .as_ref()
+            .ok_or(Error::MissingPin("slave_tx_req"))?;
+        let requested = pin.wait_for_level(0, timeout)?;
+        Ok(requested)
+    }
+
+    fn reset(&self, pulse: Duration) -> Result<(), Error> {
+        self.hold_in_reset()?;
+        self.delay.sleep(pulse);
+        self.release_reset()?;
+        self.delay.sleep(self.pins.reset_settle);
+        Ok(())
+    }
+
+    // parks the radio, it stays silent until release_reset
+    // waits for any bootloader session in progress on another thread to end first
+    pub fn hold_in_reset(&self) -> Result<(), Error> {
+        let _bus = self.bus();
+        debug!("holding in reset");
+        self.reset.set_direction(Direction::Out)?;
+        drive(&*self.reset, true, self.pins.reset_active_high)?;
+        Ok(())
+    }
+
+    // lets the application boot, bootloader_en is left alone
+    pub fn release_reset(&self) -> Result<(), Error> {
+        let _bus = self.bus();
+        debug!("releasing reset");
+        self.reset.set_direction(Direction::Out)?;
+        drive(&*self.reset, false, self.pins.reset_active_high)?;
+        Ok(())
+    }
+
+    // holds reset for delay and boots the application, e.g. after a watchdog event
+    // waits for any bootloader session in progress to end first
+    pub fn cold_restart(&self, delay: Duration) -> Result<(), Error> {
+        let _bus = self.bus();
+        // BL_ON is active low for BL, keep as input so the application boots
+        self.bootloader_en.set_direction(Direction::In)?;
+        self.reset(delay)
+    }
+
+    // a helper for the constructor
+    fn init<P: AsRef<Path>>(path: P, config: &SpiConfig) -> io::Result<Spidev> {
+        let mut spi = Spidev::open(path)?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(config.max_speed_hz)
+            .mode(if config.half_duplex {
+                config.mode | SPI_3WIRE
+            } else {
+                config.mode
+            })
+            .build();
+        spi.configure(&options)?;
+        Ok(spi)
+    }
+
+    // every SPI exchange goes through here
+    // exchanges longer than max_transfer go out as back to back transfers
+    fn transfer(&self, tx_buf: &[u8], rx_buf: &mut [u8]) -> io::Result<()> {
+        let max = self.max_transfer.max(1);
+        if !self.half_duplex {
+            for (tx, rx) in tx_buf.chunks(max).zip(rx_buf.chunks_mut(max)) {
+                self.io.transfer(tx, rx)?;
+            }
+            self.counters.transfer(tx_buf, rx_buf, false);
+        } else {
+            // the shared line is driven by us for the packet, nothing comes back,
+            // then left to the chip for the padding that clocks its reply in
+            let split = padding_start(tx_buf);
+            let (packet, padding) = rx_buf.split_at_mut(split);
+            packet.iter_mut().for_each(|b| *b = 0);
+            for tx in tx_buf[..split].chunks(max) {
+                self.io.write(tx)?;
+            }
+            for rx in padding.chunks_mut(max) {
+                self.io.read(rx)?;
+            }
+            self.counters.transfer(&tx_buf[..split], packet, true);
+            self.counters.transfer(&tx_buf[split..], padding, true);
+        }
+        #[cfg(any(test, feature = "fault-injection"))]
+        {
+            if let Some(ref injector) = self.fault_injector {
+                injector.apply(tx_buf, rx_buf, &*self.delay);
+            }
+        }
+        #[cfg(feature = "trace-packets")]
+        trace!("tx {}\nrx {}", hex(tx_buf), hex(rx_buf));
+        Ok(())
+    }
+
+    pub fn write_wait_read(&self, input_buf: &[u8], wait: u32) -> io::Result<Vec<u8>> {
+        let mut rx_buf = vec![0; input_buf.len()];
+        self.transfer(input_buf, &mut rx_buf)?;
+
+        self.delay.sleep(Duration::new(0, wait));
+
+        let tx_buf = vec![0; 255];
+        let mut rx_buf = vec![0; 255];
+        self.transfer(&tx_buf, &mut rx_buf)?;
+        Ok(rx_buf)
+    }
+
+    pub fn write(&self, input_buf: &[u8]) -> io::Result<Vec<u8>> {
+        let mut rx_buf = vec![0; input_buf.len()];
+        self.transfer(input_buf, &mut rx_buf)?;
+        Ok(rx_buf)
+    }
+
+    pub fn read(&self, rec_buf: &mut [u8]) -> io::Result<()> {
+        let tx_buf = vec![0; rec_buf.len()];
+        self.transfer(&tx_buf, rec_buf)
+    }
+
+    // the chip is reset again when the returned session is dropped without finish()
+    // blocks while another thread holds a session on the same handle
+    // inside a session of its own thread the chip is already in the bootloader, and it stays there
+    pub fn enter_bootloader(&self) -> Result<BootloaderSession<'_>, Error> {
+        let lock = self.bus();
+        if self.sessions.load(Ordering::Relaxed) > 0 {
+            return Ok(BootloaderSession::new(self, lock));
+        }
+
+        let mut pulse = self.pins.reset_pulse;
+        let mut attempt = 1;
+        loop {
+            debug!("entering bootloader, attempt {}", attempt);
+            self.backdoor_sequence(pulse)?;
+            if self.entry.attempts <= 1 {
+                break;
+            }
+            match Bootloader::ping(self) {
+                Ok(()) => break,
+                Err(e) if attempt < self.entry.attempts => {
+                    warn!("bootloader entry attempt {} failed: {}", attempt, e);
+                    if self.entry.extend_reset {
+                        pulse *= 2;
+                    }
+                    if self.entry.reexport_gpios {
+                        self.reexport_gpios()?;
+                    }
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.emit(BootloaderEvent::EnteredBootloader);
+        Ok(BootloaderSession::new(self, lock))
+    }
+
+    fn reexport_gpios(&self) -> Result<(), Error> {
+        for pin in &[&self.reset, &self.bootloader_en] {
+            pin.unexport()?;
+            pin.export()?;
+        }
+        self.reset_exported.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // resets the chip with bootloader_en asserted
+    fn backdoor_sequence(&self, pulse: Duration) -> Result<(), Error> {
+        self.bootloader_en.set_direction(Direction::Out)?;
+        drive(
+            &*self.bootloader_en,
+            true,
+            self.pins.bootloader_en_active_high,
+        )?;
+
+        self.reset(pulse)?;
+
+        let output = [0x00];
+        self.write(&output)?;
+        self.delay.sleep(self.pins.bootloader_hold);
+        drive(
+            &*self.bootloader_en,
+            false,
+            self.pins.bootloader_en_active_high,
+        )?;
+        Ok(())
+    }
+
+    pub fn flash_firmware(&self, firmware: &FirmwareImage) -> Result<FlashReport, Error> {
+        self.flash_firmware_with(firmware, &SignaturePolicy::AllowUnsigned)
+    }
+
+    // bare images are unsigned, so any policy requiring a signature refuses them
+    pub fn flash_firmware_with(
+        &self,
+        firmware: &FirmwareImage,
+        policy: &SignaturePolicy,
+    ) -> Result<FlashReport, Error> {
+        self.notify(|| {
+            policy.check_image(firmware)?;
+            let session = self.enter_bootloader()?;
+            let firmware = &*self.checked(firmware, true)?;
+            let report = match self.flash_stub {
+                Some(ref stub) => Bootloader::flash_firmware_with_stub(self, firmware, stub)?,
+                None => Bootloader::flash_firmware(self, firmware)?,
+            };
+            session.finish();
+            Ok(report)
+        })
+    }
+
+    // e.g. the application plus a hex of calibration constants, in one session with a single reset
+    // the images must not overlap, the CCFG overrides and profile apply to them as a whole
+    pub fn flash_many(
+        &self,
+        images: &[&FirmwareImage],
+        options: CampaignOptions,
+    ) -> Result<FlashReport, Error> {
+        self.notify(|| {
+            let combined = campaign::combine(images)?;
+            let session = self.enter_bootloader()?;
+            let firmware = &*self.checked(&combined, options.erase_chip)?;
+            let report = Bootloader::flash_many(self, &[firmware], options)?;
+            session.finish();
+            Ok(report)
+        })
+    }
+
+    // backs up the sectors about to be rewritten to backup and restores them if the new image fails to verify
+    pub fn flash_firmware_with_backup<P: AsRef<Path>>(
+        &self,
+        firmware: &FirmwareImage,
+        backup: P,
+    ) -> Result<FlashReport, Error> {
+        self.notify(|| {
+            let session = self.enter_bootloader()?;
+            let firmware = &*self.checked(firmware, false)?;
+            let report = Bootloader::flash_firmware_with_backup(self, firmware, backup.as_ref())?;
+            session.finish();
+            Ok(report)
+        })
+    }
+
+    // runs every check flash_firmware_with would, then reports the plan instead of flashing
+    pub fn flash_firmware_dry_run(
+        &self,
+        firmware: &FirmwareImage,
+        policy: &SignaturePolicy,
+    ) -> Result<DryRun, Error> {
+        policy.check_image(firmware)?;
+        let session = self.enter_bootloader()?;
+        let firmware = &*self.checked(firmware, true)?;
+        let dry_run = Bootloader::flash_firmware_dry_run(self, firmware)?;
+        session.finish();
+        Ok(dry_run)
+    }
+
+    pub fn flash_firmware_resumable(
+        &self,
+        firmware: &FirmwareImage,
+        store: &mut dyn StateStore,
+    ) -> Result<(), Error> {
+        self.notify(|| {
+            let session = self.enter_bootloader()?;
+            let firmware = &*self.patched(firmware)?;
+            self.check_interlock(firmware, true)?;
+            self.check_downgrade(firmware)?;
+            Bootloader::flash_firmware_resumable(self, firmware, store)?;
+            session.finish();
+            Ok(())
+        })
+    }
+
+    // starts over with a full flash when store holds no progress
+    pub fn resume_flash(
+        &self,
+        firmware: &FirmwareImage,
+        store: &mut dyn StateStore,
+    ) -> Result<(), Error> {
+        self.notify(|| {
+            let session = self.enter_bootloader()?;
+            let firmware = &*self.patched(firmware)?;
+            // a store without progress means a full flash
+            self.check_interlock(firmware, true)?;
+            self.check_downgrade(firmware)?;
+            Bootloader::resume_flash(self, firmware, store)?;
+            session.finish();
+            Ok(())
+        })
+    }
+
+    // keeps e.g. NV storage pages intact, unlike the bank erase done by flash_firmware
+    pub fn flash_firmware_preserving(
+        &self,
+        firmware: &FirmwareImage,
+        preserve: &[Range<u32>],
+    ) -> Result<(), Error> {
+        self.notify(|| {
+            let session = self.enter_bootloader()?;
+            let firmware = &*self.patched(firmware)?;
+            self.check_interlock(firmware, false)?;
+            self.check_downgrade(firmware)?;
+            Bootloader::flash_firmware_preserving(self, firmware, preserve)?;
+            session.finish();
+            Ok(())
+        })
+    }
+
+    // returns the number of sectors that had to be rewritten
+    pub fn flash_firmware_incremental(&self, firmware: &FirmwareImage) -> Result<usize, Error> {
+        self.notify(|| {
+            let session = self.enter_bootloader()?;
+            let firmware = &*self.patched(firmware)?;
+            self.check_interlock(firmware, false)?;
+            self.check_downgrade(firmware)?;
+            let rewritten = Bootloader::flash_firmware_incremental(self, firmware)?;
+            session.finish();
+            Ok(rewritten)
+        })
+    }
+
+    // after a partial or bad flash, rewrites only the sectors backing segments that fail their CRC
+    pub fn repair(&self, firmware: &FirmwareImage) -> Result<Vec<u32>, Error> {
+        self.notify(|| {
+            let session = self.enter_bootloader()?;
+            let firmware = &*self.patched(firmware)?;
+            self.check_interlock(firmware, false)?;
+            self.check_downgrade(firmware)?;
+            let repaired = Bootloader::repair(self, firmware)?;
+            session.finish();
+            Ok(repaired)
+        })
+    }
+
+    pub fn flash_bundle(&self, bundle: &FirmwareBundle) -> Result<FlashReport, Error> {
+        self.flash_bundle_with(bundle, &SignaturePolicy::AllowUnsigned)
+    }
+
+    // refuses bundles built for another chip family than the one detected
+    pub fn flash_bundle_with(
+        &self,
+        bundle: &FirmwareBundle,
+        policy: &SignaturePolicy,
+    ) -> Result<FlashReport, Error> {
+        self.notify(|| {
+            policy.check(bundle)?;
+            let session = self.enter_bootloader()?;
+            let bundle = &*self.patched_bundle(bundle)?;
+            self.check(&bundle.image, true)?;
+            let report = Bootloader::flash_bundle(self, bundle)?;
+            session.finish();
+            Ok(report)
+        })
+    }
+
+    // like flash_firmware_incremental, refusing bundles built for another chip family
+    pub fn flash_bundle_incremental(
+        &self,
+        bundle: &FirmwareBundle,
+        policy: &SignaturePolicy,
+    ) -> Result<usize, Error> {
+        self.notify(|| {
+            policy.check(bundle)?;
+            let session = self.enter_bootloader()?;
+            let bundle = &*self.patched_bundle(bundle)?;
+            self.check(&bundle.image, false)?;
+            let rewritten = Bootloader::flash_bundle_incremental(self, bundle)?;
+            session.finish();
+            Ok(rewritten)
+        })
+    }
+
+    pub fn dump_flash(&self, range: Range<u32>) -> Result<FirmwareImage, Error> {
+        let session = self.enter_bootloader()?;
+        let firmware = Bootloader::dump_flash(self, range)?;
+        session.finish();
+        Ok(firmware)
+    }
+
+    // streams the range to an Intel HEX file, so the flash contents are never held in memory
+    pub fn dump_to_file<P: AsRef<Path>>(&self, path: P, range: Range<u32>) -> Result<(), Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let session = self.enter_bootloader()?;
+        Bootloader::dump_to_writer(self, range, &mut file)?;
+        session.finish();
+        Ok(())
+    }
+
+    // checks the flashed image without writing anything, whatever self.verify says
+    pub fn verify(&self, firmware: &FirmwareImage, mode: VerifyMode) -> Result<(), Error> {
+        let firmware = &*self.patched(firmware)?;
+        let session = self.enter_bootloader()?;
+        Bootloader::verify(self, firmware, mode)?;
+        session.finish();
+        Ok(())
+    }
+
+    pub fn verify_exact(&self, firmware: &FirmwareImage) -> Result<(), Error> {
+        let firmware = &*self.patched(firmware)?;
+        let session = self.enter_bootloader()?;
+        Bootloader::verify_exact(self, firmware)?;
+        session.finish();
+        Ok(())
+    }
+
+    pub fn capabilities(&self) -> Result<BootloaderCapabilities, Error> {
+        let _session = self.enter_bootloader()?;
+        Ok(Bootloader::capabilities(self)?)
+    }
+
+    // leaves the bootloader through the session's reset once the chip has been identified
+    pub fn probe(&self, timeout: Duration) -> Result<Probe, Error> {
+        let _session = self.enter_bootloader()?;
+        Ok(Bootloader::probe(self, timeout)?)
+    }
+
+    // one-call unbrick for chips with unknown or corrupted firmware, nothing from the image or CCFG is used
+    // the session's reset afterwards boots an erased chip straight back into the bootloader
+    pub fn recover(&self, timeout: Duration) -> Result<Recovery, Error> {
+        self.notify(|| {
+            let _session = self.enter_bootloader()?;
+            Ok(Bootloader::recover(self, timeout)?)
+        })
+    }
+
+    pub fn read_protection(&self) -> Result<SectorProtection, Error> {
+        let _session = self.enter_bootloader()?;
+        Ok(Bootloader::read_protection(self)?)
+    }
+
+    // only a chip erase lifts the protection again
+    pub fn protect_sectors(&self, addresses: &[u32]) -> Result<(), Error> {
+        let _session = self.enter_bootloader()?;
+        Ok(Bootloader::protect_sectors(self, addresses)?)
+    }
+
+    // the chip is identified first, so a wrong wiring fails before anything is erased
+    pub fn erase_chip(&self) -> Result<(), Error> {
+        self.notify(|| {
+            let session = self.enter_bootloader()?;
+            Bootloader::initialize(self)?;
+            Bootloader::erase_chip(self)?;
+            Bootloader::system_reset(self)?;
+            session.finish();
+            Ok(())
+        })
+    }
+
+    // erases the sector holding address, whatever the detected sector size
+    pub fn erase_sector(&self, address: u32) -> Result<(), Error> {
+        self.notify(|| {
+            let session = self.enter_bootloader()?;
+            let info = Bootloader::initialize(self)?;
+            let sector_size = info.sector_size as u32;
+            Bootloader::erase_sector(self, address - address % sector_size)?;
+            Bootloader::system_reset(self)?;
+            session.finish();
+            Ok(())
+        })
+    }
+
+    // what the CRC32 command computes over [address, address + len), with the chip's own CRC parameters
+    pub fn crc32(&self, address: u32, len: u32) -> Result<u32, Error> {
+        let session = self.enter_bootloader()?;
+        Bootloader::initialize(self)?;
+        let crc = Bootloader::get_crc(self, address, len)?;
+        Bootloader::system_reset(self)?;
+        session.finish();
+        Ok(crc)
+    }
+
+    // boots the application through the bootloader's own reset, cold_restart pulses the reset line instead
+    pub fn reset_target(&self) -> Result<(), Error> {
+        let session = self.enter_bootloader()?;
+        Bootloader::system_reset(self)?;
+        session.finish();
+        Ok(())
+    }
+
+    // downloads blob into SRAM and runs it, flash is left alone; the chip stays with the blob until reset
+    pub fn load_and_run(&self, address: u32, blob: &[u8]) -> Result<(), Error> {
+        let session = self.enter_bootloader()?;
+        Bootloader::load_and_run(self, address, blob)?;
+        session.finish();
+        Ok(())
+    }
+
+    // sector is erased, written and left erased, pick one the application does not use
+    pub fn calibrate(&self, sector: u32, scratch: Scratch) -> Result<Calibration, Error> {
+        let session = self.enter_bootloader()?;
+        let calibration = Bootloader::calibrate(self, sector, scratch)?;
+        session.finish();
+        Ok(calibration)
+    }
+
+    pub fn bench(&self, start: u32, len: usize, scratch: Scratch) -> Result<BenchReport, Error> {
+        let session = self.enter_bootloader()?;
+        let report = Bootloader::bench(self, start, len, scratch)?;
+        session.finish();
+        Ok(report)
+    }
+
+    // from the profile, or what every family computes when there is none
+    pub(crate) fn crc_params(&self) -> CrcParams {
+        self.profile
+            .as_ref()
+            .map_or_else(CrcParams::default, |p| p.crc)
+    }
+
+    fn ccfg_address(&self) -> usize {
+        self.profile.as_ref().map_or(CCFG, |p| p.ccfg_address())
+    }
+
+    // the image as it will be written, with ccfg_overrides applied
+    fn patched<'a>(&self, firmware: &'a FirmwareImage) -> Result<Cow<'a, FirmwareImage>, Error> {
+        match self.ccfg_overrides {
+            Some(ref overrides) => {
+                let mut patched = firmware.clone();
+                overrides.apply(&mut patched, self.ccfg_address())?;
+                Ok(Cow::Owned(patched))
+            }
+            None => Ok(Cow::Borrowed(firmware)),
+        }
+    }
+
+    // the image as it will be written, once it passed every check flash_firmware_with runs
+    // called inside a session, bank_erase as for check_interlock
+    pub(crate) fn checked<'a>(
+        &self,
+        firmware: &'a FirmwareImage,
+        bank_erase: bool,
+    ) -> Result<Cow<'a, FirmwareImage>, Error> {
+        let firmware = self.patched(firmware)?;
+        self.check(&firmware, bank_erase)?;
+        Ok(firmware)
+    }
+
+    // checked for an image that is already patched
+    fn check(&self, firmware: &FirmwareImage, bank_erase: bool) -> Result<(), Error> {
+        if let Some(ref profile) = self.profile {
+            firmware.validate(profile, self.strict_segments)?;
+        }
+        self.check_interlock(firmware, bank_erase)?;
+        self.check_downgrade(firmware)
+    }
+
+    // the bundle with its image patched, sha256 and signature stay those checked against the original
+    fn patched_bundle<'a>(
+        &self,
+        bundle: &'a FirmwareBundle,
+    ) -> Result<Cow<'a, FirmwareBundle>, Error> {
+        match self.patched(&bundle.image)? {
+            Cow::Borrowed(_) => Ok(Cow::Borrowed(bundle)),
+            Cow::Owned(image) => Ok(Cow::Owned(FirmwareBundle {
+                version: bundle.version,
+                target: bundle.target,
+                build_id: bundle.build_id.clone(),
+                sha256: bundle.sha256,
+                signature: bundle.signature.clone(),
+                image,
+            })),
+        }
+    }
+
+    // the layout of the connected chip, read inside a session of the caller's
+    fn detected_layout(&self) -> Result<FlashLayout, Error> {
+        let _session = self.enter_bootloader()?;
+        Ok(FlashLayout::from(&Bootloader::initialize(self)?))
+    }
+
+    // refuses a CCFG sector the ROM bootloader could not be entered through again
+    // checked whenever the CCFG sector gets erased: by every bank erase, or when the image has data in it
+    // an image without a CCFG is refused as well rather than leaving the CCFG to whatever the erase left
+    fn check_interlock(&self, firmware: &FirmwareImage, bank_erase: bool) -> Result<(), Error> {
+        let layout = self.detected_layout()?;
+        if !bank_erase
+            && !firmware
+                .touched_sectors(layout.sector_size)
+                .contains(&layout.ccfg_page)
+        {
+            return Ok(());
+        }
+        let result = match Ccfg::from_image(firmware, layout.ccfg_address) {
+            Ok(ccfg) => ccfg::interlock(&ccfg),
+            Err(_) => Err(CcfgError::Missing),
+        };
+        match result {
+            Err(e) if self.unsafe_override.is_some() => {
+                warn!("flashing despite {}", e);
+                Ok(())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    // the version word if the image has one, else the OAD header's software version
+    fn image_version(&self, firmware: &FirmwareImage) -> Option<(VersionAt, u32)> {
+        if let Some(address) = self.version_address {
+            if let Some(version) = firmware.word_at(address as usize) {
+                return Some((VersionAt::Word(address), version));
+            }
+        }
+        let address = self.oad_header_address?;
+        let header = OadHeader::from_image(firmware, address as usize).ok()?;
+        Some((VersionAt::OadHeader(address), header.version()))
+    }
+
+    // a blank chip or one without a parsable header has no version, and anything may be flashed onto it
+    fn check_downgrade(&self, firmware: &FirmwareImage) -> Result<(), Error> {
+        if self.allow_downgrade {
+            return Ok(());
+        }
+        let (at, requested) = match self.image_version(firmware) {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+
+        let session = self.enter_bootloader()?;
+        // without MemoryRead the check cannot run, and only allow_downgrade lets the image through then
+        if !Bootloader::capabilities(self)?.memory_read {
+            return Err(Error::DowngradeUnchecked { requested });
+        }
+        Bootloader::initialize(self)?;
+        let current = match at {
+            VersionAt::Word(address) => {
+                Some(Bootloader::read_word(self, address)?).filter(|word| *word != 0xFFFF_FFFF)
+            }
+            VersionAt::OadHeader(address) => {
+                let raw = Bootloader::read_memory(self, address, OAD_HEADER_LEN)?;
+                OadHeader::from_bytes(address as usize, &raw)
+                    .ok()
+                    .map(|header| header.version())
+            }
+        };
+        Bootloader::system_reset(self)?;
+        session.finish();
+
+        match current {
+            Some(current) if current > requested => {
+                Err(Error::DowngradeRefused { current, requested })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn need_to_update_firmware(&self, firmware: &FirmwareImage) -> Result<bool, Error> {
+        // images without the version word fall back to the CRC check
+        if let Some(version_match) = self.version_match(firmware)? {
+            return Ok(!version_match);
+        }
+        Ok(!self.crc_match(firmware)?)
+    }
+
+    // None when there is no version_address or the image has no word there
+    pub(crate) fn version_match(&self, firmware: &FirmwareImage) -> Result<Option<bool>, Error> {
+        let address = match self.version_address {
+            Some(address) => address,
+            None => return Ok(None),
+        };
+        let version = match firmware.word_at(address as usize) {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+        let session = self.enter_bootloader()?;
+        // the CRC check still works without MemoryRead
+        if !Bootloader::capabilities(self)?.memory_read {
+            return Ok(None);
+        }
+        let version_match = Bootloader::version_match(self, address, version)?;
+        session.finish();
+        Ok(Some(version_match))
+    }
+
+    pub(crate) fn crc_match(&self, firmware: &FirmwareImage) -> Result<bool, Error> {
+        let firmware = &*self.patched(firmware)?;
+        let session = self.enter_bootloader()?;
+        let firmware_match = match self.crc_coalesce_gap {
+            Some(max_gap) => Bootloader::firmware_match_coalesced(self, firmware, max_gap)?,
+            None => Bootloader::firmware_match(self, firmware)?,
+        };
+        session.finish();
+        Ok(firmware_match)
+    }
+}
+
+// hands both lines back to the board and removes the sysfs entries this handle created
+impl Drop for Cc131x {
+    fn drop(&mut self) {
+        // best effort, there is no one left to report errors to
+        let _ = self.bootloader_en.set_direction(Direction::In);
+        let _ = self.reset.set_direction(Direction::In);
+        let _ = self.bootloader_en.unexport();
+        if self.reset_exported.load(Ordering::Relaxed) {
+            let _ = self.reset.unexport();
+        }
+    }
+}
+
+#[test]
+fn test_parse_bufsiz() {
+    assert_eq!(parse_bufsiz("4096\n"), Some(4096));
+    assert_eq!(parse_bufsiz("256"), Some(256));
+    assert_eq!(parse_bufsiz("0"), None);
+    assert_eq!(parse_bufsiz(""), None);
+}
+
+#[test]
+fn test_device_lock() {
+    let path = std::env::temp_dir().join(format!("cc131x-lock-{}", std::process::id()));
+    File::create(&path).unwrap();
+    let held = lock_device(&path).unwrap();
+    // a second open is a different open file description, just like another process
+    match lock_device(&path) {
+        Err(Error::DeviceBusy(ref busy)) if *busy == path => {}
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+    drop(held);
+    assert!(lock_device(&path).is_ok());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_every_flash_path_refuses_a_downgrade() {
+    use bootloader::resume::FileStore;
+    use firmware_image::Segment;
+    use mock::MockChip;
+
+    let chip = MockChip::new();
+    chip.load(0x1000, &[0, 0, 0, 2]);
+    let mut io = MockChip::handle(&chip);
+    io.version_address = Some(0x1000);
+    let older = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x1000, vec![0, 0, 0, 1]),
+            MockChip::ccfg(),
+        ],
+    };
+    let path = std::env::temp_dir().join(format!("cc131x-downgrade-{}", std::process::id()));
+    let mut store = FileStore::new(&path);
+
+    let refused = |result: Result<(), Error>| match result {
+        Err(Error::DowngradeRefused {
+            current: 0x0200_0000,
+            requested: 0x0100_0000,
+        }) => {}
+        other => panic!("unexpected result {:?}", other),
+    };
+    refused(io.flash_firmware_resumable(&older, &mut store));
+    refused(io.resume_flash(&older, &mut store));
+    refused(io.flash_firmware_preserving(&older, &[]));
+    refused(io.repair(&older).map(|_| ()));
+    assert_eq!(&chip.flash()[0x1000..0x1004], &[0, 0, 0, 2]);
+}
+
+#[test]
+fn test_downgrade_check_needs_memory_read() {
+    use firmware_image::Segment;
+    use mock::MockChip;
+
+    let chip = MockChip::new();
+    chip.without_memory_read();
+    let mut io = MockChip::handle(&chip);
+    io.version_address = Some(0x1000);
+    let firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x1000, vec![0, 0, 0, 1]),
+            MockChip::ccfg(),
+        ],
+    };
+    match io.check_downgrade(&firmware) {
+        Err(Error::DowngradeUnchecked {
+            requested: 0x0100_0000,
+        }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    io.allow_downgrade = true;
+    assert!(io.check_downgrade(&firmware).is_ok());
+}
+
+#[test]
+fn test_interlock_fails_closed() {
+    use firmware_image::Segment;
+    use mock::{MockChip, FLASH_SIZE};
+
+    let chip = MockChip::new();
+    let mut io = MockChip::handle(&chip);
+    let app = Segment::with_data(0x1000, vec![0xA5; 16]);
+    let bare = FirmwareImage {
+        segments: vec![app.clone()],
+    };
+    match io.flash_firmware(&bare) {
+        Err(Error::CCFG(CcfgError::Missing)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    // the CCFG sector is left alone, so there is nothing to check
+    io.flash_firmware_preserving(&bare, &[]).unwrap();
+
+    let mut locked = MockChip::ccfg();
+    locked.data[0x33] = 0;
+    locked.recompute_crc();
+    let locked = FirmwareImage {
+        segments: vec![app, locked],
+    };
+    match io.flash_firmware_preserving(&locked, &[]) {
+        Err(Error::CCFG(CcfgError::BootloaderDisabled)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(chip.flash()[FLASH_SIZE - 4096..].iter().all(|b| *b == 0xFF));
+
+    io.unsafe_override = Some(UnsafeOverride::i_accept_the_risk_of_bricking());
+    io.flash_firmware(&locked).unwrap();
+}
+
+#[test]
+fn test_bundle_gets_ccfg_overrides() {
+    use bundle::Version;
+    use device::{ChipFamily, CCFG_SIZE};
+    use firmware_image::Segment;
+    use mock::{MockChip, FLASH_SIZE};
+
+    let chip = MockChip::new();
+    let mut io = MockChip::handle(&chip);
+    io.ccfg_overrides = Some(CcfgOverrides {
+        backdoor_pin: Some(13),
+        ..CcfgOverrides::default()
+    });
+    let image = FirmwareImage {
+        segments: vec![Segment::with_data(0x1000, vec![0xA5; 16]), MockChip::ccfg()],
+    };
+    let bundle = FirmwareBundle::new(image, Version::new(1, 0, 0), ChipFamily::Cc13x0, "test");
+    let ccfg_in_flash = || Ccfg::from_bytes(&chip.flash()[FLASH_SIZE - CCFG_SIZE..]).unwrap();
+
+    io.flash_bundle(&bundle).unwrap();
+    assert_eq!(ccfg_in_flash().backdoor_pin, 13);
+
+    io.ccfg_overrides = Some(CcfgOverrides {
+        backdoor_pin: Some(14),
+        ..CcfgOverrides::default()
+    });
+    assert_eq!(
+        io.flash_bundle_incremental(&bundle, &SignaturePolicy::AllowUnsigned)
+            .unwrap(),
+        1
+    );
+    assert_eq!(ccfg_in_flash().backdoor_pin, 14);
+}
+
+#[test]
+fn test_padding_start() {
+    use bootloader::commands::{Command, GetChipId};
+
+    let packet = GetChipId::new().serialize().unwrap();
+    let mut padded = packet.clone();
+    padded.resize(packet.len() + 16, 0);
+    assert_eq!(padding_start(&padded), packet.len());
+    assert_eq!(padding_start(&packet), packet.len());
+    assert_eq!(padding_start(&[0; 8]), 0);
+    // the host's ACK, or data that only looks like a packet
+    assert_eq!(padding_start(&[0xCC, 0]), 2);
+    assert_eq!(padding_start(&[3, 0x21, 0x20, 0, 0]), 5);
+}
+
+#[test]
+fn test_half_duplex_reads_the_reply_after_the_packet() {
+    use mock::{MockChip, Seen, CHIP_ID, FLASH_SIZE};
+
+    let chip = MockChip::new();
+    let mut io = MockChip::handle(&chip);
+    io.half_duplex = true;
+    io.max_transfer = 4;
+    let session = io.enter_bootloader().unwrap();
+    let info = Bootloader::initialize(&io).unwrap();
+    session.finish();
+
+    assert_eq!(info.chip_id, CHIP_ID);
+    assert_eq!(info.flash_size, FLASH_SIZE);
+    assert_eq!(chip.count(Seen::Command(0x28)), 1);
+    let stats = io.statistics();
+    assert!(stats.bytes_tx > 0 && stats.bytes_rx > 0);
+}
@@ -0,0 +1,428 @@
+use byteorder::{ByteOrder, LittleEndian};
+use device::CCFG_SIZE;
+use firmware_image::{Error, FirmwareImage};
+use std::error;
+use std::fmt;
+
+/*
+ *  Decodes the Customer Configuration area (the last 88 bytes of flash) into named fields
+ *  Fields that are not modeled here are carried along untouched when the page is re-encoded
+ */
+
+const ERASE_CONF: usize = 0x34;
+const CCFG_TI_OPTIONS: usize = 0x38;
+const CCFG_TAP_DAP_0: usize = 0x3C;
+const CCFG_TAP_DAP_1: usize = 0x40;
+const IMAGE_VALID_CONF: usize = 0x44;
+const BL_CONFIG: usize = 0x30;
+
+// most enable fields are 8 bits wide and only count as enabled when set to this value
+const ENABLED: u8 = 0xC5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TapEnables {
+    pub cpu_dap: bool,
+    pub prcm: bool,
+    pub test: bool,
+    pub pbist2: bool,
+    pub pbist1: bool,
+    pub wuc: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ccfg {
+    pub bootloader_enabled: bool,
+    pub backdoor_enabled: bool,
+    pub backdoor_pin: u8,
+    pub backdoor_active_high: bool,
+    pub bank_erase_enabled: bool,
+    pub chip_erase_enabled: bool,
+    pub failure_analysis_enabled: bool,
+    pub taps: TapEnables,
+    // 0 marks a valid image on CC13x0/CC26x0
+    pub image_valid_conf: u32,
+    raw: Vec<u8>,
+}
+
+fn enable_byte(word: u32, shift: u32) -> bool {
+    (word >> shift) as u8 == ENABLED
+}
+
+fn set_enable_byte(word: u32, shift: u32, enabled: bool) -> u32 {
+    let current = (word >> shift) as u8;
+    let byte = if enabled {
+        ENABLED
+    } else if current == ENABLED {
+        0
+    } else {
+        current
+    };
+    (word & !(0xFF << shift)) | (u32::from(byte) << shift)
+}
+
+fn set_bit(word: u32, bit: u32, value: bool) -> u32 {
+    if value {
+        word | (1 << bit)
+    } else {
+        word & !(1 << bit)
+    }
+}
+
+impl Ccfg {
+    pub fn from_bytes(raw: &[u8]) -> Option<Ccfg> {
+        if raw.len() != CCFG_SIZE {
+            return None;
+        }
+        let word = |offset: usize| LittleEndian::read_u32(&raw[offset..]);
+        let bl_config = word(BL_CONFIG);
+        let erase_conf = word(ERASE_CONF);
+        let tap_dap_0 = word(CCFG_TAP_DAP_0);
+        let tap_dap_1 = word(CCFG_TAP_DAP_1);
+
+        Some(Ccfg {
+            bootloader_enabled: enable_byte(bl_config, 24),
+            backdoor_enabled: enable_byte(bl_config, 0),
+            backdoor_pin: (bl_config >> 8) as u8,
+            backdoor_active_high: bl_config & (1 << 16) != 0,
+            bank_erase_enabled: erase_conf & 1 != 0,
+            chip_erase_enabled: erase_conf & (1 << 8) != 0,
+            failure_analysis_enabled: enable_byte(word(CCFG_TI_OPTIONS), 0),
+            taps: TapEnables {
+                cpu_dap: enable_byte(tap_dap_0, 16),
+                prcm: enable_byte(tap_dap_0, 8),
+                test: enable_byte(tap_dap_0, 0),
+                pbist2: enable_byte(tap_dap_1, 16),
+                pbist1: enable_byte(tap_dap_1, 8),
+                wuc: enable_byte(tap_dap_1, 0),
+            },
+            image_valid_conf: word(IMAGE_VALID_CONF),
+            raw: raw.to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = self.raw.clone();
+        let mut update = |offset: usize, f: &dyn Fn(u32) -> u32| {
+            let word = f(LittleEndian::read_u32(&raw[offset..]));
+            LittleEndian::write_u32(&mut raw[offset..], word);
+        };
+
+        update(BL_CONFIG, &|w| {
+            let w = set_enable_byte(w, 24, self.bootloader_enabled);
+            let w = set_enable_byte(w, 0, self.backdoor_enabled);
+            let w = (w & !0xFF00) | (u32::from(self.backdoor_pin) << 8);
+            set_bit(w, 16, self.backdoor_active_high)
+        });
+        update(ERASE_CONF, &|w| {
+            let w = set_bit(w, 0, self.bank_erase_enabled);
+            set_bit(w, 8, self.chip_erase_enabled)
+        });
+        update(CCFG_TI_OPTIONS, &|w| {
+            set_enable_byte(w, 0, self.failure_analysis_enabled)
+        });
+        update(CCFG_TAP_DAP_0, &|w| {
+            let w = set_enable_byte(w, 16, self.taps.cpu_dap);
+            let w = set_enable_byte(w, 8, self.taps.prcm);
+            set_enable_byte(w, 0, self.taps.test)
+        });
+        update(CCFG_TAP_DAP_1, &|w| {
+            let w = set_enable_byte(w, 16, self.taps.pbist2);
+            let w = set_enable_byte(w, 8, self.taps.pbist1);
+            set_enable_byte(w, 0, self.taps.wuc)
+        });
+        update(IMAGE_VALID_CONF, &|_| self.image_valid_conf);
+        raw
+    }
+
+    // address is where the CCFG sits in flash, i.e. flash size - 88
+    pub fn from_image(image: &FirmwareImage, address: usize) -> Result<Ccfg, Error> {
+        for segment in &image.segments {
+            if address >= segment.start && address + CCFG_SIZE <= segment.start + segment.data.len()
+            {
+                let offset = address - segment.start;
+                return Ccfg::from_bytes(&segment.data[offset..offset + CCFG_SIZE])
+                    .ok_or(Error::MissingCcfg);
+            }
+        }
+        Err(Error::MissingCcfg)
+    }
+
+    pub fn write_to_image(&self, image: &mut FirmwareImage, address: usize) -> Result<(), Error> {
+        for segment in &mut image.segments {
+            if address >= segment.start && address + CCFG_SIZE <= segment.start + segment.data.len()
+            {
+                let offset = address - segment.start;
+                segment.data[offset..offset + CCFG_SIZE].copy_from_slice(&self.to_bytes());
+                segment.recompute_crc();
+                return Ok(());
+            }
+        }
+        Err(Error::MissingCcfg)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CcfgError {
+    Missing,
+    BootloaderDisabled,
+    BackdoorDisabled,
+    BackdoorMismatch { pin: u8, active_high: bool },
+    BankEraseDisabled,
+    FailureAnalysisDisabled,
+    // the CPU DAP is locked, so not even a debugger gets back in
+    DebugLocked,
+}
+
+impl fmt::Display for CcfgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CcfgError::Missing => write!(f, "image has no CCFG"),
+            CcfgError::BootloaderDisabled => write!(f, "CCFG disables the ROM bootloader"),
+            CcfgError::BackdoorDisabled => write!(f, "CCFG disables the bootloader backdoor"),
+            CcfgError::BackdoorMismatch { pin, active_high } => write!(
+                f,
+                "CCFG backdoor is DIO{} active {}",
+                pin,
+                if active_high { "high" } else { "low" }
+            ),
+            CcfgError::BankEraseDisabled => write!(f, "CCFG disables bank erase"),
+            CcfgError::FailureAnalysisDisabled => write!(f, "CCFG disables failure analysis"),
+            CcfgError::DebugLocked => write!(f, "CCFG locks the CPU debug port"),
+        }
+    }
+}
+
+impl error::Error for CcfgError {}
+
+// each flag makes the corresponding violation fatal
+#[derive(Debug, Clone)]
+pub struct CcfgPolicy {
+    pub require_ccfg: bool,
+    pub require_backdoor: bool,
+    // (pin, active_high) the host drives to enter the bootloader
+    pub expected_backdoor: Option<(u8, bool)>,
+    pub require_bank_erase: bool,
+    pub require_failure_analysis: bool,
+}
+
+impl Default for CcfgPolicy {
+    fn default() -> CcfgPolicy {
+        CcfgPolicy {
+            require_ccfg: true,
+            require_backdoor: true,
+            expected_backdoor: None,
+            require_bank_erase: true,
+            require_failure_analysis: false,
+        }
+    }
+}
+
+impl CcfgPolicy {
+    pub fn check(&self, ccfg: &Ccfg) -> Result<(), CcfgError> {
+        if self.require_backdoor {
+            if !ccfg.bootloader_enabled {
+                return Err(CcfgError::BootloaderDisabled);
+            }
+            if !ccfg.backdoor_enabled {
+                return Err(CcfgError::BackdoorDisabled);
+            }
+        }
+        if let Some((pin, active_high)) = self.expected_backdoor {
+            if ccfg.backdoor_pin != pin || ccfg.backdoor_active_high != active_high {
+                return Err(CcfgError::BackdoorMismatch {
+                    pin: ccfg.backdoor_pin,
+                    active_high: ccfg.backdoor_active_high,
+                });
+            }
+        }
+        if self.require_bank_erase && !ccfg.bank_erase_enabled {
+            return Err(CcfgError::BankEraseDisabled);
+        }
+        if self.require_failure_analysis && !ccfg.failure_analysis_enabled {
+            return Err(CcfgError::FailureAnalysisDisabled);
+        }
+        Ok(())
+    }
+
+    pub fn check_image(&self, image: &FirmwareImage, address: usize) -> Result<(), CcfgError> {
+        match Ccfg::from_image(image, address) {
+            Ok(ccfg) => self.check(&ccfg),
+            Err(_) if self.require_ccfg => Err(CcfgError::Missing),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+// fields left as None keep whatever the image carries
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct CcfgOverrides {
+    pub backdoor_pin: Option<u8>,
+    pub backdoor_active_high: Option<bool>,
+    pub image_valid_conf: Option<u32>,
+}
+
+impl CcfgOverrides {
+    // rewrites the CCFG at address in place, recomputing the CRC of the segment holding it
+    pub fn apply(&self, image: &mut FirmwareImage, address: usize) -> Result<(), Error> {
+        let mut ccfg = Ccfg::from_image(image, address)?;
+        if let Some(pin) = self.backdoor_pin {
+            ccfg.backdoor_pin = pin;
+        }
+        if let Some(active_high) = self.backdoor_active_high {
+            ccfg.backdoor_active_high = active_high;
+        }
+        if let Some(image_valid_conf) = self.image_valid_conf {
+            ccfg.image_valid_conf = image_valid_conf;
+        }
+        ccfg.write_to_image(image, address)
+    }
+}
+
+// required to flash an image the interlock would refuse, constructed only on purpose
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnsafeOverride {
+    _private: (),
+}
+
+impl UnsafeOverride {
+    pub fn i_accept_the_risk_of_bricking() -> UnsafeOverride {
+        UnsafeOverride { _private: () }
+    }
+}
+
+// settings that leave no way back in short of a JTAG session, or none at all
+// unlike CcfgPolicy this is not configurable, only an UnsafeOverride gets past it
+pub fn interlock(ccfg: &Ccfg) -> Result<(), CcfgError> {
+    if !ccfg.bootloader_enabled {
+        return Err(CcfgError::BootloaderDisabled);
+    }
+    if !ccfg.backdoor_enabled {
+        return Err(CcfgError::BackdoorDisabled);
+    }
+    if !ccfg.taps.cpu_dap {
+        return Err(CcfgError::DebugLocked);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn ccfg_with_bl_config(bl_config: [u8; 4]) -> Vec<u8> {
+    let mut raw = vec![0xFF; CCFG_SIZE];
+    raw[BL_CONFIG..BL_CONFIG + 4].copy_from_slice(&bl_config);
+    LittleEndian::write_u32(&mut raw[IMAGE_VALID_CONF..], 0);
+    raw
+}
+
+#[test]
+fn test_decode_bl_config() {
+    let ccfg = Ccfg::from_bytes(&ccfg_with_bl_config([0xC5, 0x07, 0xFE, 0xC5])).unwrap();
+    assert!(ccfg.bootloader_enabled);
+    assert!(ccfg.backdoor_enabled);
+    assert_eq!(ccfg.backdoor_pin, 7);
+    assert!(!ccfg.backdoor_active_high);
+    assert!(ccfg.bank_erase_enabled);
+    assert_eq!(ccfg.image_valid_conf, 0);
+}
+
+#[test]
+fn test_edit_and_write_back() {
+    use firmware_image::Segment;
+
+    let mut raw = ccfg_with_bl_config([0xC5, 0x07, 0xFE, 0xC5]);
+    raw[CCFG_TAP_DAP_0 + 2] = ENABLED;
+    let mut image = FirmwareImage {
+        segments: vec![Segment::with_data(0x1FFA8, raw.clone())],
+    };
+    let mut ccfg = Ccfg::from_image(&image, 0x1FFA8).unwrap();
+    assert_eq!(ccfg.to_bytes(), raw);
+
+    ccfg.backdoor_pin = 13;
+    ccfg.backdoor_active_high = true;
+    ccfg.taps.cpu_dap = false;
+    ccfg.write_to_image(&mut image, 0x1FFA8).unwrap();
+
+    let segment = &image.segments[0];
+    assert_eq!(
+        &segment.data[BL_CONFIG..BL_CONFIG + 4],
+        [0xC5, 0x0D, 0xFF, 0xC5]
+    );
+    assert_eq!(segment.data[CCFG_TAP_DAP_0 + 2], 0x00);
+    assert_eq!(segment.crc, ::crc::crc32::checksum_ieee(&segment.data));
+    let decoded = Ccfg::from_image(&image, 0x1FFA8).unwrap();
+    assert_eq!(decoded.backdoor_pin, 13);
+    assert!(decoded.backdoor_active_high);
+    assert!(!decoded.taps.cpu_dap);
+}
+
+#[test]
+fn test_policy_checks() {
+    let mut ccfg = Ccfg::from_bytes(&ccfg_with_bl_config([0xC5, 0x07, 0xFE, 0xC5])).unwrap();
+    let mut policy = CcfgPolicy {
+        expected_backdoor: Some((7, false)),
+        ..Default::default()
+    };
+    assert_eq!(policy.check(&ccfg), Ok(()));
+
+    ccfg.backdoor_pin = 13;
+    assert_eq!(
+        policy.check(&ccfg),
+        Err(CcfgError::BackdoorMismatch {
+            pin: 13,
+            active_high: false
+        })
+    );
+
+    ccfg.backdoor_pin = 7;
+    ccfg.bank_erase_enabled = false;
+    assert_eq!(policy.check(&ccfg), Err(CcfgError::BankEraseDisabled));
+    policy.require_bank_erase = false;
+    assert_eq!(policy.check(&ccfg), Ok(()));
+
+    ccfg.backdoor_enabled = false;
+    assert_eq!(policy.check(&ccfg), Err(CcfgError::BackdoorDisabled));
+
+    let empty = FirmwareImage {
+        segments: Vec::new(),
+    };
+    assert_eq!(policy.check_image(&empty, 0x1FFA8), Err(CcfgError::Missing));
+}
+
+#[test]
+fn test_interlock() {
+    let mut raw = ccfg_with_bl_config([0xC5, 0x07, 0xFE, 0xC5]);
+    raw[CCFG_TAP_DAP_0 + 2] = ENABLED;
+    let mut ccfg = Ccfg::from_bytes(&raw).unwrap();
+    assert_eq!(interlock(&ccfg), Ok(()));
+
+    ccfg.taps.cpu_dap = false;
+    assert_eq!(interlock(&ccfg), Err(CcfgError::DebugLocked));
+    ccfg.backdoor_enabled = false;
+    assert_eq!(interlock(&ccfg), Err(CcfgError::BackdoorDisabled));
+    ccfg.bootloader_enabled = false;
+    assert_eq!(interlock(&ccfg), Err(CcfgError::BootloaderDisabled));
+}
+
+#[test]
+fn test_overrides() {
+    use firmware_image::Segment;
+
+    let raw = ccfg_with_bl_config([0xC5, 0x07, 0xFE, 0xC5]);
+    let mut image = FirmwareImage {
+        segments: vec![Segment::with_data(0x1FFA8, raw)],
+    };
+    let overrides = CcfgOverrides {
+        backdoor_pin: Some(13),
+        ..Default::default()
+    };
+    overrides.apply(&mut image, 0x1FFA8).unwrap();
+    let ccfg = Ccfg::from_image(&image, 0x1FFA8).unwrap();
+    assert_eq!(ccfg.backdoor_pin, 13);
+    assert!(!ccfg.backdoor_active_high);
+    assert_eq!(ccfg.image_valid_conf, 0);
+    let segment = &image.segments[0];
+    assert_eq!(segment.crc, ::crc::crc32::checksum_ieee(&segment.data));
+
+    let mut empty = FirmwareImage { segments: vec![] };
+    assert!(overrides.apply(&mut empty, 0x1FFA8).is_err());
+}
@@ -0,0 +1,219 @@
+use bootloader::retry::RetryPolicy;
+use bootloader::verify::VerifyMode;
+use bootloader::Timeouts;
+use ccfg::CcfgOverrides;
+use device::ChipProfile;
+use gpio::GpioLine;
+use std::error;
+use std::fmt;
+#[cfg(any(feature = "json", feature = "toml"))]
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use {EntryPolicy, PinConfig, SpiConfig};
+
+/*
+ *  Everything needed to construct a Cc131x, with every pin spelled out by name
+ *  Only the spidev path, reset and bootloader_en are required, the rest falls back to defaults
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cc131xConfig {
+    pub spidev: PathBuf,
+    // pins are global sysfs numbers, { chip, line } or { label }
+    pub reset_pin: GpioLine,
+    pub bootloader_en_pin: GpioLine,
+    #[serde(default)]
+    pub slave_ready_pin: Option<GpioLine>,
+    #[serde(default)]
+    pub slave_tx_req_pin: Option<GpioLine>,
+    #[serde(default)]
+    pub flow_control: bool,
+    #[serde(default)]
+    pub spi: SpiConfig,
+    #[serde(default)]
+    pub pins: PinConfig,
+    #[serde(default)]
+    pub entry: EntryPolicy,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub profile: Option<ChipProfile>,
+    #[serde(default)]
+    pub version_address: Option<u32>,
+    #[serde(default)]
+    pub oad_header_address: Option<u32>,
+    #[serde(default)]
+    pub allow_downgrade: bool,
+    #[serde(default)]
+    pub ccfg_overrides: Option<CcfgOverrides>,
+    #[serde(default)]
+    pub crc_coalesce_gap: Option<usize>,
+    #[serde(default)]
+    pub pipeline: bool,
+    #[serde(default)]
+    pub verify: VerifyMode,
+    #[serde(default)]
+    pub strict_segments: bool,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    // the extension is not one of the formats compiled in
+    UnsupportedFormat(Option<String>),
+    #[cfg(feature = "json")]
+    Json(::serde_json::Error),
+    #[cfg(feature = "toml")]
+    Toml(::toml::de::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IO(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IO(ref err) => write!(f, "reading config failed: {}", err),
+            Error::UnsupportedFormat(Some(ref ext)) => {
+                write!(f, "unsupported config format .{}", ext)
+            }
+            Error::UnsupportedFormat(None) => write!(f, "config file has no extension"),
+            #[cfg(feature = "json")]
+            Error::Json(ref err) => write!(f, "invalid JSON config: {}", err),
+            #[cfg(feature = "toml")]
+            Error::Toml(ref err) => write!(f, "invalid TOML config: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IO(ref err) => Some(err),
+            Error::UnsupportedFormat(_) => None,
+            #[cfg(feature = "json")]
+            Error::Json(ref err) => Some(err),
+            #[cfg(feature = "toml")]
+            Error::Toml(ref err) => Some(err),
+        }
+    }
+}
+
+impl Cc131xConfig {
+    // the format follows the extension: .json needs the json feature, .toml the toml feature
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Cc131xConfig, Error> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase);
+        match extension.as_deref() {
+            #[cfg(feature = "json")]
+            Some("json") => ::serde_json::from_str(&fs::read_to_string(path)?).map_err(Error::Json),
+            #[cfg(feature = "toml")]
+            Some("toml") => ::toml::from_str(&fs::read_to_string(path)?).map_err(Error::Toml),
+            _ => Err(Error::UnsupportedFormat(extension)),
+        }
+    }
+}
+
+// SpiModeFlags has no serde support, so the mode goes through its raw bits
+pub mod spi_mode_bits {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use spidev::SpiModeFlags;
+
+    pub fn serialize<S: Serializer>(mode: &SpiModeFlags, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(mode.bits())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<SpiModeFlags, D::Error> {
+        u32::deserialize(deserializer).map(SpiModeFlags::from_bits_truncate)
+    }
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_config_defaults() {
+    use std::time::Duration;
+
+    let config: Cc131xConfig = ::serde_json::from_str(
+        r#"{
+            "spidev": "/dev/spidev2.1",
+            "reset_pin": 71,
+            "bootloader_en_pin": 72,
+            "spi": { "max_speed_hz": 8000000 },
+            "timeouts": { "erase": { "secs": 2, "nanos": 0 } }
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(config.spidev, PathBuf::from("/dev/spidev2.1"));
+    assert_eq!(config.slave_ready_pin, None);
+    assert_eq!(config.spi.max_speed_hz, 8_000_000);
+    assert_eq!(config.spi.mode, ::spidev::SPI_MODE_3);
+    assert!(!config.spi.half_duplex);
+    assert_eq!(config.timeouts.erase, Duration::from_secs(2));
+    assert_eq!(config.timeouts.crc, Timeouts::default().crc);
+    assert_eq!(config.pins, PinConfig::default());
+    assert_eq!(config.verify, VerifyMode::CrcPerSegment);
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn test_config_from_toml_file() {
+    let path = ::std::env::temp_dir().join(format!("cc131x-config-{}.toml", ::std::process::id()));
+    fs::write(
+        &path,
+        r#"
+spidev = "/dev/spidev2.1"
+reset_pin = 71
+bootloader_en_pin = { chip = "gpiochip1", line = 8 }
+slave_ready_pin = 73
+slave_tx_req_pin = { label = "CC_TX_REQ" }
+
+[spi]
+half_duplex = true
+
+[pins]
+reset_active_high = true
+"#,
+    )
+    .unwrap();
+    let config = Cc131xConfig::from_file(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(config.reset_pin, GpioLine::Number(71));
+    assert_eq!(
+        config.bootloader_en_pin,
+        GpioLine::ChipLine {
+            chip: "gpiochip1".to_string(),
+            line: 8
+        }
+    );
+    assert_eq!(config.slave_ready_pin, Some(GpioLine::Number(73)));
+    assert_eq!(
+        config.slave_tx_req_pin,
+        Some(GpioLine::Label {
+            label: "CC_TX_REQ".to_string()
+        })
+    );
+    assert!(config.spi.half_duplex);
+    assert_eq!(config.spi.max_speed_hz, SpiConfig::default().max_speed_hz);
+    assert!(config.pins.reset_active_high);
+    assert!(!config.pins.bootloader_en_active_high);
+}
+
+#[test]
+fn test_config_unknown_extension() {
+    match Cc131xConfig::from_file("Cargo.lock") {
+        Err(Error::UnsupportedFormat(Some(ref ext))) if ext == "lock" => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+ *  Every pause and deadline the protocol paces itself by goes through Cc131x::delay
+ *  Waits on a GPIO edge are left to the kernel's own timeout, and calibrate always measures real time
+ */
+
+pub trait DelayProvider: Send + Sync {
+    fn sleep(&self, duration: Duration);
+    fn now(&self) -> Instant;
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+// thread::sleep, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdDelay;
+
+impl DelayProvider for StdDelay {
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// spins instead of giving up the CPU, for hosts where the scheduler wakes sleepers too late
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpinDelay;
+
+impl DelayProvider for SpinDelay {
+    fn sleep(&self, duration: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            ::std::hint::spin_loop();
+        }
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// time that only passes when slept through, so waits and timeouts cost nothing in tests
+#[derive(Debug)]
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    // everything slept through so far
+    pub fn slept(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(|p| p.into_inner())
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> VirtualClock {
+        VirtualClock::new()
+    }
+}
+
+impl DelayProvider for VirtualClock {
+    fn sleep(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap_or_else(|p| p.into_inner()) += duration;
+    }
+
+    fn now(&self) -> Instant {
+        self.start + self.slept()
+    }
+}
+
+#[test]
+fn test_virtual_clock() {
+    let clock = VirtualClock::new();
+    let start = clock.now();
+    clock.sleep(Duration::from_secs(3600));
+    clock.sleep(Duration::from_millis(20));
+    assert_eq!(clock.elapsed(start), Duration::from_millis(3_600_020));
+    assert_eq!(clock.slept(), Duration::from_millis(3_600_020));
+}
@@ -0,0 +1,428 @@
+/*
+ *  Describes the silicon sitting behind the bootloader
+ *  Everything here is decoded from values read out of FCFG1, FLASH and PRCM during initialization
+ */
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use crc::crc32;
+
+// registers read during initialization (identical on CC13x0/CC26x0 and CC13x2/CC26x2)
+pub const ICEPICK_DEVICE_ID: u32 = 0x5000_1318;
+pub const FLASH_SIZE: u32 = 0x4003_002C;
+pub const PRCM_RAMHWOPT: u32 = 0x4008_2250;
+pub const MAC_BLE_0: u32 = 0x5000_12E8;
+pub const MAC_15_4_0: u32 = 0x5000_12F0;
+
+pub const SRAM_START: usize = 0x2000_0000;
+// the largest SRAM of any CC13xx/CC26xx part, on the CC13x2/CC26x2
+pub const MAX_SRAM_SIZE: usize = 80 * 1024;
+// factory configuration, read-only to the bootloader
+pub const FCFG1_START: usize = 0x5000_1000;
+pub const FCFG1_SIZE: usize = 0x400;
+
+// CCFG occupies the last 88 bytes of flash, the customer IEEE address (IEEE_MAC_0/1) sits 0x20 bytes into it
+pub const CCFG_SIZE: usize = 88;
+pub const CCFG_IEEE_MAC_OFFSET: usize = 0x20;
+
+// flash sizes the CC13xx/CC26xx parts ship with, for locating the CCFG of an image without a chip
+pub const KNOWN_FLASH_SIZES: [usize; 5] =
+    [32 * 1024, 64 * 1024, 128 * 1024, 352 * 1024, 704 * 1024];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChipFamily {
+    Cc26x0,
+    Cc13x0,
+    Cc26x2,
+    Cc13x2,
+}
+
+impl ChipFamily {
+    pub const ALL: [ChipFamily; 4] = [
+        ChipFamily::Cc26x0,
+        ChipFamily::Cc13x0,
+        ChipFamily::Cc26x2,
+        ChipFamily::Cc13x2,
+    ];
+
+    // WAFER_ID field of ICEPICK_DEVICE_ID
+    pub fn from_wafer_id(wafer_id: u16) -> Option<ChipFamily> {
+        match wafer_id {
+            0xB99A => Some(ChipFamily::Cc26x0),
+            0xB9BE => Some(ChipFamily::Cc13x0),
+            0xBB77 => Some(ChipFamily::Cc26x2),
+            0xBB41 => Some(ChipFamily::Cc13x2),
+            _ => None,
+        }
+    }
+
+    // CC13x2/CC26x2 (Agama) boot whatever IMAGE_VALID_CONF points at, so flashing writes the CCFG last
+    pub fn defers_ccfg(self) -> bool {
+        matches!(self, ChipFamily::Cc26x2 | ChipFamily::Cc13x2)
+    }
+
+    pub fn sector_size(self) -> usize {
+        match self {
+            ChipFamily::Cc26x0 | ChipFamily::Cc13x0 => 4096,
+            ChipFamily::Cc26x2 | ChipFamily::Cc13x2 => 8192,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub chip_id: u32,
+    pub family: ChipFamily,
+    pub flash_size: usize,
+    pub ram_size: usize,
+    pub sector_size: usize,
+    // MSB first, as it would be printed
+    pub ieee_mac: [u8; 8],
+}
+
+// MAC addresses are stored with the LSB at the lowest address
+pub fn mac_from_memory(mem: &[u8], mac: &mut [u8]) {
+    for (dst, src) in mac.iter_mut().zip(mem.iter().rev()) {
+        *dst = *src;
+    }
+}
+
+impl DeviceInfo {
+    pub fn from_registers(
+        chip_id: u32,
+        device_id: u32,
+        flash_size: u32,
+        ramhwopt: u32,
+        ieee_mac: [u8; 8],
+    ) -> Option<DeviceInfo> {
+        let wafer_id = ((device_id >> 12) & 0xFFFF) as u16;
+        let family = ChipFamily::from_wafer_id(wafer_id)?;
+        Some(DeviceInfo::for_family(
+            family, chip_id, flash_size, ramhwopt, ieee_mac,
+        ))
+    }
+
+    // the registers decoded as family lays them out, whatever ICEPICK_DEVICE_ID says
+    pub fn for_family(
+        family: ChipFamily,
+        chip_id: u32,
+        flash_size: u32,
+        ramhwopt: u32,
+        ieee_mac: [u8; 8],
+    ) -> DeviceInfo {
+        let sector_size = family.sector_size();
+
+        let ram_size = match family {
+            ChipFamily::Cc26x2 | ChipFamily::Cc13x2 => 80 * 1024,
+            _ => match ramhwopt & 0x3 {
+                3 => 20 * 1024,
+                2 => 16 * 1024,
+                _ => 8 * 1024,
+            },
+        };
+
+        DeviceInfo {
+            chip_id,
+            family,
+            flash_size: (flash_size & 0xFF) as usize * sector_size,
+            ram_size,
+            sector_size,
+            ieee_mac,
+        }
+    }
+
+    pub fn ccfg_address(&self) -> usize {
+        self.flash_size - CCFG_SIZE
+    }
+}
+
+// start addresses of the sectors touched by [start, start + len)
+pub fn sectors_covering(start: u32, len: u32, sector_size: usize) -> Vec<u32> {
+    if len == 0 {
+        return Vec::new();
+    }
+    let sector_size = sector_size as u32;
+    let first = start / sector_size;
+    let last = (start + len - 1) / sector_size;
+    (first..=last).map(|sector| sector * sector_size).collect()
+}
+
+// what an image is checked against before it is flashed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChipProfile {
+    pub family: ChipFamily,
+    pub flash_size: usize,
+    pub sram_size: usize,
+    pub sector_size: usize,
+    // segments must start on a sector boundary, e.g. when erasing sector by sector
+    pub require_sector_alignment: bool,
+    // what the ROM CRC32 command computes, verification compares against it
+    #[serde(default)]
+    pub crc: CrcParams,
+}
+
+// a reflected CRC32 as computed by the ROM CRC32 command, gaps are read back as erased flash
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct CrcParams {
+    // reflected, i.e. 0xEDB88320 for IEEE
+    pub polynomial: u32,
+    pub init: u32,
+    pub xor_out: u32,
+    // passed to the CRC32 command, which runs over the range read_repeat + 1 times back to back
+    pub read_repeat: u32,
+}
+
+impl Default for CrcParams {
+    fn default() -> CrcParams {
+        CrcParams {
+            polynomial: crc32::IEEE,
+            init: 0xFFFF_FFFF,
+            xor_out: 0xFFFF_FFFF,
+            read_repeat: 0,
+        }
+    }
+}
+
+impl CrcParams {
+    // Segment::crc is always IEEE over the data once, anything else has to be computed over the data again
+    pub fn is_ieee(&self) -> bool {
+        *self == CrcParams::default()
+    }
+
+    // what the CRC32 command returns over a range holding data
+    pub fn checksum(&self, data: &[u8]) -> u32 {
+        if self.is_ieee() {
+            return crc32::checksum_ieee(data);
+        }
+        // crc32::update inverts on the way in and out, so repeated updates carry on where the last left off
+        let table = crc32::make_table(self.polynomial);
+        let crc =
+            (0..=self.read_repeat).fold(!self.init, |crc, _| crc32::update(crc, &table, data));
+        !crc ^ self.xor_out
+    }
+}
+
+impl ChipProfile {
+    pub fn ccfg_address(&self) -> usize {
+        self.flash_size - CCFG_SIZE
+    }
+}
+
+impl From<&DeviceInfo> for ChipProfile {
+    fn from(info: &DeviceInfo) -> ChipProfile {
+        ChipProfile {
+            family: info.family,
+            flash_size: info.flash_size,
+            sram_size: info.ram_size,
+            sector_size: info.sector_size,
+            require_sector_alignment: false,
+            // the CRC32 command of every family so far is plain IEEE CRC32
+            crc: CrcParams::default(),
+        }
+    }
+}
+
+// where flash sectors, the CCFG, FCFG1 and SRAM sit on one chip
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlashLayout {
+    pub family: ChipFamily,
+    pub sector_size: usize,
+    pub sector_count: usize,
+    // start of the last sector, the one holding the CCFG
+    pub ccfg_page: usize,
+    pub ccfg_address: usize,
+    pub fcfg: Range<usize>,
+    pub sram: Range<usize>,
+}
+
+// what an address falls in, e.g. for error messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Sector(usize),
+    // the index of the last sector
+    CcfgPage(usize),
+    Fcfg,
+    Sram,
+    Unmapped,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Region::Sector(index) => write!(f, "sector {}", index),
+            Region::CcfgPage(index) => write!(f, "sector {} / CCFG page", index),
+            Region::Fcfg => write!(f, "FCFG1"),
+            Region::Sram => write!(f, "SRAM"),
+            Region::Unmapped => write!(f, "unmapped"),
+        }
+    }
+}
+
+impl FlashLayout {
+    pub fn new(family: ChipFamily, flash_size: usize, sram_size: usize) -> FlashLayout {
+        let sector_size = family.sector_size();
+        let sector_count = flash_size / sector_size;
+        FlashLayout {
+            family,
+            sector_size,
+            sector_count,
+            ccfg_page: (sector_count.max(1) - 1) * sector_size,
+            ccfg_address: flash_size - CCFG_SIZE,
+            fcfg: FCFG1_START..FCFG1_START + FCFG1_SIZE,
+            sram: SRAM_START..SRAM_START + sram_size,
+        }
+    }
+
+    pub fn flash_size(&self) -> usize {
+        self.sector_count * self.sector_size
+    }
+
+    // start addresses of every sector, in ascending order
+    pub fn sector_addresses(&self) -> Vec<u32> {
+        (0..self.sector_count)
+            .map(|i| (i * self.sector_size) as u32)
+            .collect()
+    }
+
+    pub fn sector_of(&self, address: usize) -> Option<usize> {
+        if address < self.flash_size() {
+            Some(address / self.sector_size)
+        } else {
+            None
+        }
+    }
+
+    pub fn region(&self, address: usize) -> Region {
+        match self.sector_of(address) {
+            Some(index) if index + 1 == self.sector_count => Region::CcfgPage(index),
+            Some(index) => Region::Sector(index),
+            None if self.fcfg.contains(&address) => Region::Fcfg,
+            None if self.sram.contains(&address) => Region::Sram,
+            None => Region::Unmapped,
+        }
+    }
+}
+
+impl ChipFamily {
+    // the largest part of the family, for when no chip has been read yet
+    pub fn layout(self) -> FlashLayout {
+        match self {
+            ChipFamily::Cc26x0 | ChipFamily::Cc13x0 => {
+                FlashLayout::new(self, 128 * 1024, 20 * 1024)
+            }
+            ChipFamily::Cc26x2 | ChipFamily::Cc13x2 => {
+                FlashLayout::new(self, 352 * 1024, 80 * 1024)
+            }
+        }
+    }
+}
+
+impl From<&DeviceInfo> for FlashLayout {
+    fn from(info: &DeviceInfo) -> FlashLayout {
+        FlashLayout::new(info.family, info.flash_size, info.ram_size)
+    }
+}
+
+impl From<&ChipProfile> for FlashLayout {
+    fn from(profile: &ChipProfile) -> FlashLayout {
+        FlashLayout::new(profile.family, profile.flash_size, profile.sram_size)
+    }
+}
+
+#[test]
+fn test_device_info_from_cc1310_registers() {
+    let mac = [0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11];
+    let info = DeviceInfo::from_registers(0x2002_8000, 0x2B9B_E02F, 0x20, 0x3, mac).unwrap();
+    assert_eq!(info.family, ChipFamily::Cc13x0);
+    assert_eq!(info.flash_size, 128 * 1024);
+    assert_eq!(info.ram_size, 20 * 1024);
+    assert_eq!(info.sector_size, 4096);
+    assert_eq!(info.ccfg_address(), 0x1FFA8);
+}
+
+#[test]
+fn test_profile_from_cc1312_registers() {
+    let info = DeviceInfo::from_registers(0, 0x0BB4_102F, 0x2C, 0, [0; 8]).unwrap();
+    assert_eq!(info.family, ChipFamily::Cc13x2);
+    let profile = ChipProfile::from(&info);
+    assert_eq!(profile.flash_size, 352 * 1024);
+    assert_eq!(profile.sector_size, 8192);
+    assert!(profile.crc.is_ieee());
+}
+
+#[test]
+fn test_mac_from_memory() {
+    let mut mac = [0; 8];
+    mac_from_memory(&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88], &mut mac);
+    assert_eq!(mac, [0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]);
+}
+
+#[test]
+fn test_sectors_covering() {
+    assert_eq!(sectors_covering(0x1000, 0x1000, 4096), [0x1000]);
+    assert_eq!(sectors_covering(0x0FFF, 2, 4096), [0x0000, 0x1000]);
+    assert_eq!(sectors_covering(0x3000, 0x2001, 8192), [0x2000, 0x4000]);
+    assert!(sectors_covering(0x1000, 0, 4096).is_empty());
+}
+
+#[test]
+fn test_device_info_unknown_wafer() {
+    assert!(DeviceInfo::from_registers(0, 0x2123_402F, 0x20, 0x3, [0; 8]).is_none());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_flash_layout() {
+    let layout = ChipFamily::Cc13x2.layout();
+    assert_eq!(layout.sector_size, 8192);
+    assert_eq!(layout.sector_count, 44);
+    assert_eq!(layout.ccfg_page, 0x56000);
+    assert_eq!(layout.ccfg_address, 0x57FA8);
+    assert_eq!(layout.region(0x18000), Region::Sector(12));
+    assert_eq!(layout.region(0x57FA8).to_string(), "sector 43 / CCFG page");
+    assert_eq!(layout.region(0x5000_12F0), Region::Fcfg);
+    assert_eq!(layout.region(0x2000_0100), Region::Sram);
+    assert_eq!(layout.region(0x58000), Region::Unmapped);
+    assert_eq!(layout.sector_addresses().len(), 44);
+}
+
+#[test]
+fn test_crc_params() {
+    let data = b"123456789";
+    let ieee = CrcParams::default();
+    assert!(ieee.is_ieee());
+    assert_eq!(ieee.checksum(data), 0xCBF4_3926);
+    // the same parameters through the generic path
+    let table = crc32::make_table(ieee.polynomial);
+    assert_eq!(
+        !crc32::update(!ieee.init, &table, data) ^ ieee.xor_out,
+        0xCBF4_3926
+    );
+
+    // CRC-32C
+    let castagnoli = CrcParams {
+        polynomial: crc32::CASTAGNOLI,
+        ..CrcParams::default()
+    };
+    assert!(!castagnoli.is_ieee());
+    assert_eq!(castagnoli.checksum(data), 0xE306_9283);
+    // JAMCRC leaves out the final inversion
+    let jamcrc = CrcParams {
+        xor_out: 0,
+        ..CrcParams::default()
+    };
+    assert_eq!(jamcrc.checksum(data), !0xCBF4_3926);
+
+    // the range is run through twice
+    let repeated = CrcParams {
+        read_repeat: 1,
+        ..CrcParams::default()
+    };
+    assert!(!repeated.is_ieee());
+    assert_eq!(
+        repeated.checksum(data),
+        crc32::checksum_ieee(b"123456789123456789")
+    );
+}
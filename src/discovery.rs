@@ -0,0 +1,70 @@
+use device::DeviceInfo;
+use std::path::PathBuf;
+use std::time::Duration;
+use {Cc131x, Cc131xBuilder};
+
+/*
+ *  Provisioning tools try every wiring a hardware SKU might use and keep whatever answers,
+ *  instead of hard-coding the spidev bus and GPIOs per SKU
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryCandidate {
+    pub spidev: PathBuf,
+    pub reset_pin: u16,
+    pub bootloader_en_pin: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredDevice {
+    pub candidate: DiscoveryCandidate,
+    pub info: DeviceInfo,
+}
+
+// cold boards get a moment to come up, an empty bus should not hold up the scan for long
+const PROBE_TIMEOUT: Duration = Duration::from_millis(250);
+
+impl Cc131x {
+    // candidates that fail to open, enter the bootloader or answer a ping are left out
+    pub fn discover(candidates: &[DiscoveryCandidate]) -> Vec<DiscoveredDevice> {
+        candidates
+            .iter()
+            .filter_map(|candidate| {
+                let result = Cc131xBuilder::new(
+                    &candidate.spidev,
+                    candidate.reset_pin,
+                    candidate.bootloader_en_pin,
+                )
+                .build()
+                .and_then(|io| io.probe(PROBE_TIMEOUT));
+                match result {
+                    Ok(probe) => {
+                        info!(
+                            "{} answered: chip id {:#010x}",
+                            candidate.spidev.display(),
+                            probe.info.chip_id
+                        );
+                        Some(DiscoveredDevice {
+                            candidate: candidate.clone(),
+                            info: probe.info,
+                        })
+                    }
+                    Err(e) => {
+                        debug!("{} did not answer: {}", candidate.spidev.display(), e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_discover_skips_missing_bus() {
+    let candidates = [DiscoveryCandidate {
+        spidev: PathBuf::from("/dev/does-not-exist"),
+        reset_pin: 60,
+        bootloader_en_pin: 115,
+    }];
+    assert!(Cc131x::discover(&candidates).is_empty());
+}
@@ -0,0 +1,112 @@
+#[cfg(test)]
+use device::ChipFamily;
+use device::ChipProfile;
+use firmware_image::{Error, FirmwareImage};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+ *  Parses firmware at build time so binaries embed a validated, serialized image instead of a
+ *  hand-generated .bincode that drifts from its .hex. In build.rs, with this crate as a build-dependency:
+ *
+ *      embed::embed_hex("firmware/app.hex", None).unwrap();
+ *
+ *  and wherever the image is needed, with the same file name:
+ *
+ *      let firmware = include_firmware!("app.hex").unwrap();
+ */
+
+// writes the serialized image to $OUT_DIR/<file name>.bincode and has cargo rebuild when the hex changes
+pub fn embed_hex<P: AsRef<Path>>(hex: P, profile: Option<&ChipProfile>) -> Result<PathBuf, Error> {
+    let out_dir = env::var_os("OUT_DIR").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "OUT_DIR is only set for build scripts",
+        )
+    })?;
+    println!("cargo:rerun-if-changed={}", hex.as_ref().display());
+    embed_hex_to(hex, Path::new(&out_dir), profile)
+}
+
+pub fn embed_hex_to<P: AsRef<Path>>(
+    hex: P,
+    out_dir: &Path,
+    profile: Option<&ChipProfile>,
+) -> Result<PathBuf, Error> {
+    let hex = hex.as_ref();
+    let firmware = FirmwareImage::from_path(hex)?;
+    if let Some(profile) = profile {
+        firmware.validate(profile, false)?;
+    }
+    let name = hex
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "hex path has no file name"))?;
+    let mut embedded = name.to_os_string();
+    embedded.push(".bincode");
+    let path = out_dir.join(embedded);
+    fs::write(&path, firmware.serialize()?)?;
+    Ok(path)
+}
+
+// the image embed_hex wrote for the hex file of this name, as a Result<FirmwareImage, firmware_image::Error>
+// given a directory too, one embed_hex_to wrote there ahead of time, relative to the invoking file
+#[macro_export]
+macro_rules! include_firmware {
+    ($name:expr) => {
+        $crate::firmware_image::FirmwareImage::deserialize(include_bytes!(concat!(
+            env!("OUT_DIR"),
+            "/",
+            $name,
+            ".bincode"
+        )))
+    };
+    ($dir:expr, $name:expr) => {
+        $crate::firmware_image::FirmwareImage::deserialize(include_bytes!(concat!(
+            $dir, "/", $name, ".bincode"
+        )))
+    };
+}
+
+#[test]
+fn test_embed_hex_to() {
+    let out_dir = env::temp_dir().join(format!("cc131x-embed-{}", ::std::process::id()));
+    fs::create_dir_all(&out_dir).unwrap();
+    let path = embed_hex_to("src/firmware/test_parsing.ihex", &out_dir, None).unwrap();
+    assert_eq!(path, out_dir.join("test_parsing.ihex.bincode"));
+
+    let embedded = FirmwareImage::deserialize(&fs::read(&path).unwrap()).unwrap();
+    let parsed = FirmwareImage::from_path(Path::new("src/firmware/test_parsing.ihex")).unwrap();
+    assert_eq!(embedded.summary(), parsed.summary());
+    fs::remove_dir_all(&out_dir).unwrap();
+
+    // the test_parsing image has no CCFG for a 352 KB part
+    let profile = ChipProfile {
+        family: ChipFamily::Cc13x2,
+        flash_size: 352 * 1024,
+        sram_size: 80 * 1024,
+        sector_size: 8192,
+        require_sector_alignment: false,
+        crc: Default::default(),
+    };
+    match embed_hex_to(
+        "src/firmware/test_parsing.ihex",
+        &env::temp_dir(),
+        Some(&profile),
+    ) {
+        Err(Error::MissingCcfg) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+// the checked in fixture has to stay what embed_hex_to makes of its hex
+#[test]
+fn test_include_firmware_fixture() {
+    let embedded = include_firmware!("firmware", "test_parsing.ihex").unwrap();
+    let parsed = FirmwareImage::from_path(Path::new("src/firmware/test_parsing.ihex")).unwrap();
+    assert_eq!(embedded.summary(), parsed.summary());
+    for (e, p) in embedded.segments.iter().zip(&parsed.segments) {
+        assert_eq!((e.start, &e.data), (p.start, &p.data));
+    }
+}
@@ -0,0 +1,137 @@
+use bootloader::commands::{is_command_packet, ACK_BYTE, NACK_BYTE};
+use delay::DelayProvider;
+#[cfg(test)]
+use delay::VirtualClock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/*
+ *  Test-only faults applied to what Cc131x::transfer clocks in, so retry and re-sync paths can be
+ *  exercised on purpose. Commands are counted from 1 as they go out; a fault armed for command n
+ *  hits the first answer to it, in the same transfer or in one of the polls after it
+ *  Delays are slept on the Cc131x's DelayProvider, so a VirtualClock sees them too
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    // the ACK never shows up, the poll times out
+    DropAck,
+    // the ACK turns into a NACK
+    Nack,
+    // the checksum of the data packet following the ACK is flipped
+    CorruptChecksum,
+    // the answer arrives this much later
+    Delay(Duration),
+}
+
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    armed: Mutex<Vec<(usize, Fault)>>,
+    pending: Mutex<Option<Fault>>,
+    commands: AtomicUsize,
+}
+
+impl FaultInjector {
+    pub fn new() -> FaultInjector {
+        FaultInjector::default()
+    }
+
+    pub fn on_command(self, n: usize, fault: Fault) -> FaultInjector {
+        self.armed.lock().unwrap().push((n, fault));
+        self
+    }
+
+    // commands seen so far
+    pub fn commands(&self) -> usize {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    pub fn apply(&self, tx: &[u8], rx: &mut [u8], delay: &dyn DelayProvider) {
+        let mut pending = self.pending.lock().unwrap();
+        if is_command_packet(tx) {
+            let n = self.commands.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut armed = self.armed.lock().unwrap();
+            *pending = armed
+                .iter()
+                .position(|(at, _)| *at == n)
+                .map(|i| armed.remove(i).1);
+        }
+        let fault = match *pending {
+            Some(fault) => fault,
+            None => return,
+        };
+        let answer = match rx.iter().position(|b| *b == ACK_BYTE || *b == NACK_BYTE) {
+            Some(pos) => pos,
+            None => return,
+        };
+        match fault {
+            Fault::DropAck => rx[answer..].iter_mut().for_each(|b| *b = 0),
+            Fault::Nack => rx[answer] = NACK_BYTE,
+            Fault::CorruptChecksum => {
+                if let Some(checksum) = rx.get_mut(answer + 2) {
+                    *checksum ^= 0xFF;
+                }
+            }
+            Fault::Delay(duration) => delay.sleep(duration),
+        }
+        // a dropped ACK stays dropped for every later poll of the same command
+        if fault != Fault::DropAck {
+            *pending = None;
+        }
+    }
+}
+
+#[test]
+fn test_faults_hit_the_nth_command() {
+    let clock = VirtualClock::new();
+    let ping = [3, 0x20, 0x20];
+    let injector = FaultInjector::new()
+        .on_command(2, Fault::Nack)
+        .on_command(3, Fault::DropAck);
+
+    let mut rx = [0, 0, ACK_BYTE];
+    injector.apply(&ping, &mut rx, &clock);
+    assert_eq!(rx, [0, 0, ACK_BYTE]);
+
+    // the answer only shows up in the poll after the command
+    let mut rx = [0, 0, 0];
+    injector.apply(&ping, &mut rx, &clock);
+    let mut rx = [0, ACK_BYTE, 0];
+    injector.apply(&[0, 0, 0], &mut rx, &clock);
+    assert_eq!(rx, [0, NACK_BYTE, 0]);
+
+    let mut rx = [0, ACK_BYTE, 0];
+    injector.apply(&ping, &mut rx, &clock);
+    assert_eq!(rx, [0, 0, 0]);
+    let mut rx = [ACK_BYTE, 0, 0];
+    injector.apply(&[0, 0, 0], &mut rx, &clock);
+    assert_eq!(rx, [0, 0, 0]);
+    assert_eq!(injector.commands(), 3);
+}
+
+#[test]
+fn test_corrupt_checksum() {
+    let clock = VirtualClock::new();
+    let injector = FaultInjector::new().on_command(1, Fault::CorruptChecksum);
+    // GetStatus answered with ACK, size 3, checksum 0x40, COMMAND_RET_SUCCESS
+    let mut rx = [0, 0, 0, ACK_BYTE, 3, 0x40, 0x40];
+    injector.apply(&[3, 0x23, 0x23, 0, 0, 0, 0], &mut rx, &clock);
+    assert_eq!(rx, [0, 0, 0, ACK_BYTE, 3, 0xBF, 0x40]);
+}
+
+#[test]
+fn test_delay_sleeps_on_the_delay_provider() {
+    let clock = VirtualClock::new();
+    let injector = FaultInjector::new().on_command(1, Fault::Delay(Duration::from_secs(5)));
+    let mut rx = [0, 0, 0];
+    injector.apply(&[3, 0x20, 0x20], &mut rx, &clock);
+    assert_eq!(clock.slept(), Duration::from_secs(0));
+    let mut rx = [0, ACK_BYTE, 0];
+    injector.apply(&[0, 0, 0], &mut rx, &clock);
+    assert_eq!(rx, [0, ACK_BYTE, 0]);
+    assert_eq!(clock.slept(), Duration::from_secs(5));
+    // only the first answer is held back
+    injector.apply(&[0, 0, 0], &mut rx, &clock);
+    assert_eq!(clock.slept(), Duration::from_secs(5));
+}
@@ -1,27 +1,199 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
 use std::fs::File;
+#[cfg(feature = "std")]
 use std::io::Error as ioError;
+#[cfg(any(feature = "gz", feature = "xz"))]
 use std::io::Read;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader};
+#[cfg(feature = "std")]
 use std::path::Path;
 
+#[cfg(feature = "std")]
 use bincode::{deserialize, serialize, ErrorKind};
+#[cfg(feature = "std")]
+use byteorder::WriteBytesExt;
+use byteorder::{ByteOrder, LittleEndian};
 use crc::crc32;
+use device::{self, ChipProfile, FlashLayout, Region};
+#[cfg(feature = "gz")]
+use flate2::read::GzDecoder;
+#[cfg(feature = "std")]
 use ihex::reader::ReaderError;
+#[cfg(feature = "std")]
 use ihex::record::Record;
-use std::iter::Iterator;
+#[cfg(feature = "std")]
+use ihex::writer::WriterError;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "std")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "std")]
+use serde::Serialize;
+#[cfg(feature = "xz")]
+use xz2::read::XzDecoder;
 
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(feature = "std")]
     IO(ioError),
     EndOfFileInMiddleOfFile,
+    #[cfg(feature = "std")]
+    IhexWriter(WriterError),
+    InvalidElf,
+    InvalidSrecRecord,
+    // line numbers start at 1, bare CR separated records each count as a line
+    #[cfg(feature = "std")]
+    BadRecord {
+        line: usize,
+        reason: ReaderError,
+    },
+    UnsupportedRecord {
+        line: usize,
+        record_type: u8,
+    },
+    // the records ran out before the end of file record
+    TruncatedFile,
+    // a non-blank line that does not begin with ':'
+    MissingStartCode {
+        line: usize,
+    },
+    #[cfg(feature = "std")]
+    Bincode(Box<ErrorKind>),
+    BadMagic,
+    UnsupportedFormatVersion(u16),
+    PayloadCrcMismatch,
+    // start addresses of the two segments
+    OverlappingSegments(usize, usize),
+    // start address of the offending segment
+    SegmentOutsideFlash(usize),
+    // the stored CRC does not match the segment data
+    SegmentCrcMismatch(usize),
+    SegmentNotSectorAligned(usize),
+    MissingCcfg,
 }
 
+#[cfg(feature = "std")]
 impl From<ioError> for Error {
     fn from(err: ioError) -> Error {
         Error::IO(err)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[cfg(feature = "std")]
+impl From<Box<ErrorKind>> for Error {
+    fn from(err: Box<ErrorKind>) -> Error {
+        Error::Bincode(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<WriterError> for Error {
+    fn from(err: WriterError) -> Error {
+        Error::IhexWriter(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "std")]
+            Error::IO(ref err) => write!(f, "I/O error: {}", err),
+            Error::EndOfFileInMiddleOfFile => {
+                write!(f, "end of file record before the end of the file")
+            }
+            #[cfg(feature = "std")]
+            Error::IhexWriter(ref err) => write!(f, "writing ihex failed: {}", err),
+            Error::InvalidElf => write!(f, "invalid ELF file"),
+            Error::InvalidSrecRecord => write!(f, "invalid S-record"),
+            #[cfg(feature = "std")]
+            Error::BadRecord { line, ref reason } => {
+                write!(f, "invalid ihex record on line {}: {}", line, reason)
+            }
+            Error::UnsupportedRecord { line, record_type } => write!(
+                f,
+                "unsupported ihex record type {:#04x} on line {}",
+                record_type, line
+            ),
+            Error::TruncatedFile => write!(f, "file ends without an end of file record"),
+            Error::MissingStartCode { line } => {
+                write!(f, "line {} does not start with ':'", line)
+            }
+            #[cfg(feature = "std")]
+            Error::Bincode(ref err) => write!(f, "bincode error: {}", err),
+            Error::BadMagic => write!(f, "bad magic"),
+            Error::UnsupportedFormatVersion(v) => write!(f, "unsupported format version {}", v),
+            Error::PayloadCrcMismatch => write!(f, "payload CRC mismatch"),
+            Error::OverlappingSegments(a, b) => {
+                write!(f, "segments at {:#010x} and {:#010x} overlap", a, b)
+            }
+            Error::SegmentOutsideFlash(a) => write!(f, "segment at {:#010x} is outside flash", a),
+            Error::SegmentCrcMismatch(a) => write!(f, "segment at {:#010x} fails its CRC", a),
+            Error::SegmentNotSectorAligned(a) => {
+                write!(f, "segment at {:#010x} is not sector aligned", a)
+            }
+            Error::MissingCcfg => write!(f, "image has no CCFG"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IO(ref err) => Some(err),
+            Error::IhexWriter(ref err) => Some(err),
+            Error::BadRecord { ref reason, .. } => Some(reason),
+            Error::Bincode(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// serialized images are prefixed with MAGIC, FORMAT_VERSION and the CRC32 of the bincode payload
+#[cfg(feature = "std")]
+const MAGIC: &[u8; 4] = b"CCFW";
+#[cfg(feature = "std")]
+const FORMAT_VERSION: u16 = 1;
+#[cfg(feature = "std")]
+const HEADER_LEN: usize = 10;
+
+#[cfg(feature = "std")]
+pub fn encode_versioned<T: Serialize>(magic: &[u8; 4], value: &T) -> Result<Vec<u8>, Error> {
+    let payload = serialize(value)?;
+    let mut encoded = Vec::with_capacity(HEADER_LEN + payload.len());
+    encoded.extend_from_slice(magic);
+    encoded.write_u16::<LittleEndian>(FORMAT_VERSION)?;
+    encoded.write_u32::<LittleEndian>(crc32::checksum_ieee(&payload))?;
+    encoded.extend_from_slice(&payload);
+    Ok(encoded)
+}
+
+#[cfg(feature = "std")]
+pub fn decode_versioned<T: DeserializeOwned>(magic: &[u8; 4], encoded: &[u8]) -> Result<T, Error> {
+    if encoded.len() < HEADER_LEN || &encoded[..4] != magic {
+        return Err(Error::BadMagic);
+    }
+    let version = LittleEndian::read_u16(&encoded[4..]);
+    if version != FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion(version));
+    }
+    let payload = &encoded[HEADER_LEN..];
+    if crc32::checksum_ieee(payload) != LittleEndian::read_u32(&encoded[6..]) {
+        return Err(Error::PayloadCrcMismatch);
+    }
+    Ok(deserialize(payload)?)
+}
+
+// value of erased flash, used to fill gaps between segments
+pub const DEFAULT_FILL: u8 = 0xFF;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Segment {
     pub start: usize,
     pub data: Vec<u8>,
@@ -29,108 +201,809 @@ pub struct Segment {
 }
 
 impl Segment {
-    fn new(start: usize, init_data: &mut Vec<u8>) -> Segment {
-        let mut data = Vec::new();
-        data.append(init_data);
-
+    // the CRC is left for compute_crcs once the segment is complete
+    fn new(start: usize, data: Vec<u8>) -> Segment {
         Segment {
             start,
             data,
             crc: 0,
         }
     }
+
+    pub fn with_data(start: usize, data: Vec<u8>) -> Segment {
+        let crc = crc32::checksum_ieee(&data);
+        Segment { start, data, crc }
+    }
+
+    pub fn recompute_crc(&mut self) {
+        self.crc = crc32::checksum_ieee(&self.data);
+    }
+
+    // None for a segment wholly in flash, what classify and validate both go by
+    fn outside_flash(&self, layout: &FlashLayout) -> Option<SkippedSegment> {
+        let end = self.start + self.data.len();
+        if end <= layout.flash_size() {
+            return None;
+        }
+        let region = match layout.region(self.start) {
+            Region::Fcfg if end <= layout.fcfg.end => Region::Fcfg,
+            Region::Sram if end <= layout.sram.end => Region::Sram,
+            Region::Fcfg | Region::Sram => Region::Unmapped,
+            region => region,
+        };
+        Some(SkippedSegment {
+            start: self.start,
+            len: self.data.len(),
+            region,
+        })
+    }
 }
-#[derive(Serialize, Deserialize, Debug)]
+// one CRC per segment, spread over the rayon pool when the feature is on
+fn compute_crcs(segments: &mut [Segment]) {
+    #[cfg(feature = "rayon")]
+    segments.par_iter_mut().for_each(Segment::recompute_crc);
+    #[cfg(not(feature = "rayon"))]
+    segments.iter_mut().for_each(Segment::recompute_crc);
+}
+
+// accumulates data records into segments as they are parsed
+#[cfg(feature = "std")]
+struct SegmentBuilder {
+    segments: Vec<Segment>,
+    ext_addr: usize,
+    hit_eof: bool,
+    // 1-based number of the last line handed to line()
+    lines: usize,
+}
+
+#[cfg(feature = "std")]
+impl SegmentBuilder {
+    fn new() -> SegmentBuilder {
+        SegmentBuilder {
+            segments: Vec::new(),
+            ext_addr: 0,
+            hit_eof: false,
+            lines: 0,
+        }
+    }
+
+    // accepts LF, CRLF or bare CR line endings; blank lines are skipped
+    // counting happens here so a bare CR moves every later line number along too
+    fn line(&mut self, text: &str) -> Result<(), Error> {
+        for record in text.split('\r').map(|r| r.trim()) {
+            self.lines += 1;
+            let line = self.lines;
+            if record.is_empty() {
+                continue;
+            }
+            if !record.starts_with(':') {
+                return Err(Error::MissingStartCode { line });
+            }
+            let record = Record::from_record_string(record).map_err(|reason| match reason {
+                ReaderError::UnsupportedRecordType(record_type) => {
+                    Error::UnsupportedRecord { line, record_type }
+                }
+                reason => Error::BadRecord { line, reason },
+            })?;
+            self.record(record)?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, record: Record) -> Result<(), Error> {
+        if self.hit_eof {
+            return Err(Error::EndOfFileInMiddleOfFile);
+        }
+        match record {
+            Record::Data { offset, value } => self.data(offset, value),
+            Record::ExtendedSegmentAddress(val) => self.ext_addr = (val as usize) << 4,
+            Record::ExtendedLinearAddress(val) => self.ext_addr = (val as usize) << 16,
+            Record::EndOfFile => self.hit_eof = true,
+            Record::StartSegmentAddress { .. } | Record::StartLinearAddress(_) => {}
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, offset: u16, mut value: Vec<u8>) {
+        let address = offset as usize | self.ext_addr;
+        if let Some(last) = self.segments.last_mut() {
+            if last.start + last.data.len() == address {
+                last.data.append(&mut value);
+                return;
+            }
+        }
+        self.segments.push(Segment::new(address, value));
+    }
+
+    // segments come out in reverse file order
+    fn finish(mut self) -> Result<FirmwareImage, Error> {
+        if !self.hit_eof {
+            return Err(Error::TruncatedFile);
+        }
+        compute_crcs(&mut self.segments);
+        self.segments.reverse();
+        Ok(FirmwareImage {
+            segments: self.segments,
+        })
+    }
+}
+
+// a segment that does not lie within flash
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedSegment {
+    pub start: usize,
+    pub len: usize,
+    // Sram or Fcfg only when wholly inside it, Region::Sector for one running past the end of flash
+    pub region: Region,
+}
+
+impl SkippedSegment {
+    // RAM-load data or FCFG bytes can be left out, anything else outside flash is an error in the image
+    pub fn can_skip(&self) -> bool {
+        matches!(self.region, Region::Sram | Region::Fcfg)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FirmwareImage {
     pub segments: Vec<Segment>,
 }
 
 impl FirmwareImage {
-    pub fn from_records(mut records: Vec<Record>) -> Result<FirmwareImage, Error> {
+    // records last in file first, ending with the end of file record; a run of them counts as one,
+    // as from older readers that turned the blank last line of a file into a second one
+    // segments come out last in file first, the same as from new()
+    #[cfg(feature = "std")]
+    pub fn from_records(records: Vec<Record>) -> Result<FirmwareImage, Error> {
+        let repeated = records
+            .iter()
+            .take_while(|r| matches!(r, Record::EndOfFile))
+            .count()
+            .saturating_sub(1);
+        let mut builder = SegmentBuilder::new();
+        for record in records.into_iter().skip(repeated).rev() {
+            builder.record(record)?;
+        }
+        builder.finish()
+    }
+
+    // "-" reads stdin; a FIFO opens like a file and is parsed as the writer fills it,
+    // so a download can be piped straight in without ever being stored
+    #[cfg(feature = "std")]
+    pub fn from_path(path: &Path) -> Result<FirmwareImage, Error> {
+        if path == Path::new("-") {
+            return Self::from_stdin();
+        }
+        let file = File::open(path)?;
+        Self::from_reader(BufReader::new(file))
+    }
+
+    // a stream cut short before its end of file record is Error::TruncatedFile
+    #[cfg(feature = "std")]
+    pub fn from_stdin() -> Result<FirmwareImage, Error> {
+        let stdin = io::stdin();
+        let locked = stdin.lock();
+        Self::from_reader(locked)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn new(file: &str) -> Result<FirmwareImage, Error> {
+        let mut builder = SegmentBuilder::new();
+        for line in file.lines() {
+            builder.line(line)?;
+        }
+        builder.finish()
+    }
+
+    // parses one line at a time so only the segment data is ever held in memory
+    #[cfg(feature = "std")]
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<FirmwareImage, Error> {
+        let mut builder = SegmentBuilder::new();
+        for line in reader.lines() {
+            builder.line(&line?)?;
+        }
+        builder.finish()
+    }
+
+    #[cfg(feature = "gz")]
+    // decompresses while parsing, corrupt or truncated streams surface as Error::IO
+    pub fn from_gz_reader<R: Read>(reader: R) -> Result<FirmwareImage, Error> {
+        Self::from_reader(BufReader::new(GzDecoder::new(reader)))
+    }
+
+    #[cfg(feature = "xz")]
+    pub fn from_xz_reader<R: Read>(reader: R) -> Result<FirmwareImage, Error> {
+        Self::from_reader(BufReader::new(XzDecoder::new(reader)))
+    }
+
+    // only 32-bit little-endian ELF files are supported, which is what the ARM toolchains emit
+    pub fn from_elf(elf: &[u8]) -> Result<FirmwareImage, Error> {
+        const PT_LOAD: u32 = 1;
+        const PHDR_SIZE: usize = 32;
+
+        if elf.len() < 0x34 || &elf[..4] != b"\x7FELF" || elf[4] != 1 || elf[5] != 1 {
+            return Err(Error::InvalidElf);
+        }
+        let phoff = LittleEndian::read_u32(&elf[0x1C..]) as usize;
+        let phentsize = LittleEndian::read_u16(&elf[0x2A..]) as usize;
+        let phnum = LittleEndian::read_u16(&elf[0x2C..]) as usize;
+        if phentsize < PHDR_SIZE {
+            return Err(Error::InvalidElf);
+        }
+
         let mut segments = Vec::new();
-        let mut ext_addr: usize = 0;
-        let mut current_segment = Segment {
-            start: 0x00,
-            data: Vec::new(),
-            crc: 0,
-        };
-        let mut hit_eof = false;
-        loop {
-            match records.pop().unwrap() {
-                Record::Data { offset, mut value } => {
-                    if hit_eof {
-                        return Err(Error::EndOfFileInMiddleOfFile);
-                    }
-                    let new_loc = offset as usize | ext_addr;
-                    if current_segment.start + current_segment.data.len() != new_loc {
-                        let crc_calc = crc32::checksum_ieee(&current_segment.data);
-                        current_segment.crc = crc_calc;
-                        segments.push(current_segment);
-                        current_segment = Segment::new(new_loc, &mut value);
-                    } else {
-                        current_segment.data.append(&mut value);
-                    }
+        for i in 0..phnum {
+            let start = phoff + i * phentsize;
+            let phdr = elf.get(start..start + PHDR_SIZE).ok_or(Error::InvalidElf)?;
+            let p_type = LittleEndian::read_u32(&phdr[0..]);
+            let offset = LittleEndian::read_u32(&phdr[4..]) as usize;
+            // physical address is the load address (LMA)
+            let paddr = LittleEndian::read_u32(&phdr[12..]);
+            let filesz = LittleEndian::read_u32(&phdr[16..]);
+            if p_type != PT_LOAD || filesz == 0 {
+                continue;
+            }
+            // the segment has to fit the 32-bit address space
+            paddr.checked_add(filesz).ok_or(Error::InvalidElf)?;
+            let end = offset
+                .checked_add(filesz as usize)
+                .ok_or(Error::InvalidElf)?;
+            let data = elf.get(offset..end).ok_or(Error::InvalidElf)?;
+            segments.push(Segment::with_data(paddr as usize, data.to_vec()));
+        }
+        Ok(FirmwareImage { segments })
+    }
+
+    pub fn from_srec(file: &str) -> Result<FirmwareImage, Error> {
+        let mut segments: Vec<Segment> = Vec::new();
+        for line in file.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            let bytes = parse_srec_line(line)?;
+            let address_len = match &line[..2] {
+                "S1" => 2,
+                "S2" => 3,
+                "S3" => 4,
+                // terminators carry the entry point, which we have no use for
+                "S7" | "S8" | "S9" => break,
+                // header and record counts
+                "S0" | "S5" | "S6" => continue,
+                _ => return Err(Error::InvalidSrecRecord),
+            };
+            if bytes.len() < address_len {
+                return Err(Error::InvalidSrecRecord);
+            }
+            let address = bytes[..address_len]
+                .iter()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            let data = &bytes[address_len..];
+
+            let contiguous = match segments.last() {
+                Some(last) => last.start + last.data.len() == address,
+                None => false,
+            };
+            if contiguous {
+                segments.last_mut().unwrap().data.extend_from_slice(data);
+            } else {
+                segments.push(Segment::new(address, data.to_vec()));
+            }
+        }
+        compute_crcs(&mut segments);
+        Ok(FirmwareImage { segments })
+    }
+
+    pub fn from_bin(data: &[u8], base: usize) -> FirmwareImage {
+        FirmwareImage {
+            segments: vec![Segment::with_data(base, data.to_vec())],
+        }
+    }
+
+    // e.g. splitting at the start of the last sector puts the CCFG page in a segment of its own
+    pub fn split_segments_at(&mut self, address: usize) {
+        let mut split = Vec::new();
+        for segment in &mut self.segments {
+            if address > segment.start && address < segment.start + segment.data.len() {
+                let tail = segment.data.split_off(address - segment.start);
+                segment.crc = crc32::checksum_ieee(&segment.data);
+                split.push(Segment::with_data(address, tail));
+            }
+        }
+        self.segments.append(&mut split);
+    }
+
+    // sorts segments by address and merges contiguous ones, gaps up to max_gap bytes are filled in
+    pub fn normalize(&mut self, max_gap: usize, fill: u8) -> Result<(), Error> {
+        self.segments.retain(|s| !s.data.is_empty());
+        self.segments.sort_by_key(|s| s.start);
+        for pair in self.segments.windows(2) {
+            if pair[0].start + pair[0].data.len() > pair[1].start {
+                return Err(Error::OverlappingSegments(pair[0].start, pair[1].start));
+            }
+        }
+
+        let mut merged: Vec<Segment> = Vec::new();
+        for mut segment in self.segments.drain(..) {
+            if let Some(last) = merged.last_mut() {
+                if segment.start - (last.start + last.data.len()) <= max_gap {
+                    last.data.resize(segment.start - last.start, fill);
+                    last.data.append(&mut segment.data);
+                    continue;
                 }
-                Record::ExtendedSegmentAddress(val) => ext_addr = (val as usize) << 4,
-                Record::ExtendedLinearAddress(val) => ext_addr = (val as usize) << 16,
-                Record::EndOfFile => {
-                    if hit_eof {
-                        segments.push(current_segment);
-                        break;
-                    } else {
-                        hit_eof = true;
+            }
+            merged.push(segment);
+        }
+        compute_crcs(&mut merged);
+        self.segments = merged;
+        Ok(())
+    }
+
+    // segments Bootloader::flash_part would leave out, wholly in SRAM or FCFG1, are accepted
+    // unless strict_segments is set, as with Cc131x::strict_segments
+    pub fn validate(&self, profile: &ChipProfile, strict_segments: bool) -> Result<(), Error> {
+        let layout = FlashLayout::from(profile);
+        let mut has_ccfg = false;
+        for segment in self.segments.iter().filter(|s| !s.data.is_empty()) {
+            if let Some(outside) = segment.outside_flash(&layout) {
+                if strict_segments || !outside.can_skip() {
+                    return Err(Error::SegmentOutsideFlash(segment.start));
+                }
+                continue;
+            }
+            let end = segment.start + segment.data.len();
+            if profile.require_sector_alignment && segment.start % layout.sector_size != 0 {
+                return Err(Error::SegmentNotSectorAligned(segment.start));
+            }
+            if segment.start <= layout.ccfg_address && end == layout.flash_size() {
+                has_ccfg = true;
+            }
+        }
+        if !has_ccfg {
+            return Err(Error::MissingCcfg);
+        }
+        Ok(())
+    }
+
+    // overlapping data is accepted as long as both images agree on its contents
+    pub fn merge(mut self, mut other: FirmwareImage) -> Result<FirmwareImage, Error> {
+        self.segments.append(&mut other.segments);
+        self.segments.retain(|s| !s.data.is_empty());
+        self.segments.sort_by_key(|s| s.start);
+
+        let mut merged: Vec<Segment> = Vec::new();
+        for segment in self.segments {
+            if let Some(last) = merged.last_mut() {
+                let last_end = last.start + last.data.len();
+                if segment.start < last_end {
+                    let overlap = (last_end - segment.start).min(segment.data.len());
+                    let offset = segment.start - last.start;
+                    if last.data[offset..offset + overlap] != segment.data[..overlap] {
+                        return Err(Error::OverlappingSegments(last.start, segment.start));
                     }
+                    last.data.extend_from_slice(&segment.data[overlap..]);
+                    continue;
                 }
-                Record::StartSegmentAddress { .. } => {}
-                _ => panic!("Unhandled iHex record type!"),
             }
+            merged.push(segment);
         }
-        segments.reverse();
-        Ok(FirmwareImage { segments })
+
+        let mut image = FirmwareImage { segments: merged };
+        image.normalize(0, DEFAULT_FILL)?;
+        Ok(image)
     }
 
-    pub fn from_path(path: &Path) -> Result<FirmwareImage, Error> {
-        let mut file = File::open(path).expect("Firmware path invalid");
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        Self::new(&contents)
+    #[cfg(feature = "std")]
+    pub fn to_ihex_string(&self) -> Result<String, Error> {
+        let mut records = Vec::new();
+        let mut upper: Option<u16> = None;
+        for segment in &self.segments {
+            records.append(&mut ihex_records(segment.start, &segment.data, &mut upper));
+        }
+        records.push(Record::EndOfFile);
+
+        let mut output = String::new();
+        for record in &records {
+            output.push_str(&record.to_string()?);
+            output.push_str("\r\n");
+        }
+        Ok(output)
     }
 
-    pub fn new(file: &str) -> Result<FirmwareImage, Error> {
-        let split = file.split("\r\n").map(|line| {
-            let record_result = Record::from_record_string(line);
-            match record_result {
-                Ok(record) => record,
-                Err(e) => {
-                    match e {
-                        // this allows us to handle untreated hex output from compilation
-                        // as last line has \r\n folowed by no start code
-                        // integrity check in from_records verifies multiple EOF only exist at EOF
-                        ReaderError::MissingStartCode => Record::EndOfFile,
-                        _ => {
-                            panic!("RecordReader Error: {:}", e);
+    // data below base is left out
+    pub fn to_bin(&self, base: usize, fill: u8) -> Vec<u8> {
+        let end = self
+            .segments
+            .iter()
+            .map(|s| s.start + s.data.len())
+            .filter(|end| *end > base)
+            .max()
+            .unwrap_or(base);
+
+        let mut bin = vec![fill; end - base];
+        for segment in &self.segments {
+            let segment_end = segment.start + segment.data.len();
+            if segment_end <= base {
+                continue;
+            }
+            let skip = base.saturating_sub(segment.start);
+            let offset = segment.start + skip - base;
+            bin[offset..segment_end - base].copy_from_slice(&segment.data[skip..]);
+        }
+        bin
+    }
+
+    // start addresses of every sector holding image data, in ascending order
+    pub fn touched_sectors(&self, sector_size: usize) -> Vec<usize> {
+        let mut sectors = Vec::new();
+        for segment in self.segments.iter().filter(|s| !s.data.is_empty()) {
+            let first = segment.start / sector_size;
+            let last = (segment.start + segment.data.len() - 1) / sector_size;
+            sectors.extend((first..=last).map(|sector| sector * sector_size));
+        }
+        sectors.sort();
+        sectors.dedup();
+        sectors
+    }
+
+    // every segment cut at the sector boundaries it crosses, so no SendData sequence spans two sectors
+    pub fn split_at_sectors(&self, sector_size: usize) -> FirmwareImage {
+        let mut segments = Vec::new();
+        for segment in &self.segments {
+            let mut offset = 0;
+            while offset < segment.data.len() {
+                let start = segment.start + offset;
+                // up to the end of the sector start is in
+                let len = (sector_size - start % sector_size).min(segment.data.len() - offset);
+                segments.push(Segment::with_data(
+                    start,
+                    segment.data[offset..offset + len].to_vec(),
+                ));
+                offset += len;
+            }
+        }
+        FirmwareImage { segments }
+    }
+
+    // the whole sector starting at sector as it should read after flashing, bytes the image leaves out
+    // are fill rather than whatever the sector held before
+    pub fn sector_image(&self, sector: usize, sector_size: usize, fill: u8) -> Segment {
+        let mut data = self.clip(sector, sector + sector_size).to_bin(sector, fill);
+        data.resize(sector_size, fill);
+        Segment::with_data(sector, data)
+    }
+
+    // segments lying entirely within flash, and where the rest would have landed
+    // e.g. RAM-load data of a debug build, or FCFG bytes a linker script left in
+    pub fn classify(&self, layout: &FlashLayout) -> (FirmwareImage, Vec<SkippedSegment>) {
+        let mut flash = Vec::new();
+        let mut skipped = Vec::new();
+        for segment in self.segments.iter().filter(|s| !s.data.is_empty()) {
+            match segment.outside_flash(layout) {
+                Some(outside) => skipped.push(outside),
+                None => flash.push(segment.clone()),
+            }
+        }
+        (FirmwareImage { segments: flash }, skipped)
+    }
+
+    // the parts of the image falling within [start, end)
+    pub fn clip(&self, start: usize, end: usize) -> FirmwareImage {
+        let mut segments = Vec::new();
+        for segment in &self.segments {
+            let from = segment.start.max(start);
+            let to = (segment.start + segment.data.len()).min(end);
+            if from < to {
+                let data = segment.data[from - segment.start..to - segment.start].to_vec();
+                segments.push(Segment::with_data(from, data));
+            }
+        }
+        FirmwareImage { segments }
+    }
+
+    pub fn recompute_crcs(&mut self) {
+        compute_crcs(&mut self.segments);
+    }
+
+    // checks every stored segment CRC against its data
+    pub fn verify_integrity(&self) -> Result<(), Error> {
+        let corrupt = |s: &&Segment| s.crc != crc32::checksum_ieee(&s.data);
+        #[cfg(feature = "rayon")]
+        let first = self.segments.par_iter().find_first(corrupt);
+        #[cfg(not(feature = "rayon"))]
+        let first = self.segments.iter().find(corrupt);
+        match first {
+            Some(segment) => Err(Error::SegmentCrcMismatch(segment.start)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut segments: Vec<&Segment> = self
+            .segments
+            .iter()
+            .filter(|s| !s.data.is_empty())
+            .collect();
+        segments.sort_by_key(|s| s.start);
+
+        let mut summary = Summary {
+            segment_count: segments.len(),
+            address_ranges: Vec::new(),
+            total_bytes: 0,
+            overall_crc32: 0,
+            touches_ccfg: false,
+            touches_sram: false,
+        };
+        for segment in segments {
+            let end = segment.start + segment.data.len();
+            extend_range(&mut summary.address_ranges, segment.start..end);
+            summary.total_bytes += segment.data.len();
+            summary.overall_crc32 =
+                crc32::update(summary.overall_crc32, &crc32::IEEE_TABLE, &segment.data);
+            summary.touches_ccfg |= device::KNOWN_FLASH_SIZES
+                .iter()
+                .any(|size| segment.start < *size && end > size - device::CCFG_SIZE);
+            let sram = device::SRAM_START..device::SRAM_START + device::MAX_SRAM_SIZE;
+            summary.touches_sram |= sram.contains(&segment.start);
+        }
+        summary
+    }
+
+    // what changes when going from self to other
+    pub fn diff(&self, other: &FirmwareImage) -> ImageDiff {
+        let mut bounds: Vec<usize> = self
+            .segments
+            .iter()
+            .chain(other.segments.iter())
+            .flat_map(|s| vec![s.start, s.start + s.data.len()])
+            .collect();
+        bounds.sort();
+        bounds.dedup();
+
+        let mut diff = ImageDiff::default();
+        for window in bounds.windows(2) {
+            let (start, end) = (window[0], window[1]);
+            match (self.bytes(start, end), other.bytes(start, end)) {
+                (Some(old), Some(new)) => {
+                    extend_range(&mut diff.covered, start..end);
+                    let mut offset = 0;
+                    while offset < old.len() {
+                        if old[offset] == new[offset] {
+                            offset += 1;
+                            continue;
                         }
+                        let run = old[offset..]
+                            .iter()
+                            .zip(&new[offset..])
+                            .take_while(|(a, b)| a != b)
+                            .count();
+                        extend_range(&mut diff.changed, start + offset..start + offset + run);
+                        offset += run;
                     }
                 }
+                (Some(_), None) => {
+                    extend_range(&mut diff.covered, start..end);
+                    extend_range(&mut diff.removed, start..end);
+                }
+                (None, Some(_)) => {
+                    extend_range(&mut diff.covered, start..end);
+                    extend_range(&mut diff.added, start..end);
+                }
+                (None, None) => {}
             }
+        }
+        diff
+    }
+
+    // the data at [start, end) if a single segment covers all of it
+    fn bytes(&self, start: usize, end: usize) -> Option<&[u8]> {
+        self.segments
+            .iter()
+            .find(|s| start >= s.start && end <= s.start + s.data.len())
+            .map(|s| &s.data[start - s.start..end - s.start])
+    }
+
+    // None unless all four bytes are covered by a single segment
+    pub fn word_at(&self, address: usize) -> Option<u32> {
+        self.segments
+            .iter()
+            .find(|s| address >= s.start && address + 4 <= s.start + s.data.len())
+            .map(|s| LittleEndian::read_u32(&s.data[address - s.start..]))
+    }
+
+    #[cfg(feature = "std")]
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        encode_versioned(MAGIC, self)
+    }
+
+    // the segment CRCs are checked, not trusted, so a blob with consistent but wrong CRCs is refused
+    #[cfg(feature = "std")]
+    pub fn deserialize(encoded: &[u8]) -> Result<FirmwareImage, Error> {
+        let firmware: FirmwareImage = decode_versioned(MAGIC, encoded)?;
+        firmware.verify_integrity()?;
+        Ok(firmware)
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Result<String, ::serde_json::Error> {
+        ::serde_json::to_string(self)
+    }
+
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &str) -> Result<FirmwareImage, ::serde_json::Error> {
+        ::serde_json::from_str(json)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, ::serde_cbor::Error> {
+        ::serde_cbor::to_vec(self)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(cbor: &[u8]) -> Result<FirmwareImage, ::serde_cbor::Error> {
+        ::serde_cbor::from_slice(cbor)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub segment_count: usize,
+    // merged where segments are contiguous, in ascending order
+    pub address_ranges: Vec<Range<usize>>,
+    pub total_bytes: usize,
+    // over the segment data in address order, gaps left out
+    pub overall_crc32: u32,
+    pub touches_ccfg: bool,
+    pub touches_sram: bool,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} segments, {} bytes, CRC32 {:#010x}",
+            self.segment_count, self.total_bytes, self.overall_crc32
+        )?;
+        for range in &self.address_ranges {
+            writeln!(
+                f,
+                "  {:#010x}..{:#010x} ({} bytes)",
+                range.start,
+                range.end,
+                range.end - range.start
+            )?;
+        }
+        write!(
+            f,
+            "CCFG: {}, SRAM: {}",
+            if self.touches_ccfg { "yes" } else { "no" },
+            if self.touches_sram { "yes" } else { "no" }
+        )
+    }
+}
+
+// address ranges added, removed or changed between two images, each list sorted and merged
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageDiff {
+    pub added: Vec<Range<usize>>,
+    pub removed: Vec<Range<usize>>,
+    pub changed: Vec<Range<usize>>,
+    // everything covered by either image
+    covered: Vec<Range<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorChange {
+    pub start: usize,
+    pub changed: bool,
+}
+
+impl ImageDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    // every sector holding data in either image, flagged when anything in it differs
+    pub fn sectors(&self, sector_size: usize) -> Vec<SectorChange> {
+        let differs = self.added.iter().chain(&self.removed).chain(&self.changed);
+        let changed = sectors_of(differs, sector_size);
+        sectors_of(self.covered.iter(), sector_size)
+            .into_iter()
+            .map(|start| SectorChange {
+                start,
+                changed: changed.binary_search(&start).is_ok(),
+            })
+            .collect()
+    }
+
+    // start addresses of the sectors a delta flash has to rewrite
+    pub fn changed_sectors(&self, sector_size: usize) -> Vec<usize> {
+        self.sectors(sector_size)
+            .into_iter()
+            .filter(|s| s.changed)
+            .map(|s| s.start)
+            .collect()
+    }
+}
+
+// appends range, merging it into the last one when they touch
+fn extend_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
+fn sectors_of<'a, I: Iterator<Item = &'a Range<usize>>>(
+    ranges: I,
+    sector_size: usize,
+) -> Vec<usize> {
+    let mut sectors = Vec::new();
+    for range in ranges.filter(|r| r.start < r.end) {
+        let first = range.start / sector_size;
+        let last = (range.end - 1) / sector_size;
+        sectors.extend((first..=last).map(|sector| sector * sector_size));
+    }
+    sectors.sort();
+    sectors.dedup();
+    sectors
+}
+
+// data records for start..start + data.len(), preceded by an extended linear address record whenever
+// the upper 16 bits differ from upper, so consecutive calls can stream a file out piece by piece
+#[cfg(feature = "std")]
+pub fn ihex_records(start: usize, data: &[u8], upper: &mut Option<u16>) -> Vec<Record> {
+    const RECORD_LEN: usize = 16;
+
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let address = start + offset;
+        let address_upper = (address >> 16) as u16;
+        if *upper != Some(address_upper) {
+            records.push(Record::ExtendedLinearAddress(address_upper));
+            *upper = Some(address_upper);
+        }
+        // data records may not cross a 64 KB boundary
+        let len = RECORD_LEN
+            .min(data.len() - offset)
+            .min(0x1_0000 - (address & 0xFFFF));
+        records.push(Record::Data {
+            offset: (address & 0xFFFF) as u16,
+            value: data[offset..offset + len].to_vec(),
         });
-        let mut records: Vec<Record> = split.collect();
-        records.reverse();
-        FirmwareImage::from_records(records)
+        offset += len;
     }
+    records
+}
 
-    pub fn serialize(self) -> Result<Vec<u8>, Box<ErrorKind>> {
-        serialize(&self)
+// returns the address and data bytes of a record after checking count and checksum
+fn parse_srec_line(line: &str) -> Result<Vec<u8>, Error> {
+    if line.len() < 4 || !line.starts_with('S') || line.len() & 1 != 0 {
+        return Err(Error::InvalidSrecRecord);
+    }
+    let mut bytes = Vec::with_capacity(line.len() / 2 - 1);
+    for i in (2..line.len()).step_by(2) {
+        let byte = line
+            .get(i..i + 2)
+            .and_then(|b| u8::from_str_radix(b, 16).ok())
+            .ok_or(Error::InvalidSrecRecord)?;
+        bytes.push(byte);
     }
 
-    pub fn deserialize(encoded: &[u8]) -> Result<FirmwareImage, Box<ErrorKind>> {
-        deserialize(encoded)
+    let count = bytes[0] as usize;
+    if count != bytes.len() - 1 || count == 0 {
+        return Err(Error::InvalidSrecRecord);
+    }
+    let sum = bytes[..count]
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_add(*b));
+    if !sum != bytes[count] {
+        return Err(Error::InvalidSrecRecord);
     }
+    Ok(bytes[1..count].to_vec())
 }
 
+#[cfg(feature = "std")]
 #[test]
 fn test_read_record_from_hex() {
-    const FW_FILE: &'static str = include_str!("firmware/test_parsing.ihex");
+    const FW_FILE: &str = include_str!("firmware/test_parsing.ihex");
     let mut firmware = FirmwareImage::new(FW_FILE).unwrap();
 
     if let Some(current_segment) = firmware.segments.pop() {
@@ -140,13 +1013,14 @@ fn test_read_record_from_hex() {
     }
 }
 
+#[cfg(feature = "std")]
 #[test]
 fn test_serialize_deserialize() {
-    const FW_FILE: &'static str = include_str!("firmware/test_parsing.ihex");
+    const FW_FILE: &str = include_str!("firmware/test_parsing.ihex");
     let firmware = FirmwareImage::new(FW_FILE).unwrap();
 
     let mut encoded = firmware.serialize().unwrap();
-    let mut decoded = FirmwareImage::deserialize(&encoded.as_mut_slice()).unwrap();
+    let mut decoded = FirmwareImage::deserialize(encoded.as_mut_slice()).unwrap();
 
     if let Some(current_segment) = decoded.segments.pop() {
         assert_eq!(current_segment.start, 0);
@@ -154,13 +1028,748 @@ fn test_serialize_deserialize() {
     }
 }
 
+#[cfg(feature = "std")]
+#[test]
+fn test_deserialize_rejects_bad_blobs() {
+    const FW_FILE: &str = include_str!("firmware/test_parsing.ihex");
+    let firmware = FirmwareImage::new(FW_FILE).unwrap();
+    let encoded = firmware.serialize().unwrap();
+
+    match FirmwareImage::deserialize(&encoded[HEADER_LEN..]) {
+        Err(Error::BadMagic) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let mut future = encoded.clone();
+    future[4] = 2;
+    match FirmwareImage::deserialize(&future) {
+        Err(Error::UnsupportedFormatVersion(2)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let mut corrupted = encoded.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xFF;
+    match FirmwareImage::deserialize(&corrupted) {
+        Err(Error::PayloadCrcMismatch) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let mut tampered = firmware.clone();
+    tampered.segments[0].data[0] ^= 0xFF;
+    match FirmwareImage::deserialize(&tampered.serialize().unwrap()) {
+        Err(Error::SegmentCrcMismatch(start)) if start == tampered.segments[0].start => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(tampered.verify_integrity().is_err());
+    tampered.recompute_crcs();
+    assert!(tampered.verify_integrity().is_ok());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_json_round_trip() {
+    const FW_FILE: &str = include_str!("firmware/test_parsing.ihex");
+    let firmware = FirmwareImage::new(FW_FILE).unwrap();
+
+    let json = firmware.to_json().unwrap();
+    let decoded = FirmwareImage::from_json(&json).unwrap();
+    assert_eq!(decoded.segments.len(), firmware.segments.len());
+    assert_eq!(decoded.segments[0].data, firmware.segments[0].data);
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_cbor_round_trip() {
+    const FW_FILE: &str = include_str!("firmware/test_parsing.ihex");
+    let firmware = FirmwareImage::new(FW_FILE).unwrap();
+
+    let cbor = firmware.to_cbor().unwrap();
+    let decoded = FirmwareImage::from_cbor(&cbor).unwrap();
+    assert_eq!(decoded.segments.len(), firmware.segments.len());
+    assert_eq!(decoded.segments[0].crc, firmware.segments[0].crc);
+}
+
+#[cfg(feature = "std")]
 #[test]
 fn test_deserialize_from_include() {
-    const FW_SERIALIZED: &'static [u8] = include_bytes!("firmware/firmware.bincode");
-    let mut decoded = FirmwareImage::deserialize(&FW_SERIALIZED).unwrap();
+    const FW_SERIALIZED: &[u8] = include_bytes!("firmware/firmware.bincode");
+    let mut decoded = FirmwareImage::deserialize(FW_SERIALIZED).unwrap();
 
     if let Some(current_segment) = decoded.segments.pop() {
         assert_eq!(current_segment.start, 0);
         assert_eq!(current_segment.data.len(), 60);
     }
 }
+
+#[cfg(feature = "std")]
+#[test]
+fn test_deserialize_shipped_firmware() {
+    const FW_SERIALIZED: &[u8] = include_bytes!("../firmware/firmware.bincode");
+    let decoded = FirmwareImage::deserialize(FW_SERIALIZED).unwrap();
+    let sram = decoded
+        .segments
+        .iter()
+        .find(|s| s.start == 0x2000_0500)
+        .unwrap();
+    assert_eq!(sram.crc, 0x190a_55ad);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_from_reader() {
+    const FW_FILE: &[u8] = include_bytes!("firmware/test_parsing.ihex");
+    let mut firmware = FirmwareImage::from_reader(FW_FILE).unwrap();
+
+    let current_segment = firmware.segments.pop().unwrap();
+    assert_eq!(current_segment.start, 0);
+    assert_eq!(current_segment.data.len(), 60);
+    assert_eq!(
+        current_segment.crc,
+        crc32::checksum_ieee(&current_segment.data)
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_from_fifo() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::{fs, thread};
+
+    let path = std::env::temp_dir().join(format!("cc131x-fifo-{}", std::process::id()));
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { ::libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+    let writer_path = path.clone();
+    let writer = thread::spawn(move || {
+        fs::write(
+            writer_path,
+            &include_bytes!("firmware/test_parsing.ihex")[..],
+        )
+        .unwrap()
+    });
+    let firmware = FirmwareImage::from_path(&path).unwrap();
+    writer.join().unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let expected = FirmwareImage::new(include_str!("firmware/test_parsing.ihex")).unwrap();
+    assert_eq!(firmware.to_bin(0, 0xFF), expected.to_bin(0, 0xFF));
+}
+
+#[cfg(feature = "gz")]
+#[test]
+fn test_from_gz_reader() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const FW_FILE: &[u8] = include_bytes!("firmware/test_parsing.ihex");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(FW_FILE).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let firmware = FirmwareImage::from_gz_reader(&compressed[..]).unwrap();
+    let expected = FirmwareImage::from_reader(FW_FILE).unwrap();
+    assert_eq!(firmware.segments[0].data, expected.segments[0].data);
+
+    match FirmwareImage::from_gz_reader(&compressed[..compressed.len() / 2]) {
+        Err(Error::IO(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[cfg(feature = "xz")]
+#[test]
+fn test_from_xz_reader() {
+    use std::io::Write;
+    use xz2::write::XzEncoder;
+
+    const FW_FILE: &[u8] = include_bytes!("firmware/test_parsing.ihex");
+    let mut encoder = XzEncoder::new(Vec::new(), 6);
+    encoder.write_all(FW_FILE).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let firmware = FirmwareImage::from_xz_reader(&compressed[..]).unwrap();
+    let expected = FirmwareImage::from_reader(FW_FILE).unwrap();
+    assert_eq!(firmware.segments[0].data, expected.segments[0].data);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_tolerant_line_endings() {
+    const RECORDS: [&str; 3] = [":0400000001020304F2", ":0400040005060708DE", ":00000001FF"];
+    for separator in &["\n", "\r\n", "\r"] {
+        let file = format!("{}{}\n\n", RECORDS.join(separator), separator);
+        let firmware = FirmwareImage::new(&file).unwrap();
+        assert_eq!(firmware.segments.len(), 1);
+        assert_eq!(firmware.segments[0].data, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_malformed_record_is_an_error() {
+    match FirmwareImage::new(":0400000001020304FF\n:00000001FF\n") {
+        Err(Error::BadRecord {
+            line: 1,
+            reason: ReaderError::ChecksumMismatch(..),
+        }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_line_without_start_code_is_an_error() {
+    match FirmwareImage::new(":0400000001020304F2\n\n0400040005060708DE\n:00000001FF\n") {
+        Err(Error::MissingStartCode { line: 3 }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_normalize_merges_and_fills() {
+    let mut firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x106, vec![0x05]),
+            Segment::with_data(0x2000, vec![0x06]),
+            Segment::with_data(0x100, vec![0x01, 0x02]),
+            Segment::with_data(0x102, vec![0x03, 0x04]),
+        ],
+    };
+    firmware.normalize(2, DEFAULT_FILL).unwrap();
+
+    assert_eq!(firmware.segments.len(), 2);
+    assert_eq!(firmware.segments[0].start, 0x100);
+    assert_eq!(
+        firmware.segments[0].data,
+        [0x01, 0x02, 0x03, 0x04, 0xFF, 0xFF, 0x05]
+    );
+    assert_eq!(
+        firmware.segments[0].crc,
+        crc32::checksum_ieee(&firmware.segments[0].data)
+    );
+    assert_eq!(firmware.segments[1].start, 0x2000);
+}
+
+#[test]
+fn test_normalize_detects_overlap() {
+    let mut firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x100, vec![0; 0x10]),
+            Segment::with_data(0x108, vec![0; 0x10]),
+        ],
+    };
+    match firmware.normalize(0, DEFAULT_FILL) {
+        Err(Error::OverlappingSegments(0x100, 0x108)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[cfg(test)]
+fn cc1310_profile() -> ChipProfile {
+    ChipProfile {
+        family: device::ChipFamily::Cc13x0,
+        flash_size: 0x2_0000,
+        sram_size: 0x5000,
+        sector_size: 0x1000,
+        require_sector_alignment: true,
+        crc: Default::default(),
+    }
+}
+
+#[test]
+fn test_validate_against_profile() {
+    let profile = cc1310_profile();
+    let mut firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x0, vec![0; 0x100]),
+            Segment::with_data(0x1F000, vec![0; 0x1000]),
+            Segment::with_data(0x2000_0000, vec![0; 0x10]),
+        ],
+    };
+    firmware.validate(&profile, false).unwrap();
+    match firmware.validate(&profile, true) {
+        Err(Error::SegmentOutsideFlash(0x2000_0000)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    // left out by Bootloader::flash_part just like SRAM
+    firmware.segments[2].start = device::FCFG1_START;
+    firmware.validate(&profile, false).unwrap();
+
+    let straddling = device::FCFG1_START + device::FCFG1_SIZE - 8;
+    firmware.segments[2].start = straddling;
+    match firmware.validate(&profile, false) {
+        Err(Error::SegmentOutsideFlash(a)) if a == straddling => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    firmware.segments[2].start = 0x2000_4FF8;
+    match firmware.validate(&profile, false) {
+        Err(Error::SegmentOutsideFlash(0x2000_4FF8)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    firmware.segments.truncate(1);
+    match firmware.validate(&profile, false) {
+        Err(Error::MissingCcfg) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    firmware.segments[0].start = 0x10;
+    match firmware.validate(&profile, false) {
+        Err(Error::SegmentNotSectorAligned(0x10)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_merge_images() {
+    let stack = FirmwareImage {
+        segments: vec![Segment::with_data(0x1000, vec![1, 2, 3, 4])],
+    };
+    let app = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x0, vec![9; 0x10]),
+            Segment::with_data(0x1002, vec![3, 4, 5, 6]),
+        ],
+    };
+    let merged = stack.merge(app).unwrap();
+    assert_eq!(merged.segments.len(), 2);
+    assert_eq!(merged.segments[1].start, 0x1000);
+    assert_eq!(merged.segments[1].data, [1, 2, 3, 4, 5, 6]);
+    assert_eq!(
+        merged.segments[1].crc,
+        crc32::checksum_ieee(&[1, 2, 3, 4, 5, 6])
+    );
+
+    let conflicting = FirmwareImage {
+        segments: vec![Segment::with_data(0x1003, vec![0xFF])],
+    };
+    match merged.merge(conflicting) {
+        Err(Error::OverlappingSegments(0x1000, 0x1003)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_ihex_round_trip() {
+    let data: Vec<u8> = (0..40).collect();
+    let firmware = FirmwareImage {
+        segments: vec![Segment::with_data(0xFFF0, data.clone())],
+    };
+
+    let hex = firmware.to_ihex_string().unwrap();
+    assert!(hex.starts_with(":020000040000FA\r\n"));
+    assert!(hex.ends_with(":00000001FF\r\n"));
+
+    let parsed = FirmwareImage::new(&hex).unwrap();
+    let segment = parsed.segments.iter().find(|s| s.start == 0xFFF0).unwrap();
+    assert_eq!(segment.data, data);
+}
+
+#[test]
+fn test_to_bin_fills_gaps() {
+    let firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x1004, vec![0xAA, 0xBB]),
+            Segment::with_data(0x0FFE, vec![0x01, 0x02, 0x03]),
+        ],
+    };
+
+    let bin = firmware.to_bin(0x1000, DEFAULT_FILL);
+    assert_eq!(bin, [0x03, 0xFF, 0xFF, 0xFF, 0xAA, 0xBB]);
+}
+
+#[test]
+fn test_touched_sectors_and_clip() {
+    let firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x2FF0, vec![0x11; 0x20]),
+            Segment::with_data(0x0000, vec![0x22; 0x10]),
+        ],
+    };
+    assert_eq!(firmware.touched_sectors(0x1000), [0x0000, 0x2000, 0x3000]);
+
+    let sector = firmware.clip(0x3000, 0x4000);
+    assert_eq!(sector.segments.len(), 1);
+    assert_eq!(sector.segments[0].start, 0x3000);
+    assert_eq!(sector.segments[0].data, [0x11; 0x10]);
+    assert_eq!(sector.segments[0].crc, crc32::checksum_ieee(&[0x11; 0x10]));
+    assert!(firmware.clip(0x1000, 0x2000).segments.is_empty());
+}
+
+#[test]
+fn test_word_at() {
+    let firmware = FirmwareImage {
+        segments: vec![Segment::with_data(
+            0x1000,
+            vec![0x78, 0x56, 0x34, 0x12, 0xAA],
+        )],
+    };
+    assert_eq!(firmware.word_at(0x1000), Some(0x1234_5678));
+    assert_eq!(firmware.word_at(0x1002), None);
+    assert_eq!(firmware.word_at(0x0FFE), None);
+}
+
+#[test]
+fn test_from_elf_uses_load_address() {
+    let mut elf = vec![0; 0x34 + 3 * 32];
+    elf[..6].copy_from_slice(b"\x7FELF\x01\x01");
+    LittleEndian::write_u32(&mut elf[0x1C..], 0x34);
+    LittleEndian::write_u16(&mut elf[0x2A..], 32);
+    LittleEndian::write_u16(&mut elf[0x2C..], 3);
+
+    let mut phdr = |index: usize, p_type: u32, vaddr: u32, paddr: u32, data: &[u8]| {
+        let offset = elf.len() as u32;
+        let base = 0x34 + index * 32;
+        LittleEndian::write_u32(&mut elf[base..], p_type);
+        LittleEndian::write_u32(&mut elf[base + 4..], offset);
+        LittleEndian::write_u32(&mut elf[base + 8..], vaddr);
+        LittleEndian::write_u32(&mut elf[base + 12..], paddr);
+        LittleEndian::write_u32(&mut elf[base + 16..], data.len() as u32);
+        elf.extend_from_slice(data);
+    };
+    // .text, .data (runs from SRAM, loaded from flash) and .bss
+    phdr(0, 1, 0x0, 0x0, &[0x01, 0x02, 0x03, 0x04]);
+    phdr(1, 1, 0x2000_0000, 0x1000, &[0xAA, 0xBB]);
+    phdr(2, 1, 0x2000_0100, 0x2000_0100, &[]);
+
+    let firmware = FirmwareImage::from_elf(&elf).unwrap();
+    assert_eq!(firmware.segments.len(), 2);
+    assert_eq!(firmware.segments[1].start, 0x1000);
+    assert_eq!(firmware.segments[1].data, [0xAA, 0xBB]);
+    assert_eq!(
+        firmware.segments[1].crc,
+        crc32::checksum_ieee(&[0xAA, 0xBB])
+    );
+}
+
+#[test]
+fn test_from_elf_rejects_garbage() {
+    assert!(FirmwareImage::from_elf(b"not an elf file at all").is_err());
+}
+
+#[test]
+fn test_from_elf_rejects_address_overflow() {
+    let mut elf = vec![0; 0x34 + 32];
+    elf[..6].copy_from_slice(b"\x7FELF\x01\x01");
+    LittleEndian::write_u32(&mut elf[0x1C..], 0x34);
+    LittleEndian::write_u16(&mut elf[0x2A..], 32);
+    LittleEndian::write_u16(&mut elf[0x2C..], 1);
+    LittleEndian::write_u32(&mut elf[0x34..], 1);
+    LittleEndian::write_u32(&mut elf[0x34 + 4..], 0x34 + 32);
+    LittleEndian::write_u32(&mut elf[0x34 + 12..], 0xFFFF_FFFF);
+    LittleEndian::write_u32(&mut elf[0x34 + 16..], 2);
+    elf.extend_from_slice(&[0xAA, 0xBB]);
+
+    match FirmwareImage::from_elf(&elf) {
+        Err(Error::InvalidElf) => {}
+        other => panic!(
+            "expected InvalidElf, got {:?}",
+            other.map(|f| f.segments.len())
+        ),
+    }
+}
+
+#[test]
+fn test_from_srec() {
+    const SREC: &str = "S00F000068656C6C6F202020202000003C\n\
+                        S11F00007C0802A6900100049421FFF07C6C1B787C8C23783C6000003863000026\n\
+                        S11F001C4BFFFFE5398000007D83637880010014382100107C0803A64E800020E9\n\
+                        S111003848656C6C6F20776F726C642E0A0042\n\
+                        S5030003F9\n\
+                        S9030000FC\n";
+    let firmware = FirmwareImage::from_srec(SREC).unwrap();
+    assert_eq!(firmware.segments.len(), 1);
+    assert_eq!(firmware.segments[0].start, 0);
+    assert_eq!(firmware.segments[0].data.len(), 28 + 28 + 14);
+    assert_eq!(
+        firmware.segments[0].crc,
+        crc32::checksum_ieee(&firmware.segments[0].data)
+    );
+}
+
+#[test]
+fn test_from_srec_bad_checksum() {
+    assert!(FirmwareImage::from_srec("S1050000AABB00\n").is_err());
+}
+
+#[test]
+fn test_from_bin_split_ccfg() {
+    let bin = vec![0xA5; 0x2000];
+    let mut firmware = FirmwareImage::from_bin(&bin, 0x1E000);
+    firmware.split_segments_at(0x1F000);
+
+    assert_eq!(firmware.segments.len(), 2);
+    assert_eq!(firmware.segments[0].start, 0x1E000);
+    assert_eq!(firmware.segments[0].data.len(), 0x1000);
+    assert_eq!(firmware.segments[1].start, 0x1F000);
+    assert_eq!(firmware.segments[1].data.len(), 0x1000);
+    assert_eq!(
+        firmware.segments[0].crc,
+        crc32::checksum_ieee(&bin[..0x1000])
+    );
+}
+
+#[test]
+fn test_classify_segments() {
+    let layout = FlashLayout::new(device::ChipFamily::Cc13x0, 0x20000, 0x5000);
+    let firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x0000, vec![1; 0x100]),
+            Segment::with_data(0x1FFA8, vec![2; 88]),
+            // the old mask test took this for flash
+            Segment::with_data(0x5000_1000, vec![3; 4]),
+            Segment::with_data(0x2000_0100, vec![4; 4]),
+            Segment::with_data(0x1FFF0, vec![5; 0x20]),
+            // runs past the end of SRAM
+            Segment::with_data(0x2000_4FFC, vec![6; 8]),
+        ],
+    };
+    let (flash, skipped) = firmware.classify(&layout);
+    let starts: Vec<usize> = flash.segments.iter().map(|s| s.start).collect();
+    assert_eq!(starts, [0x0000, 0x1FFA8]);
+    assert_eq!(
+        skipped,
+        [
+            SkippedSegment {
+                start: 0x5000_1000,
+                len: 4,
+                region: Region::Fcfg
+            },
+            SkippedSegment {
+                start: 0x2000_0100,
+                len: 4,
+                region: Region::Sram
+            },
+            SkippedSegment {
+                start: 0x1FFF0,
+                len: 0x20,
+                region: Region::CcfgPage(31)
+            },
+            SkippedSegment {
+                start: 0x2000_4FFC,
+                len: 8,
+                region: Region::Unmapped
+            },
+        ]
+    );
+    let can_skip: Vec<bool> = skipped.iter().map(|s| s.can_skip()).collect();
+    assert_eq!(can_skip, [true, true, false, false]);
+}
+
+#[test]
+fn test_split_at_sectors() {
+    let firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x0F00, vec![1; 0x2200]),
+            Segment::with_data(0x4000, vec![2; 0x10]),
+        ],
+    };
+    let split = firmware.split_at_sectors(0x1000);
+    let spans: Vec<(usize, usize)> = split
+        .segments
+        .iter()
+        .map(|s| (s.start, s.data.len()))
+        .collect();
+    assert_eq!(
+        spans,
+        [
+            (0x0F00, 0x100),
+            (0x1000, 0x1000),
+            (0x2000, 0x1000),
+            (0x3000, 0x100),
+            (0x4000, 0x10)
+        ]
+    );
+    assert!(split.verify_integrity().is_ok());
+    assert_eq!(split.to_bin(0, 0), firmware.to_bin(0, 0));
+}
+
+#[test]
+fn test_sector_image_pads() {
+    let firmware = FirmwareImage {
+        segments: vec![Segment::with_data(0x1010, vec![0xA5; 0x20])],
+    };
+    let sector = firmware.sector_image(0x1000, 0x100, DEFAULT_FILL);
+    assert_eq!(sector.start, 0x1000);
+    assert_eq!(sector.data.len(), 0x100);
+    assert!(sector.data[..0x10].iter().all(|b| *b == 0xFF));
+    assert!(sector.data[0x10..0x30].iter().all(|b| *b == 0xA5));
+    assert!(sector.data[0x30..].iter().all(|b| *b == 0xFF));
+    assert_eq!(sector.crc, crc32::checksum_ieee(&sector.data));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_untrusted_hex_never_panics() {
+    match FirmwareImage::new(":0400000001020304F2\n:0400040005060708DE\n") {
+        Err(Error::TruncatedFile) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    match FirmwareImage::new(":0400000001020304F2\n:00000006FA\n:00000001FF") {
+        Err(Error::UnsupportedRecord {
+            line: 2,
+            record_type: 6,
+        }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    match FirmwareImage::new(":00000001FF\r:0400\r") {
+        Err(Error::BadRecord { line: 2, .. }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    let data = Record::Data {
+        offset: 0,
+        value: vec![1],
+    };
+    match FirmwareImage::from_records(vec![Record::EndOfFile, data, Record::EndOfFile]) {
+        Err(Error::EndOfFileInMiddleOfFile) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(FirmwareImage::from_records(Vec::new()).is_err());
+    assert!(FirmwareImage::from_path(Path::new("/nonexistent/firmware.hex")).is_err());
+    assert!(FirmwareImage::from_reader(&[0xFFu8, 0xFE, b'\n'][..]).is_err());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_ihex_records_stream_in_chunks() {
+    let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+    let start = 0x1_0000 - 300;
+
+    let mut upper = None;
+    let mut streamed = String::new();
+    for (i, chunk) in data.chunks(252).enumerate() {
+        for record in ihex_records(start + i * 252, chunk, &mut upper) {
+            streamed.push_str(&record.to_string().unwrap());
+            streamed.push_str("\r\n");
+        }
+    }
+    streamed.push_str(":00000001FF\r\n");
+    assert_eq!(
+        FirmwareImage::new(&streamed).unwrap().segments[0].data,
+        data
+    );
+    assert_eq!(streamed.matches(":02000004").count(), 2);
+}
+
+#[test]
+fn test_diff_images() {
+    let old = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x0000, vec![0x00; 0x2000]),
+            Segment::with_data(0x4000, vec![0x11; 0x100]),
+        ],
+    };
+    let mut data = vec![0x00; 0x2100];
+    data[0x10..0x18].copy_from_slice(&[0xAA; 8]);
+    let new = FirmwareImage {
+        segments: vec![Segment::with_data(0x0000, data)],
+    };
+
+    let diff = old.diff(&new);
+    assert_eq!(diff.changed, vec![0x10..0x18]);
+    assert_eq!(diff.added, vec![0x2000..0x2100]);
+    assert_eq!(diff.removed, vec![0x4000..0x4100]);
+    assert_eq!(
+        diff.sectors(0x1000),
+        vec![
+            SectorChange {
+                start: 0x0000,
+                changed: true
+            },
+            SectorChange {
+                start: 0x1000,
+                changed: false
+            },
+            SectorChange {
+                start: 0x2000,
+                changed: true
+            },
+            SectorChange {
+                start: 0x4000,
+                changed: true
+            },
+        ]
+    );
+    assert_eq!(diff.changed_sectors(0x1000), vec![0x0000, 0x2000, 0x4000]);
+    assert!(old.diff(&old).is_empty());
+    assert_eq!(old.diff(&old).changed_sectors(0x1000), Vec::<usize>::new());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_summary() {
+    let firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x1000, vec![0x22; 0x10]),
+            Segment::with_data(0x0000, vec![0x11; 0x1000]),
+            Segment::with_data(0x1FFA8, vec![0xFF; device::CCFG_SIZE]),
+        ],
+    };
+    let summary = firmware.summary();
+    assert_eq!(summary.segment_count, 3);
+    assert_eq!(
+        summary.address_ranges,
+        vec![0x0000..0x1010, 0x1FFA8..0x20000]
+    );
+    assert_eq!(summary.total_bytes, 0x1010 + device::CCFG_SIZE);
+    let mut data = vec![0x11; 0x1000];
+    data.extend_from_slice(&[0x22; 0x10]);
+    data.extend_from_slice(&[0xFF; device::CCFG_SIZE]);
+    assert_eq!(summary.overall_crc32, crc32::checksum_ieee(&data));
+    assert!(summary.touches_ccfg);
+    assert!(!summary.touches_sram);
+
+    let ram = FirmwareImage {
+        segments: vec![Segment::with_data(device::SRAM_START, vec![0; 4])],
+    };
+    let summary = ram.summary();
+    assert!(!summary.touches_ccfg);
+    assert!(summary.touches_sram);
+    assert_eq!(
+        summary.to_string(),
+        "1 segments, 4 bytes, CRC32 0x2144df1c\n  0x20000000..0x20000004 (4 bytes)\nCCFG: no, SRAM: yes"
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_from_records_takes_reverse_file_order() {
+    const FILE: &str = ":0400000001020304F2\n:0400100005060708D2\n:00000001FF\n";
+    let mut records: Vec<Record> = FILE
+        .lines()
+        .map(|line| Record::from_record_string(line).unwrap())
+        .collect();
+    records.reverse();
+    let firmware = FirmwareImage::from_records(records.clone()).unwrap();
+    let starts: Vec<usize> = firmware.segments.iter().map(|s| s.start).collect();
+    assert_eq!(starts, [0x10, 0]);
+
+    // the second end of file record older readers made of a blank last line
+    records.insert(0, Record::EndOfFile);
+    let firmware = FirmwareImage::from_records(records).unwrap();
+    assert_eq!(firmware.segments.len(), 2);
+    assert_eq!(
+        starts,
+        FirmwareImage::new(FILE)
+            .unwrap()
+            .segments
+            .iter()
+            .map(|s| s.start)
+            .collect::<Vec<usize>>()
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_line_numbers_count_bare_cr_lines() {
+    // lines 1 and 2 are split by a bare CR, the bad record is on line 3
+    match FirmwareImage::new(":0400000001020304F2\r:0400040005060708DE\nbad\n:00000001FF\n") {
+        Err(Error::MissingStartCode { line: 3 }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
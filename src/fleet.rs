@@ -0,0 +1,92 @@
+use bootloader::FlashReport;
+use firmware_image::FirmwareImage;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use {Cc131x, Error};
+
+/*
+ *  Gang programming: one image flashed onto several radios at once, e.g. a fixture with a CC1310
+ *  on each spidev chip-select. Every device gets its own thread, and one failing does not stop the others
+ */
+
+pub struct FlashFleet {
+    devices: Vec<Arc<Cc131x>>,
+}
+
+// results are in the same order as the devices the fleet was built from
+pub struct FleetReport {
+    pub results: Vec<Result<FlashReport, Error>>,
+}
+
+impl FleetReport {
+    pub fn all_ok(&self) -> bool {
+        self.results.iter().all(|r| r.is_ok())
+    }
+
+    // (device index, error) for every device that failed
+    pub fn failures(&self) -> Vec<(usize, &Error)> {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| r.as_ref().err().map(|e| (i, e)))
+            .collect()
+    }
+}
+
+impl FlashFleet {
+    pub fn new(devices: Vec<Cc131x>) -> FlashFleet {
+        FlashFleet {
+            devices: devices.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    pub fn devices(&self) -> &[Arc<Cc131x>] {
+        &self.devices
+    }
+
+    pub fn flash(&self, firmware: Arc<FirmwareImage>) -> FleetReport {
+        let handles: Vec<_> = self
+            .devices
+            .iter()
+            .map(|device| {
+                let device = device.clone();
+                let firmware = firmware.clone();
+                thread::spawn(move || device.flash_firmware(&firmware))
+            })
+            .collect();
+
+        let results = handles
+            .into_iter()
+            .enumerate()
+            .map(|(i, handle)| {
+                let result = handle.join().unwrap_or_else(|_| {
+                    Err(Error::IO(io::Error::other("flashing thread panicked")))
+                });
+                if let Err(ref e) = result {
+                    warn!("device {} failed: {}", i, e);
+                }
+                result
+            })
+            .collect();
+        FleetReport { results }
+    }
+}
+
+#[test]
+fn test_fleet_report_failures() {
+    let report = FleetReport {
+        results: vec![
+            Ok(FlashReport::default()),
+            Err(Error::MissingPin("slave_ready")),
+            Ok(FlashReport {
+                retries: 2,
+                ..Default::default()
+            }),
+        ],
+    };
+    assert!(!report.all_ok());
+    let failures = report.failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].0, 1);
+}
@@ -0,0 +1,215 @@
+use libc;
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use Error;
+
+/*
+ *  Global sysfs GPIO numbers move around between kernels and SoC revisions, while a line's offset
+ *  on its gpiochip and its label do not. Either form is resolved to the sysfs number sysfs_gpio expects
+ *  Labels are looked up through the GPIO character device, chip offsets through /sys/class/gpio
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum GpioLine {
+    // global sysfs number, what every config used to hold
+    Number(u16),
+    // e.g. { chip = "gpiochip1", line = 17 }
+    ChipLine { chip: String, line: u32 },
+    // the line name from the device tree, e.g. { label = "CC_RESET" }
+    Label { label: String },
+}
+
+impl From<u16> for GpioLine {
+    fn from(number: u16) -> GpioLine {
+        GpioLine::Number(number)
+    }
+}
+
+impl GpioLine {
+    pub fn resolve(&self) -> Result<u16, Error> {
+        match *self {
+            GpioLine::Number(number) => Ok(number),
+            GpioLine::ChipLine { ref chip, line } => sysfs_number(chip, chip_base(chip)?, line),
+            GpioLine::Label { ref label } => {
+                let (chip, line) = find_label(label)?;
+                sysfs_number(&chip, chip_base(&chip)?, line)
+            }
+        }
+    }
+}
+
+// a line past the end of the u16 sysfs numbering cannot be on this board
+fn sysfs_number(chip: &str, base: u16, line: u32) -> Result<u16, Error> {
+    u16::try_from(line)
+        .ok()
+        .and_then(|line| base.checked_add(line))
+        .ok_or_else(|| Error::GpioNotFound(format!("{} line {}", chip, line)))
+}
+
+// the sysfs gpiochip<base> entries are named by their first number, not by the character device
+// the one belonging to a character device shares its parent device
+fn chip_base(chip: &str) -> Result<u16, Error> {
+    for entry in fs::read_dir("/sys/class/gpio")? {
+        let path = entry?.path();
+        if path.join("device").join(chip).exists() {
+            let base = fs::read_to_string(path.join("base"))?;
+            return base
+                .trim()
+                .parse()
+                .map_err(|_| Error::GpioNotFound(format!("{} has no valid base", chip)));
+        }
+    }
+    Err(Error::GpioNotFound(chip.to_string()))
+}
+
+#[repr(C)]
+struct GpioChipInfo {
+    name: [libc::c_char; 32],
+    label: [libc::c_char; 32],
+    lines: u32,
+}
+
+#[repr(C)]
+struct GpioLineInfo {
+    line_offset: u32,
+    flags: u32,
+    name: [libc::c_char; 32],
+    consumer: [libc::c_char; 32],
+}
+
+// _IOR(0xB4, 0x01, struct gpiochip_info) and _IOWR(0xB4, 0x02, struct gpioline_info) from linux/gpio.h
+const GPIO_GET_CHIPINFO_IOCTL: libc::c_ulong = 0x8044_B401;
+const GPIO_GET_LINEINFO_IOCTL: libc::c_ulong = 0xC048_B402;
+
+// errors name the request and the chip, a bare errno does not say which of the gpiochips failed
+fn ioctl<T>(
+    file: &File,
+    chip: &Path,
+    name: &str,
+    request: libc::c_ulong,
+    arg: &mut T,
+) -> io::Result<()> {
+    // the kernel only reads and writes within arg, which matches the struct behind request
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg as *mut T) } < 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(
+            err.kind(),
+            format!("{} on {}: {}", name, chip.display(), err),
+        ));
+    }
+    Ok(())
+}
+
+// (gpiochip name, offset) of the line called label
+fn find_label(label: &str) -> Result<(String, u32), Error> {
+    for entry in fs::read_dir("/dev")? {
+        let path = entry?.path();
+        let chip = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) if name.starts_with("gpiochip") => name.to_string(),
+            _ => continue,
+        };
+        if let Some(line) = find_line(&path, label)? {
+            return Ok((chip, line));
+        }
+    }
+    Err(Error::GpioNotFound(label.to_string()))
+}
+
+fn find_line(chip: &Path, label: &str) -> io::Result<Option<u32>> {
+    let file = File::open(chip)?;
+    let mut info = GpioChipInfo {
+        name: [0; 32],
+        label: [0; 32],
+        lines: 0,
+    };
+    ioctl(
+        &file,
+        chip,
+        "GPIO_GET_CHIPINFO_IOCTL",
+        GPIO_GET_CHIPINFO_IOCTL,
+        &mut info,
+    )?;
+    for line_offset in 0..info.lines {
+        let mut line = GpioLineInfo {
+            line_offset,
+            flags: 0,
+            name: [0; 32],
+            consumer: [0; 32],
+        };
+        ioctl(
+            &file,
+            chip,
+            "GPIO_GET_LINEINFO_IOCTL",
+            GPIO_GET_LINEINFO_IOCTL,
+            &mut line,
+        )?;
+        if c_str(&line.name) == label {
+            return Ok(Some(line_offset));
+        }
+    }
+    Ok(None)
+}
+
+fn c_str(buf: &[libc::c_char; 32]) -> String {
+    let bytes: Vec<u8> = buf.iter().map(|c| *c as u8).collect();
+    match CStr::from_bytes_until_nul(&bytes) {
+        Ok(s) => s.to_string_lossy().into_owned(),
+        Err(_) => String::from_utf8_lossy(&bytes).into_owned(),
+    }
+}
+
+#[test]
+fn test_ioctl_struct_sizes() {
+    use std::mem::size_of;
+
+    // the size is encoded in bits 16..30 of the ioctl request
+    assert_eq!(
+        size_of::<GpioChipInfo>() as libc::c_ulong,
+        (GPIO_GET_CHIPINFO_IOCTL >> 16) & 0x3FFF
+    );
+    assert_eq!(
+        size_of::<GpioLineInfo>() as libc::c_ulong,
+        (GPIO_GET_LINEINFO_IOCTL >> 16) & 0x3FFF
+    );
+}
+
+#[test]
+fn test_number_resolves_to_itself() {
+    assert_eq!(GpioLine::from(49).resolve().unwrap(), 49);
+}
+
+#[test]
+fn test_ioctl_error_names_the_chip() {
+    let file = File::open("/dev/null").unwrap();
+    let mut info = GpioChipInfo {
+        name: [0; 32],
+        label: [0; 32],
+        lines: 0,
+    };
+    let err = ioctl(
+        &file,
+        Path::new("/dev/null"),
+        "GPIO_GET_CHIPINFO_IOCTL",
+        GPIO_GET_CHIPINFO_IOCTL,
+        &mut info,
+    )
+    .unwrap_err();
+    let message = err.to_string();
+    assert!(
+        message.contains("GPIO_GET_CHIPINFO_IOCTL on /dev/null"),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn test_line_past_the_last_sysfs_number() {
+    assert_eq!(sysfs_number("gpiochip1", 480, 17).unwrap(), 497);
+    assert!(sysfs_number("gpiochip1", 0xFFF0, 0x10).is_err());
+    assert!(sysfs_number("gpiochip1", 0, 0x1_0000).is_err());
+}
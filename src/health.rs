@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+use {Cc131x, Error};
+
+/*
+ *  Whether the application came up after flashing, judged by the handshake lines it drives
+ *  or by a caller supplied probe, e.g. a ping over the application's own SPI protocol
+ */
+
+// how often health_check_with asks the probe
+const PROBE_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Health {
+    // the application signalled this long after the check started
+    Booted(Duration),
+    // nothing within the timeout, worth a repair or rollback
+    Silent,
+    // there is neither a handshake line nor a probe to judge by
+    Unknown,
+}
+
+impl Health {
+    pub fn is_booted(&self) -> bool {
+        matches!(*self, Health::Booted(_))
+    }
+}
+
+impl Cc131x {
+    // waits for the application to pull slave_tx_req, or else slave_ready, low
+    // call it right after flashing, while the application is still booting
+    pub fn health_check(&self, timeout: Duration) -> Result<Health, Error> {
+        let pin = match self.slave_tx_req.as_ref().or(self.slave_ready.as_ref()) {
+            Some(pin) => pin,
+            None => return Ok(Health::Unknown),
+        };
+        let start = Instant::now();
+        if pin.wait_for_level(0, timeout)? {
+            Ok(Health::Booted(start.elapsed()))
+        } else {
+            warn!("application did not signal within {:?}", timeout);
+            Ok(Health::Silent)
+        }
+    }
+
+    // asks probe until it reports the application up or timeout expires
+    pub fn health_check_with<F>(&self, timeout: Duration, mut probe: F) -> Result<Health, Error>
+    where
+        F: FnMut(&Cc131x) -> Result<bool, Error>,
+    {
+        let start = self.delay.now();
+        loop {
+            if probe(self)? {
+                return Ok(Health::Booted(self.delay.elapsed(start)));
+            }
+            if self.delay.elapsed(start) + PROBE_INTERVAL > timeout {
+                warn!("application did not answer the probe within {:?}", timeout);
+                return Ok(Health::Silent);
+            }
+            self.delay.sleep(PROBE_INTERVAL);
+        }
+    }
+}
+
+#[test]
+fn test_health_is_booted() {
+    assert!(Health::Booted(Duration::from_millis(40)).is_booted());
+    assert!(!Health::Silent.is_booted());
+    assert!(!Health::Unknown.is_booted());
+}
@@ -0,0 +1,504 @@
+use bootloader::commands::{StatusValue, ACK_BYTE, NACK_BYTE};
+use bootloader::stub::{self, OP_CRC, OP_ERASE, OP_PING, OP_RESET, OP_WRITE};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use ccfg::Ccfg;
+use crc::crc32;
+use delay::VirtualClock;
+use device::{self, CCFG_SIZE};
+use firmware_image::Segment;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use sysfs_gpio::{self, Direction};
+use transport::{Line, Transport};
+use Cc131x;
+
+/*
+ *  A CC1310 ROM bootloader simulated byte by byte behind Transport and Line, so tests can run whole sessions
+ *  Like the ROM it only answers a packet once its last byte is in, and whatever the host clocks in while an
+ *  answer is still waiting to go out is lost; those bytes are recorded so tests can assert there were none
+ *  Pending PendSV after load_and_run starts stub/flash_stub.c in place of the ROM, whatever blob was loaded
+ */
+
+pub const FLASH_SIZE: usize = 128 * 1024;
+pub const SECTOR_SIZE: usize = 4096;
+// CC1310 PG2.1
+pub const CHIP_ID: u32 = 0x2000_8000;
+const DEVICE_ID: u32 = 0xBB9B_E02F;
+const SCB_ICSR: usize = 0xE000_ED04;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Seen {
+    // reset released with bootloader_en asserted
+    Entered,
+    // a packet that passed its checksum, by command byte
+    Command(u8),
+    // a non-zero byte clocked in while an answer was pending
+    Dropped(u8),
+    // the host's ACK of a data packet
+    HostAck,
+    // an ACK or NACK clocked out to the host
+    Answer(u8),
+    // a stub frame that passed its CRC, by op
+    Stub(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineKind {
+    Reset,
+    BootloaderEn,
+}
+
+pub struct MockChip {
+    state: Mutex<State>,
+}
+
+struct State {
+    flash: Vec<u8>,
+    // everything outside flash MemoryRead can reach
+    memory: BTreeMap<u32, u8>,
+    memory_read: bool,
+    // zeros clocked out ahead of every ACK, as while the command is carried out
+    latency: usize,
+    // levels, the lines are active low and pulled up while inputs
+    reset: u8,
+    bootloader_en: u8,
+    in_bootloader: bool,
+    // the flashing stub took over from the ROM
+    stub: bool,
+    // flagged when the byte is the ACK or NACK
+    outgoing: VecDeque<(u8, bool)>,
+    packet: Vec<u8>,
+    awaiting_host_ack: bool,
+    status: StatusValue,
+    // next address and bytes left of the Download in progress
+    download: Option<(usize, usize)>,
+    seen: Vec<Seen>,
+}
+
+impl MockChip {
+    // blank flash, sitting in the application until a Cc131x enters the bootloader
+    pub fn new() -> Arc<MockChip> {
+        let mut memory = BTreeMap::new();
+        let mut poke = |address: u32, bytes: &[u8]| {
+            for (i, b) in bytes.iter().enumerate() {
+                memory.insert(address + i as u32, *b);
+            }
+        };
+        let mut word = [0; 4];
+        LittleEndian::write_u32(&mut word, DEVICE_ID);
+        poke(device::ICEPICK_DEVICE_ID, &word);
+        LittleEndian::write_u32(&mut word, (FLASH_SIZE / SECTOR_SIZE) as u32);
+        poke(device::FLASH_SIZE, &word);
+        LittleEndian::write_u32(&mut word, 3);
+        poke(device::PRCM_RAMHWOPT, &word);
+        poke(device::MAC_15_4_0, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        poke(device::MAC_BLE_0, &[1, 2, 3, 4, 5, 6]);
+
+        Arc::new(MockChip {
+            state: Mutex::new(State {
+                flash: vec![0xFF; FLASH_SIZE],
+                memory,
+                memory_read: true,
+                latency: 0,
+                reset: 1,
+                bootloader_en: 1,
+                in_bootloader: false,
+                stub: false,
+                outgoing: VecDeque::new(),
+                packet: Vec::new(),
+                awaiting_host_ack: false,
+                status: StatusValue::Success,
+                download: None,
+                seen: Vec::new(),
+            }),
+        })
+    }
+
+    // a handle on the chip whose delays only advance a VirtualClock
+    pub fn handle(chip: &Arc<MockChip>) -> Cc131x {
+        let mut io = Cc131x::with_transport(
+            Box::new(chip.clone()),
+            Box::new(MockLine {
+                chip: chip.clone(),
+                kind: LineKind::Reset,
+            }),
+            Box::new(MockLine {
+                chip: chip.clone(),
+                kind: LineKind::BootloaderEn,
+            }),
+        );
+        io.delay = Arc::new(VirtualClock::new());
+        io
+    }
+
+    // a CCFG the interlock lets through, for images flashed with a bank erase
+    pub fn ccfg() -> Segment {
+        let mut ccfg = Ccfg::from_bytes(&[0xFF; CCFG_SIZE]).unwrap();
+        ccfg.bootloader_enabled = true;
+        ccfg.backdoor_enabled = true;
+        ccfg.taps.cpu_dap = true;
+        ccfg.image_valid_conf = 0;
+        Segment::with_data(FLASH_SIZE - CCFG_SIZE, ccfg.to_bytes())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    // like a ROM that ACKs MemoryRead but never answers it
+    pub fn without_memory_read(&self) {
+        self.state().memory_read = false;
+    }
+
+    pub fn set_latency(&self, zeros: usize) {
+        self.state().latency = zeros;
+    }
+
+    pub fn load(&self, address: usize, data: &[u8]) {
+        self.state().flash[address..address + data.len()].copy_from_slice(data);
+    }
+
+    // memory outside flash, e.g. the registers read while identifying the chip
+    pub fn poke(&self, address: u32, data: &[u8]) {
+        let mut state = self.state();
+        for (i, b) in data.iter().enumerate() {
+            state.memory.insert(address + i as u32, *b);
+        }
+    }
+
+    pub fn flash(&self) -> Vec<u8> {
+        self.state().flash.clone()
+    }
+
+    pub fn seen(&self) -> Vec<Seen> {
+        self.state().seen.clone()
+    }
+
+    pub fn count(&self, seen: Seen) -> usize {
+        self.state().seen.iter().filter(|s| **s == seen).count()
+    }
+}
+
+impl State {
+    fn clock(&mut self, tx: u8) -> u8 {
+        let busy = !self.outgoing.is_empty();
+        let rx = match self.outgoing.pop_front() {
+            Some((answer, true)) => {
+                self.seen.push(Seen::Answer(answer));
+                answer
+            }
+            Some((b, false)) => b,
+            None => 0,
+        };
+        self.receive(tx, busy);
+        rx
+    }
+
+    fn receive(&mut self, tx: u8, busy: bool) {
+        if !self.in_bootloader || tx == 0 && self.packet.is_empty() {
+            return;
+        }
+        if busy {
+            self.seen.push(Seen::Dropped(tx));
+            return;
+        }
+        if self.awaiting_host_ack {
+            self.awaiting_host_ack = false;
+            self.seen.push(Seen::HostAck);
+            return;
+        }
+        if self.stub {
+            return self.receive_frame(tx);
+        }
+        self.packet.push(tx);
+        if self.packet.len() >= self.packet[0] as usize {
+            let packet = std::mem::take(&mut self.packet);
+            self.execute(&packet);
+        }
+    }
+
+    fn execute(&mut self, packet: &[u8]) {
+        let checksum = packet
+            .iter()
+            .skip(2)
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if packet.len() < 3 || checksum != packet[1] {
+            self.answer(NACK_BYTE, None);
+            return;
+        }
+        let cmd = packet[2];
+        let args = &packet[3..];
+        let word = |i: usize| BigEndian::read_u32(&args[i..]) as usize;
+        self.seen.push(Seen::Command(cmd));
+
+        let mut reply = None;
+        let previous = self.status;
+        self.status = StatusValue::Success;
+        match cmd {
+            // Ping, SetCCFG
+            0x20 | 0x2D => {}
+            0x21 => {
+                let (address, size) = (word(0), word(4));
+                if address + size <= self.flash.len() {
+                    self.download = Some((address, size));
+                } else {
+                    self.status = StatusValue::InvalidAddr;
+                }
+            }
+            // reports the command before it and leaves that alone
+            0x23 => {
+                reply = Some(vec![previous.to_byte()]);
+                self.status = previous;
+            }
+            0x24 => match self.download {
+                Some((address, left)) if args.len() <= left => {
+                    // programming only ever clears bits
+                    for (cell, b) in self.flash[address..].iter_mut().zip(args) {
+                        *cell &= *b;
+                    }
+                    let left = left - args.len();
+                    self.download = Some((address + args.len(), left)).filter(|_| left > 0);
+                }
+                _ => self.status = StatusValue::InvalidCmd,
+            },
+            0x25 => self.in_bootloader = false,
+            0x26 => {
+                let sector = word(0) / SECTOR_SIZE * SECTOR_SIZE;
+                match self.flash.get_mut(sector..sector + SECTOR_SIZE) {
+                    Some(sector) => sector.iter_mut().for_each(|b| *b = 0xFF),
+                    None => self.status = StatusValue::InvalidAddr,
+                }
+            }
+            // the range is read repeat + 1 times over
+            0x27 => {
+                let data = self.read(word(0), word(4)).repeat(word(8) + 1);
+                let mut crc = [0; 4];
+                BigEndian::write_u32(&mut crc, crc32::checksum_ieee(&data));
+                reply = Some(crc.to_vec());
+            }
+            0x28 => {
+                let mut id = [0; 4];
+                BigEndian::write_u32(&mut id, CHIP_ID);
+                reply = Some(id.to_vec());
+            }
+            0x2A if self.memory_read => {
+                let width = if args[4] == 1 { 4 } else { 1 };
+                reply = Some(self.read(word(0), args[5] as usize * width));
+            }
+            0x2B if self.memory_read => {
+                for (i, b) in args[5..].iter().enumerate() {
+                    self.memory.insert((word(0) + i) as u32, *b);
+                }
+                // PENDSVSET, the stub runs and this write is never answered
+                if word(0) == SCB_ICSR && args[5..].get(3).is_some_and(|b| b & 0x10 != 0) {
+                    self.stub = true;
+                    return;
+                }
+            }
+            0x2C => self.flash.iter_mut().for_each(|b| *b = 0xFF),
+            _ => self.status = StatusValue::UnknownCmd,
+        }
+        self.answer(ACK_BYTE, reply);
+    }
+
+    fn answer(&mut self, ack: u8, reply: Option<Vec<u8>>) {
+        let zeros = self.latency + 1;
+        self.outgoing.extend(vec![(0, false); zeros]);
+        self.outgoing.push_back((ack, true));
+        if let Some(data) = reply {
+            let checksum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            let header = [data.len() as u8 + 2, checksum];
+            self.outgoing
+                .extend(header.iter().chain(&data).map(|b| (*b, false)));
+            self.awaiting_host_ack = true;
+        }
+    }
+
+    // zeros between frames are skipped like the stub does while waiting for SYNC
+    fn receive_frame(&mut self, tx: u8) {
+        if self.packet.is_empty() && tx != stub::SYNC {
+            return;
+        }
+        self.packet.push(tx);
+        if self.packet.len() >= 4 {
+            let len = LittleEndian::read_u16(&self.packet[2..]) as usize;
+            if self.packet.len() >= 8 + len + 4 {
+                let frame = std::mem::take(&mut self.packet);
+                self.execute_frame(&frame);
+            }
+        }
+    }
+
+    fn execute_frame(&mut self, frame: &[u8]) {
+        let (op, address, payload) = match stub::decode_frame(frame) {
+            Some((op, address, payload)) => (op, address as usize, payload),
+            None => return self.answer_frame(NACK_BYTE, Vec::new()),
+        };
+        self.seen.push(Seen::Stub(op));
+        let mut reply = Vec::new();
+        let ok = match op {
+            OP_PING => true,
+            OP_ERASE => match self.flash.get_mut(address..address + SECTOR_SIZE) {
+                Some(sector) if address % SECTOR_SIZE == 0 => {
+                    sector.iter_mut().for_each(|b| *b = 0xFF);
+                    true
+                }
+                _ => false,
+            },
+            OP_WRITE => match self.flash.get_mut(address..address + payload.len()) {
+                Some(cells) => {
+                    for (cell, b) in cells.iter_mut().zip(payload) {
+                        *cell &= *b;
+                    }
+                    true
+                }
+                None => false,
+            },
+            OP_CRC if payload.len() == 4 => {
+                let data = self.read(address, LittleEndian::read_u32(payload) as usize);
+                let mut crc = [0; 4];
+                LittleEndian::write_u32(&mut crc, crc32::checksum_ieee(&data));
+                reply = stub::encode_frame(OP_CRC, address as u32, &crc).unwrap();
+                true
+            }
+            OP_RESET => {
+                self.stub = false;
+                self.in_bootloader = false;
+                return;
+            }
+            _ => false,
+        };
+        self.answer_frame(if ok { ACK_BYTE } else { NACK_BYTE }, reply);
+    }
+
+    // the stub takes as long again to get its reply frame out after the ACK
+    fn answer_frame(&mut self, ack: u8, reply: Vec<u8>) {
+        self.outgoing.extend(vec![(0, false); self.latency + 1]);
+        self.outgoing.push_back((ack, true));
+        if !reply.is_empty() {
+            self.outgoing.extend(vec![(0, false); self.latency]);
+            self.outgoing.extend(reply.into_iter().map(|b| (b, false)));
+        }
+    }
+
+    fn read(&self, address: usize, len: usize) -> Vec<u8> {
+        (address..address + len)
+            .map(|a| match self.flash.get(a) {
+                Some(b) => *b,
+                None => *self.memory.get(&(a as u32)).unwrap_or(&0),
+            })
+            .collect()
+    }
+
+    fn set_line(&mut self, kind: LineKind, level: u8) {
+        match kind {
+            LineKind::Reset => {
+                let released = self.reset == 0 && level == 1;
+                self.reset = level;
+                if level == 0 {
+                    self.in_bootloader = false;
+                } else if released {
+                    self.boot();
+                }
+            }
+            LineKind::BootloaderEn => self.bootloader_en = level,
+        }
+    }
+
+    // the ROM checks the backdoor pin once, right after reset
+    fn boot(&mut self) {
+        self.in_bootloader = self.bootloader_en == 0;
+        self.stub = false;
+        self.outgoing.clear();
+        self.packet.clear();
+        self.awaiting_host_ack = false;
+        self.download = None;
+        if self.in_bootloader {
+            self.seen.push(Seen::Entered);
+        }
+    }
+}
+
+impl Transport for Arc<MockChip> {
+    fn transfer(&self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        let mut state = self.state();
+        for (t, r) in tx.iter().zip(rx.iter_mut()) {
+            *r = state.clock(*t);
+        }
+        Ok(())
+    }
+
+    // the chip cannot answer while the host drives the shared line, whatever it clocks out then is lost
+    fn write(&self, tx: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        for t in tx {
+            state.clock(*t);
+        }
+        Ok(())
+    }
+
+    fn read(&self, rx: &mut [u8]) -> io::Result<()> {
+        let mut state = self.state();
+        for r in rx.iter_mut() {
+            *r = state.clock(0);
+        }
+        Ok(())
+    }
+}
+
+struct MockLine {
+    chip: Arc<MockChip>,
+    kind: LineKind,
+}
+
+impl Line for MockLine {
+    // an output starts out low, an input is pulled up
+    fn set_direction(&self, direction: Direction) -> Result<(), sysfs_gpio::Error> {
+        let level = match direction {
+            Direction::In | Direction::High => 1,
+            Direction::Out | Direction::Low => 0,
+        };
+        self.chip.state().set_line(self.kind, level);
+        Ok(())
+    }
+
+    fn set_value(&self, value: u8) -> Result<(), sysfs_gpio::Error> {
+        self.chip.state().set_line(self.kind, value);
+        Ok(())
+    }
+
+    fn export(&self) -> Result<(), sysfs_gpio::Error> {
+        Ok(())
+    }
+
+    fn unexport(&self) -> Result<(), sysfs_gpio::Error> {
+        Ok(())
+    }
+
+    fn wait_for_level(&self, _level: u8, _timeout: Duration) -> Result<bool, sysfs_gpio::Error> {
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_flash_firmware_on_mock() {
+    use firmware_image::FirmwareImage;
+
+    let chip = MockChip::new();
+    let io = MockChip::handle(&chip);
+    let firmware = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x1000, (0..600).map(|i| i as u8).collect()),
+            MockChip::ccfg(),
+        ],
+    };
+    io.flash_firmware(&firmware).unwrap();
+
+    let flash = chip.flash();
+    assert_eq!(&flash[0x1000..0x1000 + 600], &firmware.segments[0].data[..]);
+    assert_eq!(chip.count(Seen::Entered), 1);
+    assert_eq!(chip.count(Seen::Command(0x25)), 1);
+    assert!(!chip.seen().iter().any(|s| matches!(s, Seen::Dropped(_))));
+}
@@ -0,0 +1,441 @@
+use bootloader::Bootloader;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use crc::crc32;
+use firmware_image::{FirmwareImage, DEFAULT_FILL};
+use std::error;
+use std::fmt;
+use std::ops::Range;
+use Cc131x;
+
+/*
+ *  Parses the fixed part of the TI OAD image header (oad_image_header.h) placed at the start of an OAD image
+ *  The embedded CRC covers everything from the BIM version byte to the end of the image
+ *  With two application slots and a persistent BIM, new images go into whichever slot is not running
+ */
+
+pub const HEADER_LEN: usize = 44;
+// the CRC covers the image starting right after the crc field
+const CRC_START: usize = 12;
+
+const IMAGE_IDS: [&[u8; 8]; 3] = [b"CC13x2R1", b"CC26x2R1", b"OAD IMG "];
+
+// crc_status values, the BIM records whether it checked the image
+pub const CRC_NOT_CHECKED: u8 = 0xFF;
+pub const CRC_VALID: u8 = 0xFE;
+pub const CRC_INVALID: u8 = 0xFC;
+// image_valid is left erased, an image is invalidated by clearing it
+pub const IMAGE_VALID: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Truncated,
+    BadMagic,
+    CrcMismatch { expected: u32, actual: u32 },
+    SlotOverflow { len: usize, slot_size: usize },
+    OverlapsBim,
+    // images are position dependent, one linked for the other slot cannot be moved
+    NotLinkedAtSlot { linked: usize, slot: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Truncated => write!(f, "OAD image is truncated"),
+            Error::BadMagic => write!(f, "unknown OAD image id"),
+            Error::CrcMismatch { expected, actual } => write!(
+                f,
+                "OAD image CRC is {:#010x}, header says {:#010x}",
+                actual, expected
+            ),
+            Error::SlotOverflow { len, slot_size } => write!(
+                f,
+                "{} byte image does not fit a {} byte slot",
+                len, slot_size
+            ),
+            Error::OverlapsBim => write!(f, "image overlaps the BIM"),
+            Error::NotLinkedAtSlot { linked, slot } => write!(
+                f,
+                "image is linked at {:#010x}, not at the slot at {:#010x}",
+                linked, slot
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OadHeader {
+    // flash address the header was read from
+    pub address: usize,
+    pub image_id: [u8; 8],
+    pub crc32: u32,
+    pub bim_version: u8,
+    pub meta_version: u8,
+    pub tech_type: u16,
+    pub image_copy_status: u8,
+    pub crc_status: u8,
+    pub image_type: u8,
+    pub image_number: u8,
+    pub image_valid: u32,
+    pub length: u32,
+    pub program_entry: u32,
+    pub software_version: [u8; 4],
+    pub image_end_address: u32,
+    pub header_length: u16,
+}
+
+impl OadHeader {
+    pub fn from_bytes(address: usize, raw: &[u8]) -> Result<OadHeader, Error> {
+        if raw.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        let mut image_id = [0; 8];
+        image_id.copy_from_slice(&raw[0..8]);
+        if !IMAGE_IDS.iter().any(|id| **id == image_id) {
+            return Err(Error::BadMagic);
+        }
+        let mut software_version = [0; 4];
+        software_version.copy_from_slice(&raw[32..36]);
+
+        Ok(OadHeader {
+            address,
+            image_id,
+            crc32: LittleEndian::read_u32(&raw[8..]),
+            bim_version: raw[12],
+            meta_version: raw[13],
+            tech_type: LittleEndian::read_u16(&raw[14..]),
+            image_copy_status: raw[16],
+            crc_status: raw[17],
+            image_type: raw[18],
+            image_number: raw[19],
+            image_valid: LittleEndian::read_u32(&raw[20..]),
+            length: LittleEndian::read_u32(&raw[24..]),
+            program_entry: LittleEndian::read_u32(&raw[28..]),
+            software_version,
+            image_end_address: LittleEndian::read_u32(&raw[36..]),
+            header_length: LittleEndian::read_u16(&raw[40..]),
+        })
+    }
+
+    pub fn from_image(image: &FirmwareImage, address: usize) -> Result<OadHeader, Error> {
+        for segment in &image.segments {
+            if address >= segment.start && address < segment.start + segment.data.len() {
+                return OadHeader::from_bytes(address, &segment.data[address - segment.start..]);
+            }
+        }
+        Err(Error::Truncated)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.crc_status == CRC_VALID && self.image_valid != 0
+    }
+
+    // software_version is four ASCII characters, e.g. "0001", compared as a big endian word
+    pub fn version(&self) -> u32 {
+        BigEndian::read_u32(&self.software_version)
+    }
+
+    pub fn compute_crc(&self, image: &FirmwareImage) -> Result<u32, Error> {
+        let bin = image.to_bin(self.address, DEFAULT_FILL);
+        let len = self.length as usize;
+        if len < CRC_START || bin.len() < len {
+            return Err(Error::Truncated);
+        }
+        Ok(crc32::checksum_ieee(&bin[CRC_START..len]))
+    }
+
+    pub fn verify_crc(&self, image: &FirmwareImage) -> Result<(), Error> {
+        let actual = self.compute_crc(image)?;
+        if actual != self.crc32 {
+            return Err(Error::CrcMismatch {
+                expected: self.crc32,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn index(self) -> usize {
+        match self {
+            Slot::A => 0,
+            Slot::B => 1,
+        }
+    }
+
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SlotLayout {
+    // start addresses of slot A and slot B, each begins with an OAD header
+    pub slots: [u32; 2],
+    pub slot_size: usize,
+    // the boot image manager, never erased or written
+    pub bim: Range<u32>,
+}
+
+impl SlotLayout {
+    pub fn range(&self, slot: Slot) -> Range<u32> {
+        let start = self.slots[slot.index()];
+        start..start + self.slot_size as u32
+    }
+
+    // the slot not holding the newest valid image, A when neither holds one
+    pub fn inactive_slot(headers: &[Option<OadHeader>; 2]) -> Slot {
+        let version = |slot: Slot| {
+            headers[slot.index()]
+                .as_ref()
+                .filter(|header| header.is_valid())
+                .map(|header| header.version())
+        };
+        match (version(Slot::A), version(Slot::B)) {
+            (Some(a), Some(b)) if a >= b => Slot::B,
+            (Some(_), None) => Slot::B,
+            _ => Slot::A,
+        }
+    }
+
+    // fills in the CRC of an image linked at the start of slot, crc_status stays for the BIM to set
+    pub fn prepare(&self, image: &FirmwareImage, slot: Slot) -> Result<FirmwareImage, Error> {
+        let segments = image.segments.iter().filter(|s| !s.data.is_empty());
+        let start = segments
+            .clone()
+            .map(|s| s.start)
+            .min()
+            .ok_or(Error::Truncated)?;
+        let end = segments
+            .map(|s| s.start + s.data.len())
+            .max()
+            .ok_or(Error::Truncated)?;
+        let len = end - start;
+        if len > self.slot_size {
+            return Err(Error::SlotOverflow {
+                len,
+                slot_size: self.slot_size,
+            });
+        }
+        let base = self.slots[slot.index()] as usize;
+        if start != base {
+            return Err(Error::NotLinkedAtSlot {
+                linked: start,
+                slot: base,
+            });
+        }
+        if base < self.bim.end as usize && base + len > self.bim.start as usize {
+            return Err(Error::OverlapsBim);
+        }
+
+        let mut prepared = image.clone();
+        OadHeader::from_image(&prepared, base)?;
+        {
+            let segment = prepared
+                .segments
+                .iter_mut()
+                .find(|s| s.start == base && s.data.len() >= HEADER_LEN)
+                .ok_or(Error::Truncated)?;
+            segment.data[17] = CRC_NOT_CHECKED;
+            LittleEndian::write_u32(&mut segment.data[20..], IMAGE_VALID);
+        }
+        let crc = OadHeader::from_image(&prepared, base)?.compute_crc(&prepared)?;
+        for segment in &mut prepared.segments {
+            if segment.start == base {
+                LittleEndian::write_u32(&mut segment.data[8..], crc);
+            }
+            segment.recompute_crc();
+        }
+        Ok(prepared)
+    }
+}
+
+impl Cc131x {
+    // a slot without a parsable header reads as None
+    pub fn read_slot_headers(
+        &self,
+        layout: &SlotLayout,
+    ) -> Result<[Option<OadHeader>; 2], ::Error> {
+        let session = self.enter_bootloader()?;
+        Bootloader::initialize(self)?;
+        let mut headers = [None, None];
+        for (header, address) in headers.iter_mut().zip(&layout.slots) {
+            let raw = Bootloader::read_memory(self, *address, HEADER_LEN)?;
+            *header = OadHeader::from_bytes(*address as usize, &raw).ok();
+        }
+        Bootloader::system_reset(self)?;
+        session.finish();
+        Ok(headers)
+    }
+
+    // writes image into the slot that is not running and returns it, the BIM and the other slot are left alone
+    pub fn flash_oad_slot(
+        &self,
+        image: &FirmwareImage,
+        layout: &SlotLayout,
+    ) -> Result<Slot, ::Error> {
+        let slot = SlotLayout::inactive_slot(&self.read_slot_headers(layout)?);
+        let prepared = layout.prepare(image, slot)?;
+        let session = self.enter_bootloader()?;
+        let prepared = &*self.checked(&prepared, false)?;
+        info!("writing OAD image to slot {:?}", slot);
+
+        // the bank erase of flash_firmware would take the BIM and the running slot with it
+        let preserve = [layout.bim.clone(), layout.range(slot.other())];
+        Bootloader::flash_firmware_preserving(self, prepared, &preserve)?;
+        session.finish();
+        Ok(slot)
+    }
+}
+
+#[cfg(test)]
+fn oad_image(address: usize, body: &[u8]) -> FirmwareImage {
+    use firmware_image::Segment;
+
+    let mut data = vec![0; HEADER_LEN];
+    data[..8].copy_from_slice(b"CC13x2R1");
+    data[12] = 3; // BIM version
+    data[18] = 1; // application image
+    data[32..36].copy_from_slice(b"0001");
+    data.extend_from_slice(body);
+    let len = data.len() as u32;
+    LittleEndian::write_u32(&mut data[24..], len);
+    let crc = crc32::checksum_ieee(&data[CRC_START..]);
+    LittleEndian::write_u32(&mut data[8..], crc);
+    FirmwareImage {
+        segments: vec![Segment::with_data(address, data)],
+    }
+}
+
+#[test]
+fn test_parse_and_verify_header() {
+    let image = oad_image(0x2000, &[0xAB; 100]);
+    let header = OadHeader::from_image(&image, 0x2000).unwrap();
+    assert_eq!(&header.image_id, b"CC13x2R1");
+    assert_eq!(header.bim_version, 3);
+    assert_eq!(header.length as usize, HEADER_LEN + 100);
+    assert_eq!(&header.software_version, b"0001");
+    assert!(header.version() < BigEndian::read_u32(b"0002"));
+    header.verify_crc(&image).unwrap();
+}
+
+#[test]
+fn test_detects_corruption() {
+    let mut image = oad_image(0x2000, &[0xAB; 100]);
+    image.segments[0].data[HEADER_LEN + 5] = 0;
+    let header = OadHeader::from_image(&image, 0x2000).unwrap();
+    match header.verify_crc(&image) {
+        Err(Error::CrcMismatch { .. }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(OadHeader::from_image(&image, 0x2004), Err(Error::BadMagic));
+}
+
+#[cfg(test)]
+fn slot_header(version: &[u8; 4], crc_status: u8) -> Option<OadHeader> {
+    let image = oad_image(0, &[]);
+    let mut header = OadHeader::from_image(&image, 0).unwrap();
+    header.software_version = *version;
+    header.crc_status = crc_status;
+    header.image_valid = IMAGE_VALID;
+    Some(header)
+}
+
+#[test]
+fn test_inactive_slot() {
+    assert_eq!(SlotLayout::inactive_slot(&[None, None]), Slot::A);
+    let running_a = [
+        slot_header(b"0002", CRC_VALID),
+        slot_header(b"0001", CRC_VALID),
+    ];
+    assert_eq!(SlotLayout::inactive_slot(&running_a), Slot::B);
+    let running_b = [
+        slot_header(b"0002", CRC_INVALID),
+        slot_header(b"0001", CRC_VALID),
+    ];
+    assert_eq!(SlotLayout::inactive_slot(&running_b), Slot::A);
+    let unchecked = [
+        slot_header(b"0001", CRC_VALID),
+        slot_header(b"0003", CRC_NOT_CHECKED),
+    ];
+    assert_eq!(SlotLayout::inactive_slot(&unchecked), Slot::B);
+}
+
+#[test]
+fn test_prepare_seals_without_checking() {
+    let layout = SlotLayout {
+        slots: [0x2000, 0x10000],
+        slot_size: 0xE000,
+        bim: 0x1E000..0x20000,
+    };
+    let image = oad_image(0x10000, &[0xAB; 100]);
+    let prepared = layout.prepare(&image, Slot::B).unwrap();
+    let header = OadHeader::from_image(&prepared, 0x10000).unwrap();
+    assert_eq!(header.crc_status, CRC_NOT_CHECKED);
+    assert_eq!(header.image_valid, IMAGE_VALID);
+    header.verify_crc(&prepared).unwrap();
+    assert_eq!(
+        prepared.segments[0].crc,
+        crc32::checksum_ieee(&prepared.segments[0].data)
+    );
+
+    assert_eq!(
+        layout.prepare(&image, Slot::A).unwrap_err(),
+        Error::NotLinkedAtSlot {
+            linked: 0x10000,
+            slot: 0x2000
+        }
+    );
+    let too_big = oad_image(0x2000, &[0; 0xE000]);
+    match layout.prepare(&too_big, Slot::A) {
+        Err(Error::SlotOverflow { .. }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    let overlapping = SlotLayout {
+        bim: 0x10000..0x12000,
+        ..layout
+    };
+    assert_eq!(
+        overlapping.prepare(&image, Slot::B).unwrap_err(),
+        Error::OverlapsBim
+    );
+}
+
+#[test]
+fn test_flash_oad_slot_on_mock() {
+    use mock::MockChip;
+
+    let layout = SlotLayout {
+        slots: [0x2000, 0x10000],
+        slot_size: 0xE000,
+        bim: 0x1E000..0x20000,
+    };
+    let chip = MockChip::new();
+    let mut running = oad_image(0x2000, &[0x11; 100]);
+    running.segments[0].data[17] = CRC_VALID;
+    LittleEndian::write_u32(&mut running.segments[0].data[20..], IMAGE_VALID);
+    chip.load(0x2000, &running.segments[0].data);
+    chip.load(0x1E000, &[0x22; 16]);
+    let io = MockChip::handle(&chip);
+
+    let slot = io
+        .flash_oad_slot(&oad_image(0x10000, &[0xAB; 100]), &layout)
+        .unwrap();
+    assert_eq!(slot, Slot::B);
+    let flash = chip.flash();
+    let written = OadHeader::from_bytes(0x10000, &flash[0x10000..]).unwrap();
+    assert_eq!(written.crc_status, CRC_NOT_CHECKED);
+    assert_eq!(&flash[0x2000..0x2000 + 144], &running.segments[0].data[..]);
+    assert_eq!(&flash[0x1E000..0x1E010], &[0x22; 16]);
+}
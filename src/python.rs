@@ -0,0 +1,196 @@
+use bootloader::progress::{ProgressEvent, ProgressSink};
+use bootloader::verify::VerifyMode;
+use config::Cc131xConfig;
+use firmware_image::FirmwareImage;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyRange, PyRangeMethods};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::time::Duration;
+use {Cc131x, Cc131xBuilder};
+
+/*
+ *  Python bindings, so lab scripts drive the same flashing code as production
+ *  The GIL is released for every bootloader session, progress callbacks take it back for each call
+ */
+
+create_exception!(
+    ti_rom_bootloader_cc13xx_cc25xx,
+    BootloaderError,
+    PyException
+);
+
+fn to_py<E: Display>(err: E) -> PyErr {
+    BootloaderError::new_err(err.to_string())
+}
+
+// called as callback(bytes_done, total) for every chunk written
+struct PyProgress(PyObject);
+
+impl ProgressSink for PyProgress {
+    fn event(&self, event: &ProgressEvent) {
+        if let ProgressEvent::ChunkWritten { bytes_done, total } = *event {
+            Python::with_gil(|py| {
+                // an exception in the callback is reported, flashing carries on
+                if let Err(err) = self.0.call1(py, (bytes_done, total)) {
+                    err.write_unraisable(py, None);
+                }
+            });
+        }
+    }
+}
+
+#[pyclass(name = "Cc13xx", module = "ti_rom_bootloader_cc13xx_cc25xx")]
+pub struct PyCc13xx {
+    io: Cc131x,
+}
+
+impl PyCc13xx {
+    // progress is only set for the duration of one call
+    fn with_progress<T, F>(&mut self, py: Python, progress: Option<PyObject>, op: F) -> PyResult<T>
+    where
+        F: FnOnce(&Cc131x) -> Result<T, ::Error> + Send,
+        T: Send,
+    {
+        self.io.progress = progress.map(|p| Box::new(PyProgress(p)) as Box<dyn ProgressSink>);
+        let io = &self.io;
+        let result = py.allow_threads(|| op(io));
+        self.io.progress = None;
+        result.map_err(to_py)
+    }
+}
+
+#[pymethods]
+impl PyCc13xx {
+    // pins are global sysfs GPIO numbers
+    #[new]
+    #[pyo3(signature = (spidev, reset_pin, bootloader_en_pin, slave_ready_pin=None, slave_tx_req_pin=None))]
+    fn new(
+        spidev: PathBuf,
+        reset_pin: u16,
+        bootloader_en_pin: u16,
+        slave_ready_pin: Option<u16>,
+        slave_tx_req_pin: Option<u16>,
+    ) -> PyResult<PyCc13xx> {
+        let mut builder = Cc131xBuilder::new(spidev, reset_pin, bootloader_en_pin);
+        if let Some(pin) = slave_ready_pin {
+            builder = builder.slave_ready(pin);
+        }
+        if let Some(pin) = slave_tx_req_pin {
+            builder = builder.slave_tx_req(pin);
+        }
+        let io = builder.build().map_err(to_py)?;
+        Ok(PyCc13xx { io })
+    }
+
+    #[staticmethod]
+    fn from_config(path: PathBuf) -> PyResult<PyCc13xx> {
+        let config = Cc131xConfig::from_file(path).map_err(to_py)?;
+        let io = Cc131x::from_config(&config).map_err(to_py)?;
+        Ok(PyCc13xx { io })
+    }
+
+    // returns how many commands had to be retried
+    #[pyo3(signature = (hex_path, progress=None))]
+    fn flash(
+        &mut self,
+        py: Python,
+        hex_path: PathBuf,
+        progress: Option<PyObject>,
+    ) -> PyResult<usize> {
+        let firmware = FirmwareImage::from_path(&hex_path).map_err(to_py)?;
+        let report = self.with_progress(py, progress, |io| io.flash_firmware(&firmware))?;
+        Ok(report.retries)
+    }
+
+    // CRC per segment, or every byte read back
+    #[pyo3(signature = (hex_path, read_back=false))]
+    fn verify(&mut self, py: Python, hex_path: PathBuf, read_back: bool) -> PyResult<()> {
+        let firmware = FirmwareImage::from_path(&hex_path).map_err(to_py)?;
+        let mode = if read_back {
+            VerifyMode::ReadBack
+        } else {
+            VerifyMode::CrcPerSegment
+        };
+        self.with_progress(py, None, |io| io.verify(&firmware, mode))
+    }
+
+    // a list of (address, bytes), unreadable spans are left out
+    fn dump<'py>(
+        &mut self,
+        py: Python<'py>,
+        range: &Bound<'py, PyRange>,
+    ) -> PyResult<Vec<(usize, Bound<'py, PyBytes>)>> {
+        if range.step()? != 1 {
+            return Err(PyValueError::new_err("dump needs a range with a step of 1"));
+        }
+        // a negative bound would wrap around to the top of the address space
+        let bound = |b: isize| {
+            u32::try_from(b)
+                .map_err(|_| PyValueError::new_err(format!("dump address {} is out of range", b)))
+        };
+        let start = bound(range.start()?)?;
+        let stop = bound(range.stop()?)?;
+        let image = self.with_progress(py, None, |io| io.dump_flash(start..stop))?;
+        Ok(image
+            .segments
+            .iter()
+            .map(|s| (s.start, PyBytes::new(py, &s.data)))
+            .collect())
+    }
+
+    #[pyo3(signature = (timeout=1.0))]
+    fn chip_info<'py>(&mut self, py: Python<'py>, timeout: f64) -> PyResult<Bound<'py, PyDict>> {
+        let timeout = Duration::from_secs_f64(timeout);
+        let probe = self.with_progress(py, None, |io| io.probe(timeout))?;
+        let info = probe.info;
+        let dict = PyDict::new(py);
+        dict.set_item("chip_id", info.chip_id)?;
+        dict.set_item("family", format!("{:?}", info.family))?;
+        dict.set_item("flash_size", info.flash_size)?;
+        dict.set_item("ram_size", info.ram_size)?;
+        dict.set_item("sector_size", info.sector_size)?;
+        dict.set_item("ieee_mac", PyBytes::new(py, &info.ieee_mac))?;
+        Ok(dict)
+    }
+}
+
+#[pymodule]
+fn ti_rom_bootloader_cc13xx_cc25xx(m: &Bound<PyModule>) -> PyResult<()> {
+    m.add_class::<PyCc13xx>()?;
+    m.add("BootloaderError", m.py().get_type::<BootloaderError>())?;
+    Ok(())
+}
+
+#[test]
+fn test_progress_callback() {
+    use std::ffi::CString;
+
+    pyo3::prepare_freethreaded_python();
+    let seen = Python::with_gil(|py| {
+        let seen = py.eval(&CString::new("[]").unwrap(), None, None).unwrap();
+        let callback = seen.getattr("append").unwrap();
+        let callback = py
+            .eval(
+                &CString::new("lambda append: lambda done, total: append((done, total))").unwrap(),
+                None,
+                None,
+            )
+            .unwrap()
+            .call1((callback,))
+            .unwrap();
+        let sink = PyProgress(callback.unbind());
+        py.allow_threads(|| {
+            sink.event(&ProgressEvent::SegmentStarted { addr: 0, len: 504 });
+            sink.event(&ProgressEvent::ChunkWritten {
+                bytes_done: 252,
+                total: 504,
+            });
+        });
+        seen.extract::<Vec<(usize, usize)>>().unwrap()
+    });
+    assert_eq!(seen, [(252, 504)]);
+}
@@ -0,0 +1,100 @@
+use bootloader::Bootloader;
+use std::ops::Deref;
+use std::sync::atomic::Ordering;
+use std::sync::MutexGuard;
+use std::thread;
+use sysfs_gpio::Direction;
+use Cc131x;
+
+/*
+ *  Returned by Cc131x::enter_bootloader so that no early return leaves the radio sitting in the bootloader
+ *  Dropping it resets the chip unless finish() was called, and always hands bootloader_en back as an input
+ *  It also holds the handle's bus lock, so a Cc131x shared through an Arc runs one session at a time
+ *  Sessions opened inside another one on the same thread nest: only the outermost one resets the chip,
+ *  Bootloader::system_reset included, so e.g. Cc131x::update runs all of its steps in one session
+ */
+
+pub struct BootloaderSession<'a> {
+    io: &'a Cc131x,
+    reset_on_drop: bool,
+    // released only after drop() has reset the chip
+    _lock: BusLock<'a>,
+}
+
+// the handle's bus lock, which the thread already holding it takes again for free,
+// so Bootloader calls made inside a session do not wait on themselves
+pub(crate) struct BusLock<'a> {
+    io: &'a Cc131x,
+    // None when the lock was already held by this thread
+    guard: Option<MutexGuard<'a, ()>>,
+}
+
+impl<'a> BusLock<'a> {
+    pub(crate) fn acquire(io: &'a Cc131x) -> BusLock<'a> {
+        let me = thread::current().id();
+        if *io.lock_owner() == Some(me) {
+            return BusLock { io, guard: None };
+        }
+        // a thread that panicked mid-session leaves nothing behind worth protecting
+        let guard = io
+            .session_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *io.lock_owner() = Some(me);
+        BusLock {
+            io,
+            guard: Some(guard),
+        }
+    }
+}
+
+impl<'a> Drop for BusLock<'a> {
+    fn drop(&mut self) {
+        if self.guard.is_some() {
+            *self.io.lock_owner() = None;
+        }
+    }
+}
+
+impl<'a> BootloaderSession<'a> {
+    pub(crate) fn new(io: &'a Cc131x, lock: BusLock<'a>) -> BootloaderSession<'a> {
+        io.sessions.fetch_add(1, Ordering::Relaxed);
+        BootloaderSession {
+            io,
+            reset_on_drop: true,
+            _lock: lock,
+        }
+    }
+
+    // for operations that already ended with a system_reset of their own
+    pub fn finish(mut self) {
+        self.reset_on_drop = false;
+    }
+}
+
+impl<'a> Deref for BootloaderSession<'a> {
+    type Target = Cc131x;
+
+    fn deref(&self) -> &Cc131x {
+        self.io
+    }
+}
+
+impl<'a> Drop for BootloaderSession<'a> {
+    fn drop(&mut self) {
+        // best effort, there is no one left to report errors to
+        if self.reset_on_drop {
+            let _ = Bootloader::system_reset(self.io);
+        }
+        if self.io.sessions.fetch_sub(1, Ordering::Relaxed) == 1 {
+            // BL_ON is active low for BL, keep as input
+            let _ = self.io.bootloader_en.set_direction(Direction::In);
+        }
+    }
+}
+
+#[test]
+fn test_handle_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Cc131x>();
+}
@@ -0,0 +1,112 @@
+use bootloader::commands::{is_command_packet, Error as BlPkError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+ *  Protocol counters kept on every Cc131x, cheap enough to leave on and read from any thread,
+ *  so SPI error rates can be lined up against flash failures across a fleet
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Statistics {
+    pub retries: usize,
+    pub nacks_received: usize,
+    pub checksum_errors: usize,
+    pub bytes_tx: usize,
+    pub bytes_rx: usize,
+    pub commands_sent: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    // retries are counted by the Cc131x itself, only where counting started is kept here
+    retries_base: AtomicUsize,
+    nacks_received: AtomicUsize,
+    checksum_errors: AtomicUsize,
+    bytes_tx: AtomicUsize,
+    bytes_rx: AtomicUsize,
+    commands_sent: AtomicUsize,
+}
+
+impl Counters {
+    // in half duplex only one direction of a transfer carries anything
+    pub fn transfer(&self, tx: &[u8], rx: &[u8], half_duplex: bool) {
+        let reading = tx.iter().all(|b| *b == 0);
+        if !half_duplex || !reading {
+            self.bytes_tx.fetch_add(tx.len(), Ordering::Relaxed);
+        }
+        if !half_duplex || reading {
+            self.bytes_rx.fetch_add(rx.len(), Ordering::Relaxed);
+        }
+        if is_command_packet(tx) {
+            self.commands_sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn protocol_error(&self, err: &BlPkError) {
+        match *err {
+            BlPkError::Nack => self.nacks_received.fetch_add(1, Ordering::Relaxed),
+            BlPkError::BadChecksum => self.checksum_errors.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    pub fn snapshot(&self, retries: usize) -> Statistics {
+        Statistics {
+            retries: retries.saturating_sub(self.retries_base.load(Ordering::Relaxed)),
+            nacks_received: self.nacks_received.load(Ordering::Relaxed),
+            checksum_errors: self.checksum_errors.load(Ordering::Relaxed),
+            bytes_tx: self.bytes_tx.load(Ordering::Relaxed),
+            bytes_rx: self.bytes_rx.load(Ordering::Relaxed),
+            commands_sent: self.commands_sent.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self, retries: usize) {
+        self.retries_base.store(retries, Ordering::Relaxed);
+        for counter in &[
+            &self.nacks_received,
+            &self.checksum_errors,
+            &self.bytes_tx,
+            &self.bytes_rx,
+            &self.commands_sent,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[test]
+fn test_counters() {
+    let counters = Counters::default();
+    let ping = [3, 0x20, 0x20, 0, 0];
+    counters.transfer(&ping, &[0; 5], false);
+    counters.transfer(&[0; 4], &[0; 4], false);
+    counters.protocol_error(&BlPkError::Nack);
+    counters.protocol_error(&BlPkError::BadChecksum);
+    counters.protocol_error(&BlPkError::NoAck);
+    assert_eq!(
+        counters.snapshot(3),
+        Statistics {
+            retries: 3,
+            nacks_received: 1,
+            checksum_errors: 1,
+            bytes_tx: 9,
+            bytes_rx: 9,
+            commands_sent: 1,
+        }
+    );
+
+    counters.reset(3);
+    counters.transfer(&ping, &[0; 5], true);
+    counters.transfer(&[0; 4], &[0; 4], true);
+    assert_eq!(
+        counters.snapshot(5),
+        Statistics {
+            retries: 2,
+            bytes_tx: 5,
+            bytes_rx: 4,
+            commands_sent: 1,
+            ..Statistics::default()
+        }
+    );
+}
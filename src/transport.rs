@@ -0,0 +1,84 @@
+use spidev::{Spidev, SpidevTransfer};
+use std::io;
+use std::time::{Duration, Instant};
+use sysfs_gpio::{self, Direction, Edge, Pin};
+
+/*
+ *  The SPI bus and GPIO lines behind a Cc131x: spidev and sysfs GPIO on a real board,
+ *  or anything else that can clock bytes and drive lines, e.g. the simulated chip in mock.rs
+ */
+
+pub trait Transport: Send + Sync {
+    // full duplex, rx is clocked in while tx is clocked out and is as long as it
+    fn transfer(&self, tx: &[u8], rx: &mut [u8]) -> io::Result<()>;
+    // 3-wire, the shared line is driven by us and nothing comes back
+    fn write(&self, tx: &[u8]) -> io::Result<()>;
+    // 3-wire, the shared line is left to the chip
+    fn read(&self, rx: &mut [u8]) -> io::Result<()>;
+}
+
+impl Transport for Spidev {
+    fn transfer(&self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        Spidev::transfer(self, &mut SpidevTransfer::read_write(tx, rx))
+    }
+
+    fn write(&self, tx: &[u8]) -> io::Result<()> {
+        Spidev::transfer(self, &mut SpidevTransfer::write(tx))
+    }
+
+    fn read(&self, rx: &mut [u8]) -> io::Result<()> {
+        Spidev::transfer(self, &mut SpidevTransfer::read(rx))
+    }
+}
+
+// the part of sysfs_gpio::Pin that Cc131x uses
+pub trait Line: Send + Sync {
+    fn set_direction(&self, direction: Direction) -> Result<(), sysfs_gpio::Error>;
+    fn set_value(&self, value: u8) -> Result<(), sysfs_gpio::Error>;
+    fn export(&self) -> Result<(), sysfs_gpio::Error>;
+    fn unexport(&self) -> Result<(), sysfs_gpio::Error>;
+    // returns false if the line did not read level within timeout
+    fn wait_for_level(&self, level: u8, timeout: Duration) -> Result<bool, sysfs_gpio::Error>;
+}
+
+impl Line for Pin {
+    fn set_direction(&self, direction: Direction) -> Result<(), sysfs_gpio::Error> {
+        Pin::set_direction(self, direction)
+    }
+
+    fn set_value(&self, value: u8) -> Result<(), sysfs_gpio::Error> {
+        Pin::set_value(self, value)
+    }
+
+    fn export(&self) -> Result<(), sysfs_gpio::Error> {
+        Pin::export(self)
+    }
+
+    fn unexport(&self) -> Result<(), sysfs_gpio::Error> {
+        Pin::unexport(self)
+    }
+
+    // sleeps on an edge interrupt (epoll on the sysfs value file) rather than polling
+    fn wait_for_level(&self, level: u8, timeout: Duration) -> Result<bool, sysfs_gpio::Error> {
+        Pin::set_direction(self, Direction::In)?;
+        self.set_edge(Edge::BothEdges)?;
+        // the poller is armed before the first read so no edge slips through in between
+        let mut poller = self.get_poller()?;
+        if self.get_value()? == level {
+            return Ok(true);
+        }
+        let start = Instant::now();
+        loop {
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Ok(false);
+            }
+            let remaining = timeout - elapsed;
+            // round up so sub-millisecond remainders still wait
+            let remaining_ms = (remaining.as_micros() as isize + 999) / 1000;
+            if poller.poll(remaining_ms)? == Some(level) {
+                return Ok(true);
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use bootloader::verify::VerifyMode;
+use bootloader::Bootloader;
+use bundle::{FirmwareBundle, SignaturePolicy};
+use {Cc131x, Error};
+
+/*
+ *  The whole update sequence in one call: check the bundle, skip it if the chip already runs it,
+ *  repair a damaged copy of the same version, otherwise flash it in full or sector by sector
+ */
+
+#[derive(Debug, Clone)]
+pub struct UpdatePolicy {
+    pub signature: SignaturePolicy,
+    // rewrite only the sectors that differ instead of erasing the whole bank
+    pub incremental: bool,
+    // when the installed version matches but its CRC does not, rewrite just the damaged sectors
+    pub repair: bool,
+    // checked once more after flashing, on top of what Cc131x::verify checked while writing
+    // e.g. ReadBack on a factory line, None for OTA over a metered link
+    pub verify: VerifyMode,
+}
+
+impl Default for UpdatePolicy {
+    fn default() -> UpdatePolicy {
+        UpdatePolicy {
+            signature: SignaturePolicy::default(),
+            incremental: false,
+            repair: false,
+            verify: VerifyMode::None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateOutcome {
+    AlreadyCurrent,
+    Updated,
+    // the sectors that had to be rewritten
+    Repaired(Vec<u32>),
+    Failed(Error),
+}
+
+impl UpdateOutcome {
+    pub fn is_ok(&self) -> bool {
+        !matches!(*self, UpdateOutcome::Failed(_))
+    }
+}
+
+impl Cc131x {
+    pub fn update(&self, bundle: &FirmwareBundle, policy: &UpdatePolicy) -> UpdateOutcome {
+        match self.try_update(bundle, policy) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                warn!("update failed: {}", e);
+                UpdateOutcome::Failed(e)
+            }
+        }
+    }
+
+    // a single session around every step, whose own sessions nest in it, so the chip is entered and reset once
+    fn try_update(
+        &self,
+        bundle: &FirmwareBundle,
+        policy: &UpdatePolicy,
+    ) -> Result<UpdateOutcome, Error> {
+        policy.signature.check(bundle)?;
+        let image = &bundle.image;
+        if let Some(ref profile) = self.profile {
+            image.validate(profile, self.strict_segments)?;
+        }
+
+        let session = self.enter_bootloader()?;
+        let outcome = self.update_steps(bundle, policy)?;
+        Bootloader::system_reset(self)?;
+        session.finish();
+        Ok(outcome)
+    }
+
+    fn update_steps(
+        &self,
+        bundle: &FirmwareBundle,
+        policy: &UpdatePolicy,
+    ) -> Result<UpdateOutcome, Error> {
+        let image = &bundle.image;
+        // without a version word the CRC alone decides, a differing version always gets flashed
+        let version_match = self.version_match(image)?;
+        if version_match != Some(false) && self.crc_match(image)? {
+            info!("firmware already current");
+            return Ok(UpdateOutcome::AlreadyCurrent);
+        }
+        if version_match == Some(true) && policy.repair {
+            let sectors = self.repair(image)?;
+            info!("repaired {} sectors", sectors.len());
+            return Ok(UpdateOutcome::Repaired(sectors));
+        }
+
+        if policy.incremental {
+            let rewritten = self.flash_bundle_incremental(bundle, &policy.signature)?;
+            info!("rewrote {} sectors", rewritten);
+        } else {
+            self.flash_bundle_with(bundle, &policy.signature)?;
+        }
+        if policy.verify == VerifyMode::ReadBack {
+            self.verify_exact(image)?;
+        } else if policy.verify != VerifyMode::None {
+            self.verify(image, policy.verify)?;
+        }
+        Ok(UpdateOutcome::Updated)
+    }
+}
+
+#[test]
+fn test_outcome_is_ok() {
+    assert!(UpdateOutcome::AlreadyCurrent.is_ok());
+    assert!(UpdateOutcome::Repaired(vec![0x1000]).is_ok());
+    assert!(!UpdateOutcome::Failed(Error::MissingPin("reset")).is_ok());
+}
+
+#[test]
+fn test_update_enters_and_resets_once() {
+    use bundle::Version;
+    use device::ChipFamily;
+    use firmware_image::{FirmwareImage, Segment};
+    use mock::{MockChip, Seen};
+
+    let chip = MockChip::new();
+    let io = MockChip::handle(&chip);
+    let image = FirmwareImage {
+        segments: vec![
+            Segment::with_data(0x1000, vec![0xA5; 300]),
+            MockChip::ccfg(),
+        ],
+    };
+    let bundle = FirmwareBundle::new(image, Version::new(1, 0, 0), ChipFamily::Cc13x0, "test");
+    let policy = UpdatePolicy {
+        verify: VerifyMode::CrcPerSegment,
+        ..UpdatePolicy::default()
+    };
+    match io.update(&bundle, &policy) {
+        UpdateOutcome::Updated => {}
+        other => panic!("unexpected outcome {:?}", other),
+    }
+    assert_eq!(&chip.flash()[0x1000..0x1000 + 300], &[0xA5; 300][..]);
+    assert_eq!(chip.count(Seen::Entered), 1);
+    assert_eq!(chip.count(Seen::Command(0x25)), 1);
+
+    // and once more when there is nothing to do
+    match io.update(&bundle, &policy) {
+        UpdateOutcome::AlreadyCurrent => {}
+        other => panic!("unexpected outcome {:?}", other),
+    }
+    assert_eq!(chip.count(Seen::Entered), 2);
+    assert_eq!(chip.count(Seen::Command(0x25)), 2);
+}
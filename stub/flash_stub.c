@@ -0,0 +1,154 @@
+/*
+ *  The open flashing stub src/bootloader/stub.rs talks to, loaded into SRAM by Bootloader::load_and_run
+ *  Built against TI's CC13x0/CC26x0 driverlib and linked at a 256 byte aligned SRAM address, e.g.
+ *    arm-none-eabi-gcc -mcpu=cortex-m3 -mthumb -Os -nostartfiles -I$DRIVERLIB -T flash_stub.ld \
+ *      flash_stub.c $DRIVERLIB/driverlib/bin/gcc/driverlib.lib -o flash_stub.elf
+ *    arm-none-eabi-objcopy -O binary flash_stub.elf flash_stub.bin
+ *  and handed to FlashStub::new with the address it is linked at
+ *
+ *  It is entered through its PendSV vector straight out of the ROM bootloader's command loop,
+ *  so SSI0 is still the SPI slave the ROM configured from the CCFG and is used as it is
+ *
+ *  Frame: 0xA5, op, payload length (u16 LE), address (u32 LE), payload, CRC32 of op..payload (u32 LE)
+ *  Every frame is answered with 0xCC (ACK) or 0x33 (NACK), OP_CRC follows its ACK with a reply frame
+ *  of the same format holding the CRC32 of [address, address + length) as a u32 LE
+ */
+
+#include <stdint.h>
+#include <driverlib/flash.h>
+#include <driverlib/ssi.h>
+#include <driverlib/sys_ctrl.h>
+
+#define SYNC 0xA5
+#define OP_PING 0x00
+#define OP_ERASE 0x01
+#define OP_WRITE 0x02
+#define OP_CRC 0x03
+#define OP_RESET 0x04
+#define ACK 0xCC
+#define NACK 0x33
+
+// DEFAULT_MAX_FRAME on the host
+#define MAX_PAYLOAD 4096
+#define HEADER_LEN 8
+
+static uint8_t frame[HEADER_LEN + MAX_PAYLOAD + 4];
+static uint32_t stack[128];
+
+static uint32_t crc32(uint32_t crc, const uint8_t *data, uint32_t len)
+{
+    crc = ~crc;
+    while (len--) {
+        crc ^= *data++;
+        for (int bit = 0; bit < 8; bit++) {
+            crc = (crc >> 1) ^ (0xEDB88320 & -(crc & 1));
+        }
+    }
+    return ~crc;
+}
+
+static uint8_t get(void)
+{
+    uint32_t b;
+    SSIDataGet(SSI0_BASE, &b);
+    return (uint8_t)b;
+}
+
+static void put(uint8_t b)
+{
+    SSIDataPut(SSI0_BASE, b);
+}
+
+static uint32_t le16(const uint8_t *p)
+{
+    return p[0] | (uint32_t)p[1] << 8;
+}
+
+static uint32_t le32(const uint8_t *p)
+{
+    return le16(p) | le16(p + 2) << 16;
+}
+
+static void put_le32(uint32_t v)
+{
+    for (int i = 0; i < 4; i++) {
+        put(v >> (8 * i));
+    }
+}
+
+// 0 once a whole frame with a good CRC is in, the host reads zeros while it waits so they are skipped
+static int receive(void)
+{
+    while (get() != SYNC) {
+    }
+    for (int i = 1; i < HEADER_LEN; i++) {
+        frame[i] = get();
+    }
+    uint32_t len = le16(&frame[2]);
+    if (len > MAX_PAYLOAD) {
+        return -1;
+    }
+    for (uint32_t i = 0; i < len + 4; i++) {
+        frame[HEADER_LEN + i] = get();
+    }
+    return le32(&frame[HEADER_LEN + len]) == crc32(0, &frame[1], HEADER_LEN - 1 + len) ? 0 : -1;
+}
+
+static void reply_crc(uint32_t address, uint32_t crc)
+{
+    uint8_t reply[HEADER_LEN + 4] = {
+        SYNC, OP_CRC, 4, 0,
+        address, address >> 8, address >> 16, address >> 24,
+        crc, crc >> 8, crc >> 16, crc >> 24,
+    };
+    put(ACK);
+    for (int i = 0; i < HEADER_LEN + 4; i++) {
+        put(reply[i]);
+    }
+    put_le32(crc32(0, &reply[1], HEADER_LEN + 3));
+}
+
+static void stub_main(void)
+{
+    for (;;) {
+        if (receive() != 0) {
+            put(NACK);
+            continue;
+        }
+        uint8_t op = frame[1];
+        uint32_t len = le16(&frame[2]);
+        uint32_t address = le32(&frame[4]);
+        uint8_t *payload = &frame[HEADER_LEN];
+        switch (op) {
+        case OP_PING:
+            put(ACK);
+            break;
+        case OP_ERASE:
+            put(FlashSectorErase(address) == FAPI_STATUS_SUCCESS ? ACK : NACK);
+            break;
+        case OP_WRITE:
+            put(FlashProgram(payload, address, len) == FAPI_STATUS_SUCCESS ? ACK : NACK);
+            break;
+        case OP_CRC:
+            if (len != 4) {
+                put(NACK);
+                break;
+            }
+            reply_crc(address, crc32(0, (const uint8_t *)address, le32(payload)));
+            break;
+        case OP_RESET:
+            SysCtrlSystemReset();
+            break;
+        default:
+            put(NACK);
+        }
+    }
+}
+
+// only the entries up to PendSV are ever used, the ROM pends PendSV after pointing VTOR here
+__attribute__((section(".vectors"), used))
+static void (*const vectors[15])(void) = {
+    (void (*)(void))(stack + sizeof(stack) / sizeof(stack[0])),
+    stub_main,
+    [14] = stub_main,
+};
@@ -1,6 +1,8 @@
-extern crate cc131x;
+#![cfg(feature = "std")]
+
 extern crate crc;
 extern crate sysfs_gpio;
+extern crate ti_rom_bootloader_cc13xx_cc25xx as cc131x;
 
 mod tests {
     use cc131x::firmware_image::FirmwareImage;
@@ -10,16 +12,15 @@ mod tests {
     fn test_startup() {
         let io = Cc131x::new("/dev/spidev2.1", 71, 72, 73, 74).unwrap();
 
-        const FW_FILE1: &'static str = include_str!("../src/firmware/test_parsing.ihex");
-        let firmware1 = FirmwareImage::new(FW_FILE1);
+        const FW_FILE1: &str = include_str!("../src/firmware/test_parsing.ihex");
+        let firmware1 = FirmwareImage::new(FW_FILE1).unwrap();
         let need_to_update_firmware = io.need_to_update_firmware(&firmware1).unwrap();
         if need_to_update_firmware {
             io.flash_firmware(&firmware1).unwrap();
         }
 
-        const FW_FILE2: &'static str =
-            include_str!("../firmware/gateway_CC1310_LAUNCHXL_tirtos_gcc.hex");
-        let firmware2 = FirmwareImage::new(FW_FILE2);
+        const FW_FILE2: &str = include_str!("../firmware/gateway_CC1310_LAUNCHXL_tirtos_gcc.hex");
+        let firmware2 = FirmwareImage::new(FW_FILE2).unwrap();
         let need_to_update_firmware = io.need_to_update_firmware(&firmware2).unwrap();
         if need_to_update_firmware {
             io.flash_firmware(&firmware2).unwrap();